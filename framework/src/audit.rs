@@ -0,0 +1,101 @@
+//! Audit trail of sensitive actions, e.g. an admin signing in as another user.
+
+use crate::client_ip::ClientIp;
+use log::info;
+use sqlx::SqlitePool;
+
+/// Record that user `actor_id` did `action`, to user `target_id` if any, from address
+/// `ip` (see `ClientIp::of`).
+///
+/// Entries go to the log and to the `audit_log` table the application's migrations
+/// must create:
+///
+/// ```sql
+/// CREATE TABLE audit_log (
+///     id INTEGER PRIMARY KEY AUTOINCREMENT,
+///     actor_id INTEGER NOT NULL,
+///     action TEXT NOT NULL,
+///     target_id INTEGER,
+///     ip TEXT,
+///     created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+/// );
+/// ```
+///
+/// Ids aren't foreign keys, so entries outlive the users they name.
+///
+/// # Errors
+///
+/// Returns the database error if the entry can't be stored. Callers should fail the
+/// action then rather than carry it out unrecorded.
+pub async fn record(
+    db: &SqlitePool,
+    actor_id: i64,
+    action: &str,
+    target_id: Option<i64>,
+    ip: Option<ClientIp>,
+) -> Result<(), sqlx::Error> {
+    let ip = ip.map(|ip| ip.to_string());
+    sqlx::query("INSERT INTO audit_log (actor_id, action, target_id, ip) VALUES (?, ?, ?, ?)")
+        .bind(actor_id)
+        .bind(action)
+        .bind(target_id)
+        .bind(&ip)
+        .execute(db)
+        .await?;
+
+    let from = ip.as_deref().unwrap_or("unknown address");
+    match target_id {
+        Some(target_id) => info!("Audit: user {actor_id} {action} user {target_id} from {from}"),
+        None => info!("Audit: user {actor_id} {action} from {from}"),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    #[actix_web::test]
+    async fn entry_records_the_client_address() {
+        let data = testing::app_data().await;
+        sqlx::query(
+            "CREATE TABLE audit_log (
+                 id INTEGER PRIMARY KEY AUTOINCREMENT,
+                 actor_id INTEGER NOT NULL,
+                 action TEXT NOT NULL,
+                 target_id INTEGER,
+                 ip TEXT,
+                 created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+             )",
+        )
+        .execute(&data.db)
+        .await
+        .unwrap();
+
+        let ip = ClientIp("203.0.113.9".parse().unwrap());
+        record(&data.db, 1, "impersonate_start", Some(2), Some(ip))
+            .await
+            .unwrap();
+        record(&data.db, 1, "impersonate_stop", Some(2), None)
+            .await
+            .unwrap();
+
+        let entries: Vec<(String, Option<i64>, Option<String>)> =
+            sqlx::query_as("SELECT action, target_id, ip FROM audit_log ORDER BY id")
+                .fetch_all(&data.db)
+                .await
+                .unwrap();
+        assert_eq!(
+            entries,
+            vec![
+                (
+                    "impersonate_start".to_string(),
+                    Some(2),
+                    Some("203.0.113.9".to_string())
+                ),
+                ("impersonate_stop".to_string(), Some(2), None),
+            ]
+        );
+    }
+}
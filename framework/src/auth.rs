@@ -1,29 +1,413 @@
-use crate::structs::User;
+use crate::{
+    AppData,
+    error::AppError,
+    structs::{User, UserRole},
+};
 use actix_web::{
-    Error, FromRequest, HttpRequest, HttpResponse, dev::Payload, http::header::LOCATION,
+    Error, FromRequest, HttpRequest, HttpResponse,
+    body::MessageBody,
+    cookie::{Cookie, SameSite, time::Duration as CookieDuration},
+    dev::{Payload, ServiceRequest, ServiceResponse},
+    http::header::{AUTHORIZATION, LOCATION},
+    middleware::Next,
+    web,
 };
-use argon2::Config;
-use futures::future::{Ready, ready};
+use argon2::{Config, Variant, Version};
+use base64::{Engine, engine::general_purpose::STANDARD_NO_PAD};
+use chrono::Utc;
+use futures::future::{LocalBoxFuture, Ready, ready};
 use jsonwebtoken::{DecodingKey, EncodingKey, Header, Validation, decode, encode};
+use log::{error, warn};
 use rand::{RngCore, rng};
-use std::time::{SystemTime, UNIX_EPOCH};
+use sha2::{Digest, Sha256};
+use sqlx::SqlitePool;
+use std::fmt::Write;
+use std::marker::PhantomData;
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use thiserror::Error;
+use tokio::sync::Semaphore;
+
+/// Upper bound for an encoded JWT. Anything larger is rejected before decoding.
+pub const MAX_TOKEN_LENGTH: usize = 8 * 1024;
+
+/// Version of the scheme `hash_password` produces, stored next to each hash in
+/// `users.password_version`. Bump it when the argon2 parameters change so existing
+/// hashes get replaced on the next login (see `User::password_outdated`).
+pub const PASSWORD_VERSION: i64 = 1;
+
+/// Argon2id cost factors for new password hashes, set with `FrameworkApp::argon2_params`
+/// and kept in `AppData::argon2_params`.
+///
+/// The default is the OWASP recommendation (19 MiB, 2 passes, 1 lane). Raise it on
+/// hardware that can afford it; lower it only where speed matters more than strength,
+/// e.g. in CI. Existing hashes keep verifying with the parameters they were created with,
+/// and `needs_rehash` reports the ones weaker than the current setting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Argon2Params {
+    /// Memory in KiB.
+    pub mem_cost: u32,
+    /// Number of passes.
+    pub time_cost: u32,
+    /// Degree of parallelism.
+    pub lanes: u32,
+}
+
+impl Default for Argon2Params {
+    fn default() -> Self {
+        let config = Config::default();
+        Self {
+            mem_cost: config.mem_cost,
+            time_cost: config.time_cost,
+            lanes: config.lanes,
+        }
+    }
+}
 
-pub fn hash_password(password: &str) -> Result<String, argon2::Error> {
+/// Hash `password` with argon2id, the cost factors of `params` (usually
+/// `data.argon2_params`) and a random salt.
+///
+/// This blocks the calling thread for the whole hash, which is meant to be slow. Use
+/// it in cron jobs or CLI tools; request handlers should call `hash_password_async`.
+///
+/// # Errors
+///
+/// Returns the argon2 error if hashing fails.
+pub fn hash_password(password: &str, params: Argon2Params) -> Result<String, argon2::Error> {
     let mut salt = vec![0u8; 16];
     rng().fill_bytes(&mut salt);
 
-    let config = Config::default();
+    let config = Config {
+        mem_cost: params.mem_cost,
+        time_cost: params.time_cost,
+        lanes: params.lanes,
+        ..Config::default()
+    };
     let hash = argon2::hash_encoded(password.as_bytes(), &salt, &config)?;
 
     Ok(hash)
 }
 
+/// Check `password` against an encoded hash. Besides argon2, bcrypt hashes (`$2a$`,
+/// `$2b$`, `$2y$`) imported from other systems are accepted; see `needs_rehash`.
+///
+/// Like `hash_password` this blocks the calling thread; in request handlers use
+/// `verify_password_async`.
+///
+/// A hash that can't be parsed counts as a mismatch; use `verify_password_detailed`
+/// to tell it apart and to learn whether the hash should be upgraded.
+#[must_use]
 pub fn verify_password(password: &str, hash: &str) -> bool {
-    match argon2::verify_encoded(hash, password.as_bytes()) {
-        Ok(matches) => matches,
-        Err(_) => false,
+    check_password(password, hash).is_ok_and(|(ok, _)| ok)
+}
+
+/// Whether `password` matches `hash`, along with the parsed hash for argon2 (`None`
+/// for bcrypt), so callers can look at its parameters without parsing it again.
+fn check_password(password: &str, hash: &str) -> Result<(bool, Option<Argon2Hash>), argon2::Error> {
+    if is_bcrypt(hash) {
+        let ok = bcrypt::verify(password, hash).map_err(|_| argon2::Error::DecodingFail)?;
+        return Ok((ok, None));
+    }
+
+    let parsed = Argon2Hash::parse(hash)?;
+    let ok = argon2::verify_raw(
+        password.as_bytes(),
+        &parsed.salt,
+        &parsed.hash,
+        &parsed.config,
+    )?;
+    Ok((ok, Some(parsed)))
+}
+
+/// An encoded argon2 hash (`$argon2id$v=19$m=19456,t=2,p=1$<salt>$<hash>`), split
+/// into what `argon2::verify_raw` needs.
+struct Argon2Hash {
+    config: Config<'static>,
+    salt: Vec<u8>,
+    hash: Vec<u8>,
+}
+
+impl Argon2Hash {
+    fn parse(encoded: &str) -> Result<Self, argon2::Error> {
+        let decode = |part: &str| {
+            STANDARD_NO_PAD
+                .decode(part)
+                .map_err(|_| argon2::Error::DecodingFail)
+        };
+        // hashes from before argon2 1.3 have no version part
+        let (variant, version, costs, salt, hash) = match *encoded.split('$').collect::<Vec<_>>() {
+            ["", variant, version, costs, salt, hash] => {
+                let version = version
+                    .strip_prefix("v=")
+                    .ok_or(argon2::Error::DecodingFail)?;
+                (variant, Version::from_str(version)?, costs, salt, hash)
+            }
+            ["", variant, costs, salt, hash] => (variant, Version::Version10, costs, salt, hash),
+            _ => return Err(argon2::Error::DecodingFail),
+        };
+
+        let mut costs = costs.split(',').map(|cost| cost.split_once('='));
+        let mut cost = |name| match costs.next() {
+            Some(Some((key, value))) if key == name => {
+                value.parse().map_err(|_| argon2::Error::DecodingFail)
+            }
+            _ => Err(argon2::Error::DecodingFail),
+        };
+        let config = Config {
+            variant: Variant::from_str(variant)?,
+            version,
+            mem_cost: cost("m")?,
+            time_cost: cost("t")?,
+            lanes: cost("p")?,
+            ..Config::default()
+        };
+        if costs.next().is_some() {
+            return Err(argon2::Error::DecodingFail);
+        }
+
+        Ok(Self {
+            config,
+            salt: decode(salt)?,
+            hash: decode(hash)?,
+        })
+    }
+
+    /// Another scheme than the one `hash_password` produces, or any cost factor below
+    /// `params`.
+    fn weaker_than(&self, params: Argon2Params) -> bool {
+        self.config.variant != Variant::Argon2id
+            || self.config.version != Version::Version13
+            || self.config.mem_cost < params.mem_cost
+            || self.config.time_cost < params.time_cost
+            || self.config.lanes < params.lanes
+    }
+}
+
+/// Outcome of `verify_password_detailed`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VerifyResult {
+    /// The password matches the hash.
+    pub ok: bool,
+    /// The password matched and its hash should be replaced via `hash_password`,
+    /// see `needs_rehash`. Always `false` for a mismatch.
+    pub needs_rehash: bool,
+}
+
+/// Like `verify_password`, but also reports whether a matching hash is outdated
+/// compared to `params`, read from the hash as parsed for the check, so a login can
+/// upgrade it right away. A stored hash that can't be parsed (a corrupted row, a
+/// botched migration) is an error instead of a plain mismatch, so it can be logged.
+///
+/// # Errors
+///
+/// Returns the `argon2::Error` if `hash` is malformed; `DecodingFail` for bcrypt hashes.
+pub fn verify_password_detailed(
+    password: &str,
+    hash: &str,
+    params: Argon2Params,
+) -> Result<VerifyResult, argon2::Error> {
+    let (ok, parsed) = check_password(password, hash)?;
+
+    Ok(VerifyResult {
+        ok,
+        // bcrypt hashes are always replaced
+        needs_rehash: ok && parsed.is_none_or(|parsed| parsed.weaker_than(params)),
+    })
+}
+
+/// Whether `hash` should be replaced (via `hash_password`) once the password has been
+/// verified: it uses a legacy scheme or another argon2 variant, or any of its cost
+/// factors is below `params`.
+#[must_use]
+pub fn needs_rehash(hash: &str, params: Argon2Params) -> bool {
+    Argon2Hash::parse(hash).map_or(true, |parsed| parsed.weaker_than(params))
+}
+
+fn is_bcrypt(hash: &str) -> bool {
+    ["$2a$", "$2b$", "$2x$", "$2y$"]
+        .iter()
+        .any(|prefix| hash.starts_with(prefix))
+}
+
+#[derive(Debug, Error)]
+pub enum PasswordError {
+    #[error("Password hashing failed: {0}")]
+    Hash(#[from] argon2::Error),
+    #[error("Password hashing task failed: {0}")]
+    Blocking(#[from] actix_web::error::BlockingError),
+}
+
+static HASH_SEMAPHORE: OnceLock<Semaphore> = OnceLock::new();
+
+/// Caps concurrent async hash/verify operations (each allocates argon2's `mem_cost`).
+/// Sized by `PASSWORD_HASH_CONCURRENCY`, defaulting to the number of CPUs.
+fn hash_semaphore() -> &'static Semaphore {
+    HASH_SEMAPHORE.get_or_init(|| {
+        let permits = std::env::var("PASSWORD_HASH_CONCURRENCY")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .filter(|n| *n > 0)
+            .unwrap_or_else(|| std::thread::available_parallelism().map_or(4, usize::from));
+        Semaphore::new(permits)
+    })
+}
+
+/// `hash_password` on the blocking thread pool, so argon2 doesn't stall the async workers.
+///
+/// # Errors
+///
+/// Returns `PasswordError` if hashing fails or the blocking task can't be run.
+pub async fn hash_password_async(
+    password: &str,
+    params: Argon2Params,
+) -> Result<String, PasswordError> {
+    let password = password.to_owned();
+    Ok(block_limited(hash_semaphore(), move || hash_password(&password, params)).await??)
+}
+
+/// `web::block(f)` once a permit of `semaphore` is free, so at most its number of
+/// permits run at the same time; the rest wait.
+async fn block_limited<F, R>(
+    semaphore: &Semaphore,
+    f: F,
+) -> Result<R, actix_web::error::BlockingError>
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    let _permit = semaphore.acquire().await;
+    web::block(f).await
+}
+
+/// `verify_password` on the blocking thread pool, so argon2 doesn't stall the async workers.
+pub async fn verify_password_async(password: &str, hash: &str) -> bool {
+    let (password, hash) = (password.to_owned(), hash.to_owned());
+    match block_limited(hash_semaphore(), move || check_password(&password, &hash)).await {
+        Ok(result) => result.is_ok_and(|(ok, _)| ok),
+        Err(e) => {
+            error!("Password verification task failed: {e}");
+            false
+        }
+    }
+}
+
+/// `verify_password_detailed` on the blocking thread pool.
+///
+/// # Errors
+///
+/// Returns `PasswordError::Hash` if `hash` is malformed, `PasswordError::Blocking` if
+/// the blocking task can't be run.
+pub async fn verify_password_detailed_async(
+    password: &str,
+    hash: &str,
+    params: Argon2Params,
+) -> Result<VerifyResult, PasswordError> {
+    let (password, hash) = (password.to_owned(), hash.to_owned());
+    Ok(block_limited(hash_semaphore(), move || {
+        verify_password_detailed(&password, &hash, params)
+    })
+    .await??)
+}
+
+/// Length of a TOTP time step in seconds, the RFC 6238 default authenticator apps use.
+pub const TOTP_STEP_SECONDS: i64 = 30;
+
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// A random 160-bit TOTP secret, base32 encoded as authenticator apps expect it.
+/// Store it per user (`users.totp_secret`) to require a code at login.
+#[must_use]
+pub fn generate_totp_secret() -> String {
+    let mut bytes = [0u8; 20];
+    rng().fill_bytes(&mut bytes);
+
+    // 160 bits are exactly 32 base32 characters, no padding needed
+    let bits = bytes
+        .iter()
+        .flat_map(|byte| (0..8).rev().map(move |i| (byte >> i) & 1));
+    let mut secret = String::with_capacity(32);
+    let mut index = 0;
+    for (n, bit) in bits.enumerate() {
+        index = (index << 1) | usize::from(bit);
+        if n % 5 == 4 {
+            secret.push(char::from(BASE32_ALPHABET[index]));
+            index = 0;
+        }
+    }
+    secret
+}
+
+/// `otpauth://` URI that enrolls `secret` in an authenticator app, usually shown as a
+/// QR code. `issuer` is the name the app lists the account under, e.g. `data.domain`
+/// (see `AppData::totp_provisioning_uri`).
+#[must_use]
+pub fn totp_provisioning_uri(secret: &str, account: &str, issuer: &str) -> String {
+    let (account, issuer) = (percent_encode(account), percent_encode(issuer));
+    format!(
+        "otpauth://totp/{issuer}:{account}?secret={secret}&issuer={issuer}\
+         &algorithm=SHA1&digits=6&period={TOTP_STEP_SECONDS}"
+    )
+}
+
+fn percent_encode(value: &str) -> String {
+    value.bytes().fold(String::new(), |mut out, byte| {
+        if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+            out.push(char::from(byte));
+        } else {
+            let _ = write!(out, "%{byte:02X}");
+        }
+        out
+    })
+}
+
+/// Check a 6-digit TOTP `code` against the base32 `secret`.
+///
+/// Codes of the previous and the next time step are accepted too, for clock skew.
+/// Returns the time step the code belongs to: store it (e.g. `users.totp_last_step`)
+/// and pass it back as `last_step` next time, since codes of that or an earlier step
+/// are rejected so an intercepted code can't be replayed.
+#[must_use]
+pub fn verify_totp_code(secret: &str, code: &str, last_step: Option<i64>) -> Option<i64> {
+    let code = code.trim();
+    if code.len() != 6 || !code.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
     }
+    let key = decode_base32(secret)?;
+    let current = Utc::now().timestamp().div_euclid(TOTP_STEP_SECONDS);
+
+    (current - 1..=current + 1)
+        .filter(|step| last_step.is_none_or(|last| *step > last))
+        .find(|step| totp_code(&key, *step).is_some_and(|expected| expected == code))
+}
+
+/// The RFC 6238 code of `key` for time step `step` (HMAC-SHA1, 6 digits).
+pub(crate) fn totp_code(key: &[u8], step: i64) -> Option<String> {
+    use hmac::{Hmac, Mac};
+
+    let mut mac = Hmac::<sha1::Sha1>::new_from_slice(key).ok()?;
+    mac.update(&u64::try_from(step).ok()?.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+
+    let offset = usize::from(hash[hash.len() - 1] & 0x0f);
+    let value = u32::from_be_bytes(hash[offset..offset + 4].try_into().ok()?) & 0x7fff_ffff;
+    Some(format!("{:06}", value % 1_000_000))
+}
+
+/// Decode unpadded base32 (case and spaces ignored, as users type secrets by hand).
+pub(crate) fn decode_base32(input: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::new();
+    let (mut buffer, mut bits) = (0u32, 0);
+    for c in input.chars().filter(|c| !c.is_whitespace() && *c != '=') {
+        let c = u8::try_from(c.to_ascii_uppercase()).ok()?;
+        let value = BASE32_ALPHABET.iter().position(|&a| a == c)?;
+        buffer = (buffer << 5) | u32::try_from(value).ok()?;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push(u8::try_from((buffer >> bits) & 0xff).ok()?);
+        }
+    }
+    (!bytes.is_empty()).then_some(bytes)
 }
 
 #[derive(Debug, Error)]
@@ -32,6 +416,8 @@ pub enum JwtError {
     SecretNotSet,
     #[error("Error calculating expiration time: {0}")]
     ExpirationError(#[from] std::time::SystemTimeError),
+    #[error("Token lifetime is too long")]
+    TtlTooLong,
     #[error("Error encoding the JWT")]
     JwtEncodingError,
     #[error("Error decoding the JWT")]
@@ -44,12 +430,27 @@ pub enum JwtError {
     Unauthorized,
 }
 
-#[derive(Debug)]
+/// Why an auth extractor rejected a request. Converts into an actix `Error` (the
+/// `/login` redirect itself) or, for handlers using `?`, into an `AppError`.
+#[derive(Debug, Error)]
 pub enum AuthError {
+    #[error("Authentication required, redirecting to /login")]
     Redirect(HttpResponse),
+    #[error("Authentication failed: {0}")]
     Other(Error),
 }
 
+/// A redirect becomes `AppError::NoAuth`, i.e. the themed `401` page (or JSON for API
+/// clients) instead of the redirect.
+impl From<AuthError> for AppError {
+    fn from(err: AuthError) -> Self {
+        match err {
+            AuthError::Redirect(_) => AppError::NoAuth,
+            AuthError::Other(err) => AppError::Internal(err.to_string()),
+        }
+    }
+}
+
 impl From<JwtError> for AuthError {
     fn from(err: JwtError) -> Self {
         match err {
@@ -78,24 +479,105 @@ impl From<AuthError> for Error {
     }
 }
 
-#[derive(serde::Serialize, serde::Deserialize, Debug)]
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
 pub struct Claims {
     pub sub: i64,
-    pub role: crate::structs::UserRole,
+    pub role: UserRole,
     pub exp: usize,
+    /// The admin signed in as `sub`, see `create_impersonation_jwt`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub impersonator: Option<i64>,
+}
+
+tokio::task_local! {
+    /// Claims of the request being handled, for renders that have no request.
+    static CURRENT_CLAIMS: Option<Claims>;
+}
+
+/// The claims of the request being handled, if called from within a handler and the
+/// request carries a valid session token.
+pub(crate) fn current_claims() -> Option<Claims> {
+    CURRENT_CLAIMS.try_with(Clone::clone).ok().flatten()
+}
+
+/// Makes the request's claims available to `current_claims` while it is handled, so
+/// templates can tell e.g. impersonation sessions apart.
+pub(crate) async fn scope_claims(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let claims = read_jwt(req.request()).ok();
+    CURRENT_CLAIMS.scope(claims, next.call(req)).await
+}
+
+/// Extracts the validated claims of the session token without any role check.
+impl FromRequest for Claims {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(read_jwt(req).map_err(AuthError::from).map_err(Error::from))
+    }
 }
 
+/// Lifetime of tokens from `create_jwt`, unless `JWT_TTL_SECONDS` says otherwise.
+pub const DEFAULT_JWT_TTL: Duration = Duration::from_hours(1);
+
+/// A session token for `user` that expires after `DEFAULT_JWT_TTL`.
+///
+/// # Errors
+///
+/// Returns `JwtError` if the expiry can't be computed or the token can't be encoded.
 pub fn create_jwt(user: User, secret: &str) -> Result<String, JwtError> {
+    create_jwt_with_ttl(&user, secret, DEFAULT_JWT_TTL)
+}
+
+/// Like `create_jwt`, but the token expires after `ttl`. Give the session cookie the
+/// same lifetime (`AppData::jwt_ttl`) so both run out together.
+///
+/// # Errors
+///
+/// Returns `JwtError` if the expiry can't be computed or the token can't be encoded.
+pub fn create_jwt_with_ttl(user: &User, secret: &str, ttl: Duration) -> Result<String, JwtError> {
+    encode_jwt(user.id, user.role, None, secret, ttl)
+}
+
+/// A session token for `user` held by admin `impersonator`, to see the app as the
+/// user does. The claims carry `impersonator`, so handlers can tell such sessions
+/// apart and end them by issuing the admin a token of their own again. Record both
+/// steps with `audit::record`.
+///
+/// # Errors
+///
+/// Returns `JwtError` if the expiry can't be computed or the token can't be encoded.
+pub fn create_impersonation_jwt(
+    user: &User,
+    impersonator: i64,
+    secret: &str,
+    ttl: Duration,
+) -> Result<String, JwtError> {
+    encode_jwt(user.id, user.role, Some(impersonator), secret, ttl)
+}
+
+/// Sign a token for user `sub` with `role` that expires after `ttl`.
+pub(crate) fn encode_jwt(
+    sub: i64,
+    role: UserRole,
+    impersonator: Option<i64>,
+    secret: &str,
+    ttl: Duration,
+) -> Result<String, JwtError> {
     let expiration = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .map_err(JwtError::ExpirationError)?
-        .as_secs()
-        + 3600 * 1;
+        .duration_since(UNIX_EPOCH)?
+        .checked_add(ttl)
+        .and_then(|exp| usize::try_from(exp.as_secs()).ok())
+        .ok_or(JwtError::TtlTooLong)?;
 
     let claims = Claims {
-        sub: user.id,
-        role: user.role,
-        exp: expiration as usize,
+        sub,
+        role,
+        exp: expiration,
+        impersonator,
     };
 
     let header = Header::default();
@@ -104,23 +586,459 @@ pub fn create_jwt(user: User, secret: &str) -> Result<String, JwtError> {
     encode(&header, &claims, &encoding_key).map_err(|_| JwtError::JwtEncodingError)
 }
 
+/// Lifetime of refresh tokens, see `create_refresh_token`.
+pub const REFRESH_TOKEN_TTL: Duration = Duration::from_hours(30 * 24);
+
+/// Issue a long-lived, single-use refresh token for `user_id`.
+///
+/// Only its SHA-256 is stored, in the `refresh_tokens` table the application's
+/// migrations must create:
+///
+/// ```sql
+/// CREATE TABLE refresh_tokens (
+///     token_hash TEXT PRIMARY KEY NOT NULL,
+///     user_id INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+///     expires_at TIMESTAMP NOT NULL
+/// );
+/// ```
+///
+/// # Errors
+///
+/// Returns the database error if the token can't be stored.
+pub async fn create_refresh_token(
+    db: &SqlitePool,
+    user_id: i64,
+    ttl: Duration,
+) -> Result<String, sqlx::Error> {
+    let mut bytes = [0u8; 32];
+    rng().fill_bytes(&mut bytes);
+    let token = bytes.iter().fold(String::new(), |mut s, b| {
+        let _ = write!(s, "{b:02x}");
+        s
+    });
+    let expires_at = Utc::now().naive_utc() + ttl;
+
+    sqlx::query("INSERT INTO refresh_tokens (token_hash, user_id, expires_at) VALUES (?, ?, ?)")
+        .bind(token_hash(&token))
+        .bind(user_id)
+        .bind(expires_at)
+        .execute(db)
+        .await?;
+
+    Ok(token)
+}
+
+/// Redeem a refresh token and return the user it was issued to. The token is deleted
+/// either way, so each one works once; issue a new one to rotate it. Expired tokens
+/// are cleaned up on the way.
+///
+/// # Errors
+///
+/// Returns the database error if the `refresh_tokens` table can't be accessed.
+pub async fn consume_refresh_token(
+    db: &SqlitePool,
+    token: &str,
+) -> Result<Option<i64>, sqlx::Error> {
+    let now = Utc::now().naive_utc();
+    sqlx::query("DELETE FROM refresh_tokens WHERE expires_at <= ?")
+        .bind(now)
+        .execute(db)
+        .await?;
+
+    sqlx::query_scalar("DELETE FROM refresh_tokens WHERE token_hash = ? RETURNING user_id")
+        .bind(token_hash(token))
+        .fetch_optional(db)
+        .await
+}
+
+/// Revoke a refresh token, e.g. on logout.
+///
+/// # Errors
+///
+/// Returns the database error if the token can't be deleted.
+pub async fn revoke_refresh_token(db: &SqlitePool, token: &str) -> Result<(), sqlx::Error> {
+    sqlx::query("DELETE FROM refresh_tokens WHERE token_hash = ?")
+        .bind(token_hash(token))
+        .execute(db)
+        .await?;
+    Ok(())
+}
+
+/// Revoke every refresh token of `user_id`, e.g. after their password changed, so
+/// their other sessions end once the current session token expires.
+///
+/// # Errors
+///
+/// Returns the database error if the tokens can't be deleted.
+pub async fn revoke_user_refresh_tokens(db: &SqlitePool, user_id: i64) -> Result<(), sqlx::Error> {
+    sqlx::query("DELETE FROM refresh_tokens WHERE user_id = ?")
+        .bind(user_id)
+        .execute(db)
+        .await?;
+    Ok(())
+}
+
+/// Lifetime of password reset tokens, see `create_reset_token`.
+pub const RESET_TOKEN_TTL: Duration = Duration::from_hours(1);
+
+/// Issue a single-use password reset token for `user_id`, valid for `ttl`; send it to
+/// the user (e.g. as a link) and redeem it with `consume_reset_token`.
+///
+/// Only its SHA-256 is stored, in the `password_resets` table the application's
+/// migrations must create:
+///
+/// ```sql
+/// CREATE TABLE password_resets (
+///     token_hash TEXT PRIMARY KEY NOT NULL,
+///     user_id INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+///     expires_at TIMESTAMP NOT NULL,
+///     used BOOLEAN NOT NULL DEFAULT FALSE
+/// );
+/// ```
+///
+/// Expired and used tokens are deleted on the way, so the table doesn't grow.
+///
+/// # Errors
+///
+/// Returns the database error if the token can't be stored.
+pub async fn create_reset_token(
+    db: &SqlitePool,
+    user_id: i64,
+    ttl: Duration,
+) -> Result<String, sqlx::Error> {
+    let mut bytes = [0u8; 32];
+    rng().fill_bytes(&mut bytes);
+    let token = bytes.iter().fold(String::new(), |mut s, b| {
+        let _ = write!(s, "{b:02x}");
+        s
+    });
+    let now = Utc::now().naive_utc();
+
+    sqlx::query("DELETE FROM password_resets WHERE used OR expires_at <= ?")
+        .bind(now)
+        .execute(db)
+        .await?;
+
+    sqlx::query("INSERT INTO password_resets (token_hash, user_id, expires_at) VALUES (?, ?, ?)")
+        .bind(token_hash(&token))
+        .bind(user_id)
+        .bind(now + ttl)
+        .execute(db)
+        .await?;
+
+    Ok(token)
+}
+
+/// Redeem a password reset token and return the user it was issued to, or `None` if
+/// it is unknown, expired or was already used. A token works once.
+///
+/// # Errors
+///
+/// Returns the database error if the `password_resets` table can't be accessed.
+pub async fn consume_reset_token(db: &SqlitePool, token: &str) -> Result<Option<i64>, sqlx::Error> {
+    sqlx::query_scalar(
+        "UPDATE password_resets SET used = TRUE \
+         WHERE token_hash = ? AND NOT used AND expires_at > ? RETURNING user_id",
+    )
+    .bind(token_hash(token))
+    .bind(Utc::now().naive_utc())
+    .fetch_optional(db)
+    .await
+}
+
+/// Header API clients send their key in, see `ApiKey`.
+pub const API_KEY_HEADER: &str = "x-api-key";
+
+/// Issue an API key with `role` for scripts and integrations that can't keep a
+/// session cookie. The key is only returned here; store it right away.
+///
+/// Only its SHA-256 is stored, in the `api_keys` table the application's migrations
+/// must create:
+///
+/// ```sql
+/// CREATE TABLE api_keys (
+///     key_hash TEXT PRIMARY KEY NOT NULL,
+///     role TEXT NOT NULL,
+///     revoked BOOLEAN NOT NULL DEFAULT FALSE,
+///     created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+/// );
+/// ```
+///
+/// # Errors
+///
+/// Returns the database error if the key can't be stored.
+pub async fn create_api_key(db: &SqlitePool, role: UserRole) -> Result<String, sqlx::Error> {
+    let mut bytes = [0u8; 32];
+    rng().fill_bytes(&mut bytes);
+    let key = bytes.iter().fold(String::new(), |mut s, b| {
+        let _ = write!(s, "{b:02x}");
+        s
+    });
+
+    sqlx::query("INSERT INTO api_keys (key_hash, role) VALUES (?, ?)")
+        .bind(token_hash(&key))
+        .bind(role)
+        .execute(db)
+        .await?;
+
+    Ok(key)
+}
+
+/// Revoke an API key; requests with it are rejected from then on. Returns whether
+/// the key existed and wasn't revoked yet.
+///
+/// # Errors
+///
+/// Returns the database error if the `api_keys` table can't be accessed.
+pub async fn revoke_api_key(db: &SqlitePool, key: &str) -> Result<bool, sqlx::Error> {
+    let result =
+        sqlx::query("UPDATE api_keys SET revoked = TRUE WHERE key_hash = ? AND NOT revoked")
+            .bind(token_hash(key))
+            .execute(db)
+            .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// When repeated failed logins lock an account, see `record_login_failure`. `run()`
+/// reads it from the `LOGIN_*` variables into `AppData::lockout`.
+#[derive(Debug, Clone, Copy)]
+pub struct LockoutPolicy {
+    /// Failures in a row that lock the account (`LOGIN_MAX_FAILURES`, default 5).
+    pub max_failures: i64,
+    /// How long it stays locked (`LOGIN_LOCKOUT_SECONDS`, default 15 minutes).
+    pub cooldown: Duration,
+}
+
+impl Default for LockoutPolicy {
+    fn default() -> Self {
+        Self {
+            max_failures: 5,
+            cooldown: Duration::from_mins(15),
+        }
+    }
+}
+
+/// Key of `email` in `login_attempts`, so differently cased spellings share a counter.
+fn lockout_key(email: &str) -> String {
+    email.trim().to_lowercase()
+}
+
+/// Whether logins for `email` are locked after too many failures. Check it before the
+/// password and answer like a wrong password, so the lock can't be told apart.
+///
+/// # Errors
+///
+/// Returns the database error if the `login_attempts` table can't be accessed.
+pub async fn login_locked(db: &SqlitePool, email: &str) -> Result<bool, sqlx::Error> {
+    let locked: Option<i64> =
+        sqlx::query_scalar("SELECT 1 FROM login_attempts WHERE email = ? AND locked_until > ?")
+            .bind(lockout_key(email))
+            .bind(Utc::now().naive_utc())
+            .fetch_optional(db)
+            .await?;
+    Ok(locked.is_some())
+}
+
+/// Count a failed login for the existing account `email`; don't call it for unknown
+/// emails, or anyone could fill the table. The `policy.max_failures`th failure in a
+/// row locks it for the cooldown and starts the count over. Rows whose lock has
+/// expired are removed on the way.
+///
+/// # Errors
+///
+/// Returns the database error if the `login_attempts` table can't be accessed.
+pub async fn record_login_failure(
+    db: &SqlitePool,
+    email: &str,
+    policy: LockoutPolicy,
+) -> Result<(), sqlx::Error> {
+    let key = lockout_key(email);
+
+    sqlx::query("DELETE FROM login_attempts WHERE locked_until <= ?")
+        .bind(Utc::now().naive_utc())
+        .execute(db)
+        .await?;
+
+    let failures: i64 = sqlx::query_scalar(
+        "INSERT INTO login_attempts (email, failures) VALUES (?, 1)
+         ON CONFLICT (email) DO UPDATE SET failures = failures + 1
+         RETURNING failures",
+    )
+    .bind(&key)
+    .fetch_one(db)
+    .await?;
+
+    if failures >= policy.max_failures {
+        warn!("Locking logins for {key} after {failures} failed attempts");
+        sqlx::query("UPDATE login_attempts SET failures = 0, locked_until = ? WHERE email = ?")
+            .bind(Utc::now().naive_utc() + policy.cooldown)
+            .bind(&key)
+            .execute(db)
+            .await?;
+    }
+    Ok(())
+}
+
+/// Forget the failed logins of `email`, after a successful login.
+///
+/// # Errors
+///
+/// Returns the database error if the `login_attempts` table can't be accessed.
+pub async fn reset_login_failures(db: &SqlitePool, email: &str) -> Result<(), sqlx::Error> {
+    sqlx::query("DELETE FROM login_attempts WHERE email = ?")
+        .bind(lockout_key(email))
+        .execute(db)
+        .await?;
+    Ok(())
+}
+
+/// Name and attributes of the session cookies.
+///
+/// Apps on subdomains of one parent domain share cookies scoped to it, so give each
+/// its own `name` (or a host-only `domain`) to keep their sessions apart.
+#[derive(Debug, Clone)]
+pub struct CookieConfig {
+    /// Name of the access token cookie (`COOKIE_NAME`, default `token`). The refresh
+    /// token cookie is named `refresh_<name>`, the CSRF cookie `csrf_<name>`.
+    pub name: String,
+    /// `COOKIE_SAME_SITE`: `strict`, `lax` or `none`. Defaults to `Strict`, or `None`
+    /// when the app is embedded cross-site (`EMBED_ORIGINS`).
+    pub same_site: SameSite,
+    /// `COOKIE_SECURE`. Defaults to on outside dev and whenever embedding.
+    pub secure: bool,
+    /// Fixed `Domain` attribute (`COOKIE_DOMAIN`); by default it's derived from
+    /// `DOMAIN` per request, see `AppData::cookie_domain`.
+    pub domain: Option<String>,
+    /// `COOKIE_PATH`, default `/`.
+    pub path: String,
+}
+
+impl CookieConfig {
+    /// Name of the refresh token cookie.
+    #[must_use]
+    pub fn refresh_name(&self) -> String {
+        format!("refresh_{}", self.name)
+    }
+
+    /// Name of the CSRF token cookie, see `csrf::CsrfGuard`.
+    #[must_use]
+    pub fn csrf_name(&self) -> String {
+        format!("csrf_{}", self.name)
+    }
+}
+
+/// A session cookie (`data.cookie.name` or its refresh token cookie) with the
+/// attributes used at login. `logout` clears them with the same attributes; browsers
+/// only replace a cookie whose domain, path and flags match.
+#[must_use]
+pub fn session_cookie(
+    req: &HttpRequest,
+    data: &AppData,
+    name: &str,
+    value: String,
+    max_age: Duration,
+) -> Cookie<'static> {
+    let mut cookie = Cookie::build(name.to_owned(), value)
+        .path(data.cookie.path.clone())
+        .same_site(data.cookie.same_site)
+        .secure(data.cookie.secure)
+        .max_age(CookieDuration::try_from(max_age).unwrap_or(CookieDuration::MAX))
+        .http_only(true)
+        .finish();
+    if let Some(domain) = data.cookie_domain(req) {
+        cookie.set_domain(domain.to_owned());
+    }
+    cookie
+}
+
+/// The session cookie (`data.cookie.name`) for `jwt`, with a `Max-Age` of the token's
+/// remaining lifetime, so the browser drops the cookie when the token runs out. Use it
+/// whenever a token is issued or renewed; a token that can't be decoded gets
+/// `AppData::jwt_ttl`.
+#[must_use]
+pub fn token_cookie(req: &HttpRequest, data: &AppData, jwt: String) -> Cookie<'static> {
+    let max_age = data.verify_jwt(&jwt).map_or(data.jwt_ttl, |claims| {
+        let now = Utc::now().timestamp().unsigned_abs();
+        Duration::from_secs((claims.exp as u64).saturating_sub(now))
+    });
+    session_cookie(req, data, &data.cookie.name, jwt, max_age)
+}
+
+/// Sign the user out: revoke the refresh token, expire both session cookies and
+/// redirect to `/login`. Mount it directly, e.g. `web::get().to(logout)`.
+pub async fn logout(req: HttpRequest, data: web::Data<AppData>) -> HttpResponse {
+    if let Some(refresh) = req.cookie(&data.cookie.refresh_name())
+        && let Err(e) = revoke_refresh_token(&data.db, refresh.value()).await
+    {
+        warn!("Failed to revoke refresh token: {e}");
+    }
+
+    let mut res = HttpResponse::SeeOther();
+    res.append_header((LOCATION, "/login"));
+    for name in [data.cookie.name.clone(), data.cookie.refresh_name()] {
+        res.cookie(session_cookie(
+            &req,
+            &data,
+            &name,
+            String::new(),
+            Duration::ZERO,
+        ));
+    }
+    res.finish()
+}
+
+fn token_hash(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+/// The session token of a request: the session cookie (`token` unless `COOKIE_NAME`
+/// says otherwise), or for API clients without it an `Authorization: Bearer <jwt>`
+/// header. A malformed header counts as no token.
+#[must_use]
+pub fn request_token(req: &HttpRequest) -> Option<String> {
+    let name = req
+        .app_data::<web::Data<AppData>>()
+        .map_or("token", |data| data.cookie.name.as_str());
+    if let Some(cookie) = req.cookie(name) {
+        return Some(cookie.value().to_string());
+    }
+
+    let header = req.headers().get(AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, token) = header.split_once(' ')?;
+    let token = token.trim();
+    (scheme.eq_ignore_ascii_case("bearer") && !token.is_empty()).then(|| token.to_string())
+}
+
 pub fn read_jwt(req: &HttpRequest) -> Result<Claims, JwtError> {
-    let token = req
-        .cookie("token")
-        .ok_or(JwtError::TokenNotFound)?
-        .value()
-        .to_string();
+    let token = request_token(req).ok_or(JwtError::TokenNotFound)?;
+
+    if token.len() > MAX_TOKEN_LENGTH {
+        return Err(JwtError::JwtDecodingError);
+    }
 
     let data = req
         .app_data::<actix_web::web::Data<crate::AppData>>()
         .ok_or(JwtError::SecretNotSet)?;
-    let secret = &data.jwt_secret;
 
+    data.verify_jwt(&token)
+}
+
+/// Validate an encoded token against `secret` and return its claims.
+///
+/// Tokens are accepted up to `leeway` seconds past their `exp` to tolerate clock
+/// skew between servers.
+///
+/// # Errors
+///
+/// Returns `JwtError::JwtExpired` for expired tokens and `JwtError::JwtDecodingError`
+/// for anything else that fails validation.
+pub fn decode_jwt(token: &str, secret: &str, leeway: u64) -> Result<Claims, JwtError> {
     let decoding_key = DecodingKey::from_secret(secret.as_bytes());
-    let validation = Validation::new(jsonwebtoken::Algorithm::HS256);
+    let mut validation = Validation::new(jsonwebtoken::Algorithm::HS256);
+    validation.leeway = leeway;
 
     let token_data =
-        decode::<Claims>(&token, &decoding_key, &validation).map_err(|e| match e.kind() {
+        decode::<Claims>(token, &decoding_key, &validation).map_err(|e| match e.kind() {
             jsonwebtoken::errors::ErrorKind::ExpiredSignature => JwtError::JwtExpired,
             _ => JwtError::JwtDecodingError,
         })?;
@@ -128,6 +1046,28 @@ pub fn read_jwt(req: &HttpRequest) -> Result<Claims, JwtError> {
     Ok(token_data.claims)
 }
 
+/// Like `decode_jwt`, but tokens that don't verify with `secret` are tried against the
+/// `previous` secrets too, so sessions survive a rotation of `JWT_SECRET` until they expire.
+///
+/// # Errors
+///
+/// Same as `decode_jwt`, for the last secret tried.
+pub fn decode_jwt_rotated(
+    token: &str,
+    secret: &str,
+    previous: &[String],
+    leeway: u64,
+) -> Result<Claims, JwtError> {
+    let mut result = decode_jwt(token, secret, leeway);
+    for old in previous {
+        if !matches!(result, Err(JwtError::JwtDecodingError)) {
+            break;
+        }
+        result = decode_jwt(token, old, leeway);
+    }
+    result
+}
+
 #[derive(Debug)]
 pub struct AuthUser {
     pub claims: Claims,
@@ -147,31 +1087,971 @@ impl FromRequest for AuthUser {
     }
 }
 
+/// The claims of the session token if there is a valid one, `None` for guests.
+///
+/// Unlike `AuthUser` it never fails, for pages that render for everyone but differ
+/// for signed-in users (e.g. "Login" vs. a dashboard link).
+#[derive(Debug)]
+pub struct OptionalAuthUser(pub Option<Claims>);
+
+impl FromRequest for OptionalAuthUser {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(Ok(OptionalAuthUser(read_jwt(req).ok())))
+    }
+}
+
+/// A request authenticated with an `X-API-Key` header (see `create_api_key`).
+///
+/// `claims` carry the key's role, so role checks work as for session users. API keys
+/// don't belong to a user, so `claims.sub` is 0. Missing, unknown and revoked keys
+/// all get the same `401 Unauthorized`.
 #[derive(Debug)]
-pub struct AdminUser {
+pub struct ApiKey {
     pub claims: Claims,
 }
 
-impl FromRequest for AdminUser {
+impl FromRequest for ApiKey {
     type Error = Error;
-    type Future = Ready<Result<Self, Self::Error>>;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
 
-    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
-        let auth_future = AuthUser::from_request(req, payload);
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let key = req
+            .headers()
+            .get(API_KEY_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .map(|key| key.trim().to_string());
+        let data = req.app_data::<web::Data<AppData>>().cloned();
+
+        Box::pin(async move {
+            let invalid = || Error::from(AppError::Auth("Invalid API key".into()));
+            let (Some(key), Some(data)) = (key, data) else {
+                return Err(invalid());
+            };
+
+            let role: Option<UserRole> =
+                sqlx::query_scalar("SELECT role FROM api_keys WHERE key_hash = ? AND NOT revoked")
+                    .bind(token_hash(&key))
+                    .fetch_optional(&data.db)
+                    .await
+                    .map_err(AppError::from)?;
+            let role = role.ok_or_else(invalid)?;
+
+            Ok(ApiKey {
+                claims: Claims {
+                    sub: 0,
+                    role,
+                    exp: usize::try_from(Utc::now().timestamp()).unwrap_or_default(),
+                    impersonator: None,
+                },
+            })
+        })
+    }
+}
+
+/// Marker for the role a `Role` extractor requires, see the types in `role`.
+pub trait RequiredRole {
+    const ROLE: UserRole;
+}
+
+/// Role markers for `Role`.
+pub mod role {
+    use super::{RequiredRole, UserRole};
+
+    #[derive(Debug)]
+    pub struct Admin;
+    #[derive(Debug)]
+    pub struct User;
+
+    impl RequiredRole for Admin {
+        const ROLE: UserRole = UserRole::Admin;
+    }
+
+    impl RequiredRole for User {
+        const ROLE: UserRole = UserRole::User;
+    }
+}
+
+/// A logged-in user with at least role `R`, e.g. `Role<role::User>` also admits
+/// admins. Anyone else gets the same `/login` redirect as `AuthUser`.
+#[derive(Debug)]
+pub struct Role<R: RequiredRole> {
+    pub claims: Claims,
+    _role: PhantomData<R>,
+}
+
+/// `Role<role::Admin>`, the extractor for admin-only routes.
+pub type AdminUser = Role<role::Admin>;
 
-        let result = match auth_future.into_inner() {
-            Ok(auth_user) => {
-                if auth_user.claims.role == crate::structs::UserRole::Admin {
-                    Ok(AdminUser {
-                        claims: auth_user.claims,
+impl<R: RequiredRole> FromRequest for Role<R> {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let result = read_jwt(req)
+            .and_then(|claims| {
+                if claims.role.satisfies(R::ROLE) {
+                    Ok(Role {
+                        claims,
+                        _role: PhantomData,
                     })
                 } else {
-                    Err(AuthError::from(JwtError::Unauthorized).into())
+                    Err(JwtError::Unauthorized)
                 }
+            })
+            .map_err(AuthError::from)
+            .map_err(Error::from);
+
+        ready(result)
+    }
+}
+
+/// `AdminUser` and `CsrfGuard` in one extractor, for state-changing admin routes such
+/// as deleting a user, so the CSRF check can't be left out.
+///
+/// A request without an admin session gets the `/login` redirect, one without a valid
+/// CSRF token the themed `403 Forbidden` page.
+#[derive(Debug)]
+pub struct AdminAction {
+    pub claims: Claims,
+}
+
+impl FromRequest for AdminAction {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let result = AdminUser::from_request(req, payload)
+            .into_inner()
+            .and_then(|admin| {
+                crate::csrf::CsrfGuard::from_request(req, payload)
+                    .into_inner()
+                    .map(|_| AdminAction {
+                        claims: admin.claims,
+                    })
+            });
+
+        ready(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use actix_web::{
+        App,
+        http::StatusCode,
+        test::{TestRequest, call_service, init_service, read_body},
+    };
+
+    fn oversized_cookie() -> Cookie<'static> {
+        Cookie::new("token", "a".repeat(MAX_TOKEN_LENGTH + 1))
+    }
+
+    #[test]
+    fn oversized_token_is_rejected_before_decoding() {
+        // Without `AppData` a token that got as far as the decoder fails with `SecretNotSet`
+        let req = TestRequest::default()
+            .cookie(oversized_cookie())
+            .to_http_request();
+
+        assert!(matches!(read_jwt(&req), Err(JwtError::JwtDecodingError)));
+    }
+
+    #[actix_web::test]
+    async fn oversized_token_redirects_to_login() {
+        let data = web::Data::new(testing::app_data().await);
+        let app = init_service(App::new().app_data(data).route(
+            "/",
+            web::get().to(|_: AuthUser| async { HttpResponse::Ok().finish() }),
+        ))
+        .await;
+
+        let req = TestRequest::get()
+            .uri("/")
+            .cookie(oversized_cookie())
+            .to_request();
+        let res = call_service(&app, req).await;
+
+        assert_eq!(res.status(), StatusCode::FOUND);
+        assert_eq!(res.headers().get(LOCATION).unwrap(), "/login");
+    }
+
+    #[actix_web::test]
+    async fn async_verify_matches_sync_verify() {
+        let hash = hash_password_async("correct horse", Argon2Params::default())
+            .await
+            .unwrap();
+
+        for password in ["correct horse", "wrong horse", ""] {
+            assert_eq!(
+                verify_password_async(password, &hash).await,
+                verify_password(password, &hash),
+                "{password:?}"
+            );
+        }
+        assert!(verify_password("correct horse", &hash));
+        assert!(!verify_password_async("not a hash", "$argon2id$broken").await);
+    }
+
+    #[actix_web::test]
+    async fn blocking_work_is_capped_at_the_permits() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        static RUNNING: AtomicUsize = AtomicUsize::new(0);
+        static MAX_RUNNING: AtomicUsize = AtomicUsize::new(0);
+        let semaphore = Semaphore::new(2);
+
+        let tasks = (0..6).map(|_| {
+            block_limited(&semaphore, || {
+                let running = RUNNING.fetch_add(1, Ordering::SeqCst) + 1;
+                MAX_RUNNING.fetch_max(running, Ordering::SeqCst);
+                std::thread::sleep(Duration::from_millis(50));
+                RUNNING.fetch_sub(1, Ordering::SeqCst);
+            })
+        });
+        for result in futures::future::join_all(tasks).await {
+            result.unwrap();
+        }
+
+        assert_eq!(MAX_RUNNING.load(Ordering::SeqCst), 2);
+    }
+
+    #[actix_web::test]
+    async fn admin_action_requires_csrf_even_for_admins() {
+        let data = testing::app_data().await;
+        let token = testing::token(&data, 1, UserRole::Admin);
+        let app = init_service(App::new().app_data(web::Data::new(data)).route(
+            "/",
+            web::post().to(|_: AdminAction| async { HttpResponse::Ok().finish() }),
+        ))
+        .await;
+
+        let req = TestRequest::post()
+            .uri("/")
+            .cookie(Cookie::new("token", token.clone()))
+            .to_request();
+        let res = call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+        let req = TestRequest::post()
+            .uri("/")
+            .cookie(Cookie::new("token", token))
+            .cookie(Cookie::new("csrf_token", "csrf"))
+            .insert_header((crate::csrf::CSRF_HEADER, "csrf"))
+            .to_request();
+        let res = call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn admin_action_rejects_users_with_csrf() {
+        let data = testing::app_data().await;
+        let req = testing::with_csrf(
+            testing::signed_in(TestRequest::post().uri("/"), &data, 2, UserRole::User),
+            &data,
+        );
+        let app = init_service(App::new().app_data(web::Data::new(data)).route(
+            "/",
+            web::post().to(|_: AdminAction| async { HttpResponse::Ok().finish() }),
+        ))
+        .await;
+
+        let res = call_service(&app, req.to_request()).await;
+        assert_eq!(res.status(), StatusCode::FOUND);
+    }
+
+    /// A token for user 1 that expired `seconds_ago`.
+    fn expired_token(seconds_ago: i64) -> String {
+        let claims = Claims {
+            sub: 1,
+            role: UserRole::User,
+            exp: usize::try_from(Utc::now().timestamp() - seconds_ago).unwrap(),
+            impersonator: None,
+        };
+        encode(
+            &Header::default(),
+            &claims,
+            &EncodingKey::from_secret(testing::JWT_SECRET.as_bytes()),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn leeway_tolerates_only_slightly_expired_tokens() {
+        let within = expired_token(10);
+        let beyond = expired_token(60);
+
+        assert_eq!(decode_jwt(&within, testing::JWT_SECRET, 30).unwrap().sub, 1);
+        assert!(matches!(
+            decode_jwt(&beyond, testing::JWT_SECRET, 30),
+            Err(JwtError::JwtExpired)
+        ));
+        assert!(matches!(
+            decode_jwt(&within, testing::JWT_SECRET, 0),
+            Err(JwtError::JwtExpired)
+        ));
+    }
+
+    #[actix_web::test]
+    async fn claims_extract_for_non_admins() {
+        let data = testing::app_data().await;
+        let req = testing::signed_in(TestRequest::get().uri("/"), &data, 7, UserRole::User);
+        let app = init_service(App::new().app_data(web::Data::new(data)).route(
+            "/",
+            web::get().to(|claims: Claims| async move {
+                HttpResponse::Ok().body(format!("{} {}", claims.sub, claims.role))
+            }),
+        ))
+        .await;
+
+        let res = call_service(&app, req.to_request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(read_body(res).await, "7 user");
+    }
+
+    #[test]
+    fn bcrypt_hash_verifies_and_asks_for_an_upgrade() {
+        let hash = bcrypt::hash("correct horse", 4).unwrap();
+
+        assert_eq!(
+            verify_password_detailed("correct horse", &hash, Argon2Params::default()).unwrap(),
+            VerifyResult {
+                ok: true,
+                needs_rehash: true,
+            }
+        );
+        assert_eq!(
+            verify_password_detailed("wrong horse", &hash, Argon2Params::default()).unwrap(),
+            VerifyResult {
+                ok: false,
+                needs_rehash: false,
+            }
+        );
+
+        let upgraded = hash_password("correct horse", Argon2Params::default()).unwrap();
+        assert!(upgraded.starts_with("$argon2id$"));
+        assert!(
+            !verify_password_detailed("correct horse", &upgraded, Argon2Params::default())
+                .unwrap()
+                .needs_rehash
+        );
+    }
+
+    #[actix_web::test]
+    async fn token_of_previous_secret_is_accepted_after_rotation() {
+        let mut data = testing::app_data().await;
+        let old_token = testing::token(&data, 7, UserRole::User);
+        data.jwt_secret = "rotated-secret".to_string();
+        data.jwt_previous_secrets = std::sync::Arc::new(vec![testing::JWT_SECRET.to_string()]);
+        let data = web::Data::new(data);
+        let app = init_service(App::new().app_data(data.clone()).route(
+            "/",
+            web::get().to(|user: AuthUser| async move { user.claims.sub.to_string() }),
+        ))
+        .await;
+
+        let req = TestRequest::get()
+            .uri("/")
+            .cookie(Cookie::new("token", old_token.clone()));
+        let res = call_service(&app, req.to_request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(read_body(res).await, "7");
+
+        // Once the grace period ends the old secret is dropped from the list
+        assert!(matches!(
+            decode_jwt_rotated(&old_token, &data.jwt_secret, &[], 0),
+            Err(JwtError::JwtDecodingError)
+        ));
+        let stranger = encode_jwt(7, UserRole::User, None, "unknown", Duration::from_mins(1));
+        assert!(matches!(
+            data.verify_jwt(&stranger.unwrap()),
+            Err(JwtError::JwtDecodingError)
+        ));
+    }
+
+    #[test]
+    fn token_expires_after_its_ttl() {
+        let now = usize::try_from(Utc::now().timestamp()).unwrap();
+        let token = encode_jwt(7, UserRole::User, None, "secret", Duration::from_mins(5)).unwrap();
+
+        let claims = decode_jwt(&token, "secret", 0).unwrap();
+        assert!((now + 300..=now + 301).contains(&claims.exp));
+        assert_eq!(DEFAULT_JWT_TTL, Duration::from_hours(1));
+    }
+
+    #[test]
+    fn huge_ttl_is_an_error_instead_of_a_panic() {
+        assert!(matches!(
+            encode_jwt(7, UserRole::User, None, "secret", Duration::MAX),
+            Err(JwtError::TtlTooLong)
+        ));
+    }
+
+    #[actix_web::test]
+    async fn refresh_token_works_once_and_expired_ones_are_removed() {
+        let data = testing::app_data().await;
+        sqlx::query(
+            "CREATE TABLE refresh_tokens (
+                 token_hash TEXT PRIMARY KEY NOT NULL,
+                 user_id INTEGER NOT NULL,
+                 expires_at TIMESTAMP NOT NULL
+             )",
+        )
+        .execute(&data.db)
+        .await
+        .unwrap();
+
+        let token = create_refresh_token(&data.db, 7, REFRESH_TOKEN_TTL)
+            .await
+            .unwrap();
+        assert_eq!(
+            consume_refresh_token(&data.db, &token).await.unwrap(),
+            Some(7)
+        );
+        assert_eq!(consume_refresh_token(&data.db, &token).await.unwrap(), None);
+
+        let expired = create_refresh_token(&data.db, 7, Duration::ZERO)
+            .await
+            .unwrap();
+        let revoked = create_refresh_token(&data.db, 7, REFRESH_TOKEN_TTL)
+            .await
+            .unwrap();
+        revoke_refresh_token(&data.db, &revoked).await.unwrap();
+        assert_eq!(
+            consume_refresh_token(&data.db, &expired).await.unwrap(),
+            None
+        );
+        assert_eq!(
+            consume_refresh_token(&data.db, &revoked).await.unwrap(),
+            None
+        );
+
+        let left: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM refresh_tokens")
+            .fetch_one(&data.db)
+            .await
+            .unwrap();
+        assert_eq!(left, 0);
+    }
+
+    #[actix_web::test]
+    async fn role_extractor_admits_the_role_and_above() {
+        let data = web::Data::new(testing::app_data().await);
+        let app = init_service(
+            App::new()
+                .app_data(data.clone())
+                .route(
+                    "/notes",
+                    web::get()
+                        .to(|user: Role<role::User>| async move { user.claims.sub.to_string() }),
+                )
+                .route(
+                    "/admin",
+                    web::get().to(|user: AdminUser| async move { user.claims.sub.to_string() }),
+                ),
+        )
+        .await;
+        let as_role = |uri: &str, role| {
+            testing::signed_in(TestRequest::get().uri(uri), &data, 7, role).to_request()
+        };
+
+        for (uri, role, allowed) in [
+            ("/notes", UserRole::User, true),
+            ("/notes", UserRole::Admin, true),
+            ("/notes", UserRole::None, false),
+            ("/admin", UserRole::Admin, true),
+            ("/admin", UserRole::User, false),
+        ] {
+            let res = call_service(&app, as_role(uri, role)).await;
+            if allowed {
+                assert_eq!(res.status(), StatusCode::OK, "{role} on {uri}");
+            } else {
+                assert_eq!(res.status(), StatusCode::FOUND, "{role} on {uri}");
+                assert_eq!(res.headers().get(LOCATION).unwrap(), "/login");
             }
-            Err(e) => Err(e),
+        }
+
+        let res = call_service(&app, TestRequest::get().uri("/notes").to_request()).await;
+        assert_eq!(res.headers().get(LOCATION).unwrap(), "/login");
+    }
+
+    #[actix_web::test]
+    async fn bearer_header_is_read_when_there_is_no_cookie() {
+        let data = web::Data::new(testing::app_data().await);
+        let user_token = testing::token(&data, 7, UserRole::User);
+        let admin_token = testing::token(&data, 1, UserRole::Admin);
+        let claims_of = |req: TestRequest| read_jwt(&req.app_data(data.clone()).to_http_request());
+
+        let bearer =
+            TestRequest::get().insert_header((AUTHORIZATION, format!("Bearer {user_token}")));
+        assert_eq!(claims_of(bearer).unwrap().sub, 7);
+
+        let lowercase =
+            TestRequest::get().insert_header((AUTHORIZATION, format!("bearer  {user_token} ")));
+        assert_eq!(claims_of(lowercase).unwrap().sub, 7);
+
+        // The cookie wins over the header
+        let both = TestRequest::get()
+            .cookie(Cookie::new("token", admin_token))
+            .insert_header((AUTHORIZATION, format!("Bearer {user_token}")));
+        assert_eq!(claims_of(both).unwrap().sub, 1);
+
+        for header in [
+            format!("Basic {user_token}"),
+            "Bearer".to_string(),
+            "Bearer ".to_string(),
+        ] {
+            let req = TestRequest::get().insert_header((AUTHORIZATION, header.clone()));
+            assert!(
+                matches!(claims_of(req), Err(JwtError::TokenNotFound)),
+                "{header}"
+            );
+        }
+    }
+
+    #[actix_web::test]
+    async fn logout_clears_cookies_with_the_login_attributes() {
+        let mut data = testing::app_data().await;
+        data.cookie.domain = Some("example.com".to_string());
+        data.cookie.path = "/app".to_string();
+        data.cookie.same_site = SameSite::Lax;
+        data.cookie.secure = true;
+        let data = web::Data::new(data);
+        let app = init_service(
+            App::new()
+                .app_data(data.clone())
+                .route("/logout", web::get().to(logout)),
+        )
+        .await;
+
+        let res = call_service(&app, TestRequest::get().uri("/logout").to_request()).await;
+        assert_eq!(res.status(), StatusCode::SEE_OTHER);
+        assert_eq!(res.headers().get(LOCATION).unwrap(), "/login");
+
+        let req = TestRequest::default().to_http_request();
+        let cleared: Vec<_> = res.response().cookies().collect();
+        assert_eq!(cleared.len(), 2);
+        for cookie in cleared {
+            let login = session_cookie(
+                &req,
+                &data,
+                cookie.name(),
+                "jwt".to_string(),
+                Duration::from_hours(1),
+            );
+            assert_eq!(cookie.value(), "");
+            assert_eq!(cookie.max_age(), Some(CookieDuration::ZERO));
+            assert_eq!(cookie.domain(), login.domain());
+            assert_eq!(cookie.path(), login.path());
+            assert_eq!(cookie.same_site(), login.same_site());
+            assert_eq!(cookie.secure(), login.secure());
+            assert_eq!(cookie.http_only(), login.http_only());
+        }
+    }
+
+    #[actix_web::test]
+    async fn token_cookie_expires_with_its_token() {
+        let data = testing::app_data().await;
+        let req = TestRequest::default().to_http_request();
+        let jwt = encode_jwt(
+            7,
+            UserRole::User,
+            None,
+            &data.jwt_secret,
+            Duration::from_secs(90),
+        )
+        .unwrap();
+
+        let cookie = token_cookie(&req, &data, jwt);
+        assert_eq!(cookie.name(), data.cookie.name);
+        let max_age = cookie.max_age().unwrap().whole_seconds();
+        assert!((89..=90).contains(&max_age));
+
+        let cookie = token_cookie(&req, &data, "not-a-jwt".to_string());
+        let ttl = i64::try_from(data.jwt_ttl.as_secs()).unwrap();
+        assert_eq!(cookie.max_age().unwrap().whole_seconds(), ttl);
+    }
+
+    #[actix_web::test]
+    async fn session_is_read_from_the_configured_cookie_name() {
+        let mut data = testing::app_data().await;
+        data.cookie.name = "app_session".to_string();
+        let data = web::Data::new(data);
+        let token = testing::token(&data, 7, UserRole::User);
+
+        let req = TestRequest::get()
+            .cookie(Cookie::new("app_session", token.clone()))
+            .app_data(data.clone())
+            .to_http_request();
+        assert_eq!(read_jwt(&req).unwrap().sub, 7);
+
+        // Another app's `token` cookie on the same domain isn't picked up
+        let req = TestRequest::get()
+            .cookie(Cookie::new("token", token))
+            .app_data(data.clone())
+            .to_http_request();
+        assert!(matches!(read_jwt(&req), Err(JwtError::TokenNotFound)));
+
+        assert_eq!(data.cookie.refresh_name(), "refresh_app_session");
+        assert_eq!(data.cookie.csrf_name(), "csrf_app_session");
+    }
+
+    #[actix_web::test]
+    async fn async_hashing_leaves_the_worker_free() {
+        let started = std::time::Instant::now();
+        let ((hash, hashed_at), ticked_at) = futures::join!(
+            async {
+                (
+                    hash_password_async("hunter2", Argon2Params::default()).await,
+                    started.elapsed(),
+                )
+            },
+            async {
+                actix_web::rt::time::sleep(Duration::from_millis(1)).await;
+                started.elapsed()
+            }
+        );
+
+        // Had argon2 run on the worker, the timer couldn't fire before it finished
+        assert!(ticked_at < hashed_at, "{ticked_at:?} >= {hashed_at:?}");
+        let hash = hash.unwrap();
+        assert!(verify_password_async("hunter2", &hash).await);
+        assert!(!verify_password_async("hunter3", &hash).await);
+    }
+
+    #[test]
+    fn hash_uses_the_given_params_and_weaker_hashes_need_rehash() {
+        let weak = Argon2Params {
+            mem_cost: 1024,
+            time_cost: 1,
+            lanes: 1,
         };
+        let hash = hash_password("correct horse", weak).unwrap();
+        assert!(hash.contains("$m=1024,t=1,p=1$"), "{hash}");
 
-        ready(result)
+        assert!(!needs_rehash(&hash, weak));
+        assert!(needs_rehash(&hash, Argon2Params::default()));
+        assert!(
+            verify_password_detailed("correct horse", &hash, Argon2Params::default())
+                .unwrap()
+                .needs_rehash
+        );
+        assert!(verify_password("correct horse", &hash));
+    }
+
+    #[test]
+    fn verify_reports_rehash_from_the_parsed_hash() {
+        let params = Argon2Params::default();
+        let check = |password, hash| verify_password_detailed(password, hash, params).unwrap();
+
+        // argon2i instead of argon2id
+        let argon2i = "$argon2i$v=19$m=4096,t=3,p=1$c29tZXNhbHQ\
+                       $iWh06vD8Fy27wf9npn6FXWiCX4K6pW6Ue1Bnzz07Z8A";
+        assert_eq!(
+            check("password", argon2i),
+            VerifyResult {
+                ok: true,
+                needs_rehash: true,
+            }
+        );
+        assert_eq!(
+            check("wrong", argon2i),
+            VerifyResult {
+                ok: false,
+                needs_rehash: false,
+            }
+        );
+
+        // argon2 1.0 hashes carry no version part
+        let config = Config {
+            version: Version::Version10,
+            mem_cost: params.mem_cost,
+            time_cost: params.time_cost,
+            lanes: params.lanes,
+            ..Config::default()
+        };
+        let v10 = argon2::hash_encoded(b"password", b"somesalt", &config)
+            .unwrap()
+            .replace("$v=16$", "$");
+        assert!(check("password", &v10).ok);
+        assert!(check("password", &v10).needs_rehash);
+
+        let current = hash_password("password", params).unwrap();
+        assert_eq!(
+            check("password", &current),
+            VerifyResult {
+                ok: true,
+                needs_rehash: false,
+            }
+        );
+        let reordered = current.replacen("m=19456,t=2", "t=2,m=19456", 1);
+        assert_ne!(reordered, current);
+        assert!(verify_password_detailed("password", &reordered, params).is_err());
+    }
+
+    #[actix_web::test]
+    async fn optional_auth_user_serves_guests_and_members() {
+        let data = web::Data::new(testing::app_data().await);
+        let app = init_service(App::new().app_data(data.clone()).route(
+            "/",
+            web::get().to(|OptionalAuthUser(claims): OptionalAuthUser| async move {
+                match claims {
+                    Some(claims) => HttpResponse::Ok().body(format!("dashboard {}", claims.sub)),
+                    None => HttpResponse::Ok().body("login"),
+                }
+            }),
+        ))
+        .await;
+
+        let req = testing::signed_in(TestRequest::get().uri("/"), &data, 7, UserRole::User);
+        let res = call_service(&app, req.to_request()).await;
+        assert_eq!(read_body(res).await, "dashboard 7");
+
+        // Neither a missing nor an invalid token redirects
+        for req in [
+            TestRequest::get().uri("/"),
+            TestRequest::get()
+                .uri("/")
+                .cookie(Cookie::new("token", "not a jwt")),
+        ] {
+            let res = call_service(&app, req.to_request()).await;
+            assert_eq!(res.status(), StatusCode::OK);
+            assert_eq!(read_body(res).await, "login");
+        }
+    }
+
+    #[test]
+    fn totp_code_matches_the_rfc_test_vector() {
+        // RFC 6238 appendix B: key "12345678901234567890", T = 59s gives 94287082
+        let key = decode_base32("GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ").unwrap();
+        assert_eq!(key, b"12345678901234567890");
+        assert_eq!(totp_code(&key, 59 / TOTP_STEP_SECONDS).unwrap(), "287082");
+    }
+
+    #[test]
+    fn totp_accepts_neighbouring_steps_and_rejects_replays() {
+        let secret = generate_totp_secret();
+        let key = decode_base32(&secret).unwrap();
+        let current = Utc::now().timestamp().div_euclid(TOTP_STEP_SECONDS);
+        let code = |step| totp_code(&key, step).unwrap();
+
+        for step in [current - 1, current, current + 1] {
+            assert_eq!(verify_totp_code(&secret, &code(step), None), Some(step));
+        }
+        assert_eq!(verify_totp_code(&secret, &code(current + 3), None), None);
+        assert_eq!(verify_totp_code(&secret, "12345", None), None);
+
+        // A code of the step that was last accepted, or an earlier one, is a replay
+        assert_eq!(
+            verify_totp_code(&secret, &code(current), Some(current)),
+            None
+        );
+        assert_eq!(
+            verify_totp_code(&secret, &code(current - 1), Some(current)),
+            None
+        );
+        assert_eq!(
+            verify_totp_code(&secret, &code(current + 1), Some(current)),
+            Some(current + 1)
+        );
+    }
+
+    #[actix_web::test]
+    async fn failures_lock_the_account_until_the_cooldown_ends() {
+        let data = testing::app_data().await;
+        sqlx::query(
+            "CREATE TABLE login_attempts (
+                email TEXT PRIMARY KEY NOT NULL,
+                failures INTEGER NOT NULL DEFAULT 0,
+                locked_until TIMESTAMP
+            )",
+        )
+        .execute(&data.db)
+        .await
+        .unwrap();
+        let policy = LockoutPolicy {
+            max_failures: 3,
+            cooldown: Duration::from_mins(15),
+        };
+
+        for _ in 0..2 {
+            record_login_failure(&data.db, "Ada@Example.com", policy)
+                .await
+                .unwrap();
+        }
+        assert!(!login_locked(&data.db, "ada@example.com").await.unwrap());
+        record_login_failure(&data.db, "ada@example.com ", policy)
+            .await
+            .unwrap();
+        assert!(login_locked(&data.db, "ada@example.com").await.unwrap());
+
+        reset_login_failures(&data.db, "ada@example.com")
+            .await
+            .unwrap();
+        assert!(!login_locked(&data.db, "ada@example.com").await.unwrap());
+
+        // An expired lock doesn't lock and its row goes with the next failure
+        sqlx::query("INSERT INTO login_attempts (email, failures, locked_until) VALUES (?, 0, ?)")
+            .bind("grace@example.com")
+            .bind(Utc::now().naive_utc() - Duration::from_secs(1))
+            .execute(&data.db)
+            .await
+            .unwrap();
+        assert!(!login_locked(&data.db, "grace@example.com").await.unwrap());
+        record_login_failure(&data.db, "ada@example.com", policy)
+            .await
+            .unwrap();
+        let emails: Vec<String> = sqlx::query_scalar("SELECT email FROM login_attempts")
+            .fetch_all(&data.db)
+            .await
+            .unwrap();
+        assert_eq!(emails, ["ada@example.com"]);
+    }
+
+    #[actix_web::test]
+    async fn reset_tokens_work_once_and_stale_ones_are_pruned() {
+        let data = testing::app_data().await;
+        sqlx::query(
+            "CREATE TABLE password_resets (
+                token_hash TEXT PRIMARY KEY NOT NULL,
+                user_id INTEGER NOT NULL,
+                expires_at TIMESTAMP NOT NULL,
+                used BOOLEAN NOT NULL DEFAULT FALSE
+            )",
+        )
+        .execute(&data.db)
+        .await
+        .unwrap();
+
+        let token = create_reset_token(&data.db, 7, RESET_TOKEN_TTL)
+            .await
+            .unwrap();
+        assert_eq!(
+            consume_reset_token(&data.db, &token).await.unwrap(),
+            Some(7)
+        );
+        assert_eq!(consume_reset_token(&data.db, &token).await.unwrap(), None);
+        assert_eq!(
+            consume_reset_token(&data.db, "unknown").await.unwrap(),
+            None
+        );
+
+        let expired = create_reset_token(&data.db, 8, Duration::ZERO)
+            .await
+            .unwrap();
+        assert_eq!(consume_reset_token(&data.db, &expired).await.unwrap(), None);
+
+        // the used and the expired token go with the next one issued
+        create_reset_token(&data.db, 9, RESET_TOKEN_TTL)
+            .await
+            .unwrap();
+        let users: Vec<i64> = sqlx::query_scalar("SELECT user_id FROM password_resets")
+            .fetch_all(&data.db)
+            .await
+            .unwrap();
+        assert_eq!(users, [9]);
+    }
+
+    #[actix_web::test]
+    async fn api_key_authenticates_until_revoked() {
+        let data = web::Data::new(testing::app_data().await);
+        sqlx::query(
+            "CREATE TABLE api_keys (
+                key_hash TEXT PRIMARY KEY NOT NULL,
+                role TEXT NOT NULL,
+                revoked BOOLEAN NOT NULL DEFAULT FALSE,
+                created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+            )",
+        )
+        .execute(&data.db)
+        .await
+        .unwrap();
+        let key = create_api_key(&data.db, UserRole::Admin).await.unwrap();
+        let app = init_service(App::new().app_data(data.clone()).route(
+            "/",
+            web::get().to(|key: ApiKey| async move {
+                HttpResponse::Ok().body(format!("{} {}", key.claims.sub, key.claims.role))
+            }),
+        ))
+        .await;
+        let with_key = |key: &str| {
+            TestRequest::get()
+                .uri("/")
+                .insert_header((API_KEY_HEADER, key))
+                .to_request()
+        };
+
+        let res = call_service(&app, with_key(&key)).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(read_body(res).await, "0 admin");
+
+        assert!(revoke_api_key(&data.db, &key).await.unwrap());
+        assert!(!revoke_api_key(&data.db, &key).await.unwrap());
+
+        // Revoked, unknown and missing keys can't be told apart
+        let revoked = call_service(&app, with_key(&key)).await;
+        let unknown = call_service(&app, with_key("guess")).await;
+        let missing = call_service(&app, TestRequest::get().uri("/").to_request()).await;
+        for res in [revoked, unknown, missing] {
+            assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+            assert_eq!(
+                res.response().extensions().get::<String>().unwrap(),
+                "Invalid API key"
+            );
+        }
+    }
+
+    #[test]
+    fn auth_error_displays_and_converts_into_app_error() {
+        let redirect = AuthError::from(JwtError::JwtExpired);
+        assert_eq!(
+            redirect.to_string(),
+            "Authentication required, redirecting to /login"
+        );
+        assert!(matches!(AppError::from(redirect), AppError::NoAuth));
+
+        let other = AuthError::from(JwtError::SecretNotSet);
+        assert!(other.to_string().starts_with("Authentication failed: "));
+        assert!(matches!(AppError::from(other), AppError::Internal(_)));
+
+        // Handlers returning `AppResult` can use `?` on it
+        let handler =
+            || -> crate::error::AppResult<()> { Err(AuthError::from(JwtError::TokenNotFound))? };
+        assert_eq!(
+            actix_web::ResponseError::status_code(&handler().unwrap_err()),
+            StatusCode::UNAUTHORIZED
+        );
+    }
+
+    #[test]
+    fn malformed_hash_is_an_error_not_a_mismatch() {
+        let params = Argon2Params::default();
+        let hash = hash_password("correct horse", params).unwrap();
+
+        assert!(
+            verify_password_detailed("correct horse", &hash, params)
+                .unwrap()
+                .ok
+        );
+        assert!(
+            !verify_password_detailed("wrong horse", &hash, params)
+                .unwrap()
+                .ok
+        );
+        for corrupt in [
+            "",
+            "plaintext",
+            "$argon2id$v=19$broken",
+            &hash[..hash.len() / 2],
+        ] {
+            assert!(
+                verify_password_detailed("correct horse", corrupt, params).is_err(),
+                "{corrupt}"
+            );
+            assert!(!verify_password("correct horse", corrupt));
+        }
     }
 }
@@ -0,0 +1,112 @@
+use crate::AppData;
+use actix_web::{Error, FromRequest, HttpRequest, dev::Payload, error::ErrorBadRequest, web};
+use futures::future::{Ready, ready};
+use std::net::IpAddr;
+
+/// The client's IP address.
+///
+/// This is the socket's peer address, unless `TRUST_PROXY` is set: then the app runs
+/// behind a reverse proxy and the last `X-Forwarded-For` entry (the one the proxy
+/// appended) is used instead. Without `TRUST_PROXY` forwarding headers are ignored,
+/// since any client can send them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientIp(pub IpAddr);
+
+impl ClientIp {
+    /// Resolve the client IP of `req`; `None` if there's no peer address (e.g. in tests).
+    #[must_use]
+    pub fn of(req: &HttpRequest) -> Option<Self> {
+        let trust_proxy = req
+            .app_data::<web::Data<AppData>>()
+            .is_some_and(|data| data.trust_proxy);
+
+        let forwarded = trust_proxy
+            .then(|| {
+                req.headers()
+                    .get_all("x-forwarded-for")
+                    .filter_map(|value| value.to_str().ok())
+                    .flat_map(|value| value.split(','))
+                    .last()
+                    .and_then(|ip| ip.trim().parse::<IpAddr>().ok())
+            })
+            .flatten();
+
+        forwarded
+            .or_else(|| req.peer_addr().map(|addr| addr.ip()))
+            .map(|ip| ClientIp(ip.to_canonical()))
+    }
+}
+
+impl std::fmt::Display for ClientIp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl FromRequest for ClientIp {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(Self::of(req).ok_or_else(|| ErrorBadRequest("Client address unknown")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use actix_web::{
+        App,
+        http::StatusCode,
+        test::{TestRequest, call_service, init_service, read_body},
+    };
+    use std::net::SocketAddr;
+
+    async fn client_ip_of(trust_proxy: bool, req: TestRequest) -> (StatusCode, String) {
+        let mut data = testing::app_data().await;
+        data.trust_proxy = trust_proxy;
+        let app = init_service(App::new().app_data(web::Data::new(data)).route(
+            "/",
+            web::get().to(|ip: ClientIp| async move { ip.to_string() }),
+        ))
+        .await;
+
+        let res = call_service(&app, req.to_request()).await;
+        let status = res.status();
+        (
+            status,
+            String::from_utf8(read_body(res).await.to_vec()).unwrap(),
+        )
+    }
+
+    fn forwarded() -> TestRequest {
+        TestRequest::get()
+            .peer_addr(SocketAddr::from(([10, 0, 0, 2], 40000)))
+            .insert_header(("x-forwarded-for", "198.51.100.7, 203.0.113.9"))
+    }
+
+    #[actix_web::test]
+    async fn forwarded_address_is_used_only_behind_a_trusted_proxy() {
+        assert_eq!(
+            client_ip_of(true, forwarded()).await,
+            (StatusCode::OK, "203.0.113.9".to_string())
+        );
+        assert_eq!(
+            client_ip_of(false, forwarded()).await,
+            (StatusCode::OK, "10.0.0.2".to_string())
+        );
+    }
+
+    #[actix_web::test]
+    async fn mapped_ipv6_peer_is_reported_as_ipv4() {
+        let req = TestRequest::get().peer_addr("[::ffff:192.0.2.1]:40000".parse().unwrap());
+        assert_eq!(
+            client_ip_of(false, req).await,
+            (StatusCode::OK, "192.0.2.1".to_string())
+        );
+
+        let (status, _) = client_ip_of(false, TestRequest::get()).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+}
@@ -0,0 +1,131 @@
+use actix_web::{
+    HttpRequest,
+    http::header::{self, ContentEncoding, Encoding},
+    web::Bytes,
+};
+use log::{debug, error};
+use std::{
+    collections::HashMap,
+    io::Write,
+    sync::{LazyLock, PoisonError, RwLock},
+};
+
+/// Files smaller than this aren't worth compressing.
+const MIN_SIZE: usize = 1024;
+
+/// Brotli and gzip encodings of embedded assets, compressed on first request and kept
+/// for the lifetime of the process (the files are baked into the binary, so they
+/// never change). Used when `COMPRESS_ASSETS` is set.
+pub(crate) static ASSETS: LazyLock<CompressedAssets> = LazyLock::new(CompressedAssets::default);
+
+#[derive(Default)]
+pub(crate) struct CompressedAssets {
+    cache: RwLock<HashMap<(String, ContentEncoding), Bytes>>,
+}
+
+impl CompressedAssets {
+    /// The encoding the client prefers among those we offer and the compressed body,
+    /// or `None` if the file should be sent as is.
+    pub(crate) fn get(
+        &self,
+        req: &HttpRequest,
+        path: &str,
+        content_type: &str,
+        contents: &[u8],
+    ) -> Option<(ContentEncoding, Bytes)> {
+        if contents.len() < MIN_SIZE || !is_compressible(content_type) {
+            return None;
+        }
+
+        let accept = <header::AcceptEncoding as header::Header>::parse(req).ok()?;
+        let offered = [Encoding::brotli(), Encoding::gzip()];
+        let Encoding::Known(encoding @ (ContentEncoding::Brotli | ContentEncoding::Gzip)) =
+            accept.negotiate(offered.iter())?
+        else {
+            return None;
+        };
+
+        let key = (path.to_string(), encoding);
+        if let Some(body) = self
+            .cache
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(&key)
+        {
+            return Some((encoding, body.clone()));
+        }
+
+        let body = Bytes::from(compress(encoding, contents)?);
+        debug!("Compressed {path} with {encoding:?}");
+        self.cache
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(key, body.clone());
+        Some((encoding, body))
+    }
+}
+
+fn is_compressible(content_type: &str) -> bool {
+    content_type.starts_with("text/")
+        || content_type.starts_with("image/svg")
+        || content_type.starts_with("application/javascript")
+        || content_type.starts_with("application/json")
+        || content_type.starts_with("application/manifest+json")
+        || content_type.starts_with("application/wasm")
+        || content_type.starts_with("application/xml")
+}
+
+fn compress(encoding: ContentEncoding, contents: &[u8]) -> Option<Vec<u8>> {
+    let result = if encoding == ContentEncoding::Brotli {
+        let mut out = Vec::new();
+        let params = brotli::enc::BrotliEncoderParams::default();
+        brotli::BrotliCompress(&mut &contents[..], &mut out, &params).map(|_| out)
+    } else {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::best());
+        encoder.write_all(contents).and_then(|()| encoder.finish())
+    };
+
+    result
+        .inspect_err(|e| error!("Failed to compress asset with {encoding:?}: {e}"))
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[actix_web::test]
+    async fn asset_is_compressed_once_and_then_served_from_cache() {
+        let assets = CompressedAssets::default();
+        let contents = "body { color: red; }\n".repeat(100);
+        let req = TestRequest::default()
+            .insert_header((header::ACCEPT_ENCODING, "br, gzip"))
+            .to_http_request();
+
+        let (encoding, first) = assets
+            .get(&req, "style.css", "text/css", contents.as_bytes())
+            .unwrap();
+        let (_, second) = assets
+            .get(&req, "style.css", "text/css", contents.as_bytes())
+            .unwrap();
+
+        assert_eq!(encoding, ContentEncoding::Brotli);
+        assert!(first.len() < contents.len());
+        // The second request gets the cached bytes rather than a fresh compression
+        assert_eq!(first.as_ptr(), second.as_ptr());
+        assert_eq!(assets.cache.read().unwrap().len(), 1);
+    }
+
+    #[actix_web::test]
+    async fn small_or_binary_files_are_sent_as_is() {
+        let assets = CompressedAssets::default();
+        let req = TestRequest::default()
+            .insert_header((header::ACCEPT_ENCODING, "gzip"))
+            .to_http_request();
+
+        assert!(assets.get(&req, "a.css", "text/css", b"tiny").is_none());
+        assert!(assets.get(&req, "a.png", "image/png", &[0; 4096]).is_none());
+        assert!(assets.cache.read().unwrap().is_empty());
+    }
+}
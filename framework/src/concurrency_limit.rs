@@ -0,0 +1,140 @@
+use actix_web::{
+    Error, HttpResponse,
+    body::EitherBody,
+    dev::{Service, ServiceRequest, ServiceResponse, Transform, forward_ready},
+};
+use futures::future::{LocalBoxFuture, Ready, ready};
+use log::warn;
+use std::{
+    collections::HashMap,
+    rc::Rc,
+    sync::{Arc, LazyLock, Mutex, PoisonError},
+};
+use tokio::sync::Semaphore;
+
+/// Semaphores by group name. Routes are configured once per worker, so the limit has
+/// to live outside the middleware to apply to the whole server.
+static GROUPS: LazyLock<Mutex<HashMap<&'static str, Arc<Semaphore>>>> =
+    LazyLock::new(Mutex::default);
+
+/// Bulkhead for expensive endpoints: at most `max` requests of `group` are handled at
+/// once, further ones get the themed `503 Service Unavailable` page right away.
+///
+/// Unlike the rate limiters this doesn't care how often a client calls, only how many
+/// calls are in flight, so one slow endpoint (e.g. report generation) can't tie up
+/// every worker. Wrap all routes that should share the limit with the same group:
+///
+/// ```ignore
+/// web::resource("/reports/export")
+///     .wrap(concurrency_limit("reports", 2))
+///     .route(web::get().to(export))
+/// ```
+///
+/// The first call for a group fixes its limit. A request holds its slot until the
+/// handler has produced the response; streamed bodies are sent after releasing it.
+pub fn concurrency_limit(group: &'static str, max: usize) -> ConcurrencyLimit {
+    let permits = GROUPS
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .entry(group)
+        .or_insert_with(|| Arc::new(Semaphore::new(max)))
+        .clone();
+
+    ConcurrencyLimit { group, permits }
+}
+
+#[derive(Clone)]
+pub struct ConcurrencyLimit {
+    group: &'static str,
+    permits: Arc<Semaphore>,
+}
+
+impl<S, B> Transform<S, ServiceRequest> for ConcurrencyLimit
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = ConcurrencyLimitService<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(ConcurrencyLimitService {
+            service: Rc::new(service),
+            limit: self.clone(),
+        }))
+    }
+}
+
+pub struct ConcurrencyLimitService<S> {
+    service: Rc<S>,
+    limit: ConcurrencyLimit,
+}
+
+impl<S, B> Service<ServiceRequest> for ConcurrencyLimitService<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let Ok(permit) = Arc::clone(&self.limit.permits).try_acquire_owned() else {
+            warn!(
+                "Concurrency limit of {} reached, rejecting {}",
+                self.limit.group,
+                req.path()
+            );
+            let res = req.into_response(HttpResponse::ServiceUnavailable().finish());
+            return Box::pin(ready(Ok(res.map_into_right_body())));
+        };
+
+        let service = Rc::clone(&self.service);
+        Box::pin(async move {
+            let res = service.call(req).await;
+            drop(permit);
+            res.map(ServiceResponse::map_into_left_body)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{
+        App,
+        http::StatusCode,
+        test::{TestRequest, call_service, init_service},
+        web,
+    };
+
+    #[actix_web::test]
+    async fn request_beyond_the_limit_gets_503() {
+        let app = init_service(
+            App::new().service(
+                web::resource("/reports")
+                    .wrap(concurrency_limit("test_reports", 2))
+                    .route(web::get().to(HttpResponse::Ok)),
+            ),
+        )
+        .await;
+        let req = || TestRequest::get().uri("/reports").to_request();
+
+        // A call takes its slot right away and keeps it until the response is done
+        let first = app.call(req());
+        let second = app.call(req());
+        let res = call_service(&app, req()).await;
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        assert_eq!(first.await.unwrap().status(), StatusCode::OK);
+        assert_eq!(second.await.unwrap().status(), StatusCode::OK);
+        let res = call_service(&app, req()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+}
@@ -1,10 +1,97 @@
-use chrono::Local;
+use chrono::{DateTime, Local, Utc};
+use futures::future::BoxFuture;
 use log::{error, info};
+use serde::Serialize;
 use std::env;
-use std::fs::{create_dir_all, OpenOptions};
+use std::fs::{OpenOptions, create_dir_all};
 use std::io::Write;
 use std::path::PathBuf;
+use std::sync::{Arc, Mutex, PoisonError};
 use tokio_cron_scheduler::{Job, JobScheduler, JobSchedulerError};
+use uuid::Uuid;
+
+struct RegisteredJob {
+    id: Uuid,
+    name: String,
+    schedule: String,
+    run_on_start: bool,
+}
+
+static REGISTRY: Mutex<Vec<RegisteredJob>> = Mutex::new(Vec::new());
+
+/// Runs of `run_on_start` jobs waiting for the server to be ready.
+static STARTUP_RUNS: Mutex<Vec<BoxFuture<'static, ()>>> = Mutex::new(Vec::new());
+
+#[derive(Serialize, Debug, Clone)]
+pub struct JobInfo {
+    pub name: String,
+    pub schedule: String,
+    pub next_run: Option<DateTime<Utc>>,
+    /// Also ran once when the server started.
+    pub run_on_start: bool,
+}
+
+fn register(id: Uuid, name: &str, schedule: &str, run_on_start: bool) {
+    REGISTRY
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .push(RegisteredJob {
+            id,
+            name: name.to_string(),
+            schedule: schedule.to_string(),
+            run_on_start,
+        });
+}
+
+fn queue_startup_run(run: BoxFuture<'static, ()>) {
+    STARTUP_RUNS
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .push(run);
+}
+
+/// Start the queued runs of `run_on_start` jobs, once the server is listening.
+pub(crate) fn run_startup_jobs() {
+    let runs = std::mem::take(&mut *STARTUP_RUNS.lock().unwrap_or_else(PoisonError::into_inner));
+    for run in runs {
+        actix_web::rt::spawn(run);
+    }
+}
+
+/// Drop queued startup runs, e.g. when cron isn't started after all.
+pub(crate) fn clear_startup_jobs() {
+    STARTUP_RUNS
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clear();
+}
+
+/// All jobs registered through `add_job`/`add_async_job`, with their next scheduled run.
+pub async fn jobs(sched: &JobScheduler) -> Vec<JobInfo> {
+    let registered: Vec<(Uuid, JobInfo)> = REGISTRY
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .iter()
+        .map(|job| {
+            let info = JobInfo {
+                name: job.name.clone(),
+                schedule: job.schedule.clone(),
+                next_run: None,
+                run_on_start: job.run_on_start,
+            };
+            (job.id, info)
+        })
+        .collect();
+
+    let mut sched = sched.clone();
+    let mut jobs = Vec::with_capacity(registered.len());
+    for (id, mut info) in registered {
+        info.next_run = sched.next_tick_for_job(id).await.ok().flatten();
+        jobs.push(info);
+    }
+
+    jobs
+}
 
 pub async fn add_job<F>(
     sched: &JobScheduler,
@@ -15,16 +102,54 @@ pub async fn add_job<F>(
 where
     F: Fn() -> Result<(), Box<dyn std::error::Error>> + Send + Sync + 'static,
 {
-    let job_name = job_name.to_string();
+    add_job_with(sched, job_name, schedule, false, job_action).await
+}
+
+/// Like `add_job`; with `run_on_start` the job also runs once as soon as the server
+/// is listening, e.g. to warm a cache before the first scheduled tick.
+///
+/// # Errors
+///
+/// Returns `JobSchedulerError` if `schedule` isn't a valid cron expression or the job
+/// can't be added.
+pub async fn add_job_with<F>(
+    sched: &JobScheduler,
+    job_name: &str,
+    schedule: &str,
+    run_on_start: bool,
+    job_action: F,
+) -> Result<(), JobSchedulerError>
+where
+    F: Fn() -> Result<(), Box<dyn std::error::Error>> + Send + Sync + 'static,
+{
+    let name = job_name.to_string();
+    let job_action = Arc::new(job_action);
 
-    sched
+    let scheduled_action = job_action.clone();
+    let id = sched
         .add(Job::new(schedule, move |_uuid, _l| {
-            let job_name = job_name.clone();
-            if let Err(e) = execute_job(&job_name, &job_action) {
-                error!("Job {} failed: {}", job_name, e);
+            let job_name = name.clone();
+            if let Err(e) = execute_job(&job_name, &*scheduled_action) {
+                error!("Job {job_name} failed: {e}");
             }
         })?)
         .await?;
+    register(id, job_name, schedule, run_on_start);
+
+    if run_on_start {
+        let job_name = job_name.to_string();
+        queue_startup_run(Box::pin(async move {
+            let run = actix_web::rt::task::spawn_blocking(move || {
+                execute_job(&job_name, &*job_action)
+                    .map_err(|e| format!("Job {job_name} failed: {e}"))
+            });
+            match run.await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => error!("{e}"),
+                Err(e) => error!("Startup run of a job panicked: {e}"),
+            }
+        }));
+    }
 
     Ok(())
 }
@@ -39,19 +164,50 @@ where
     F: Fn() -> Fut + Send + Clone + Sync + 'static,
     Fut: std::future::Future<Output = Result<(), Box<dyn std::error::Error>>> + Send + 'static,
 {
-    let job_name = job_name.to_string();
+    add_async_job_with(sched, job_name, schedule, false, job_action).await
+}
 
-    sched
+/// Like `add_async_job`; with `run_on_start` the job also runs once as soon as the
+/// server is listening.
+///
+/// # Errors
+///
+/// Same as `add_job_with`.
+pub async fn add_async_job_with<F, Fut>(
+    sched: &JobScheduler,
+    job_name: &str,
+    schedule: &str,
+    run_on_start: bool,
+    job_action: F,
+) -> Result<(), JobSchedulerError>
+where
+    F: Fn() -> Fut + Send + Clone + Sync + 'static,
+    Fut: std::future::Future<Output = Result<(), Box<dyn std::error::Error>>> + Send + 'static,
+{
+    let name = job_name.to_string();
+
+    let scheduled_action = job_action.clone();
+    let id = sched
         .add(Job::new_async(schedule, move |_uuid, _l| {
-            let job_name = job_name.clone();
-            let job_action = job_action.clone();
+            let job_name = name.clone();
+            let job_action = scheduled_action.clone();
             Box::pin(async move {
                 if let Err(e) = execute_job_async(&job_name, job_action).await {
-                    error!("Job {} failed: {}", job_name, e);
+                    error!("Job {job_name} failed: {e}");
                 }
             })
         })?)
         .await?;
+    register(id, job_name, schedule, run_on_start);
+
+    if run_on_start {
+        let job_name = job_name.to_string();
+        queue_startup_run(Box::pin(async move {
+            if let Err(e) = execute_job_async(&job_name, job_action).await {
+                error!("Job {job_name} failed: {e}");
+            }
+        }));
+    }
 
     Ok(())
 }
@@ -161,3 +317,55 @@ fn create_log_file(job_name: &str, datetime: &str) -> std::io::Result<PathBuf> {
 
     Ok(log_file_path)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[actix_web::test]
+    async fn registered_job_is_listed_with_its_schedule() {
+        let sched = JobScheduler::new().await.unwrap();
+        add_job(&sched, "nightly-report", "0 0 3 * * *", || Ok(()))
+            .await
+            .unwrap();
+
+        let jobs = jobs(&sched).await;
+        let job = jobs
+            .iter()
+            .find(|job| job.name == "nightly-report")
+            .unwrap();
+        assert_eq!(job.schedule, "0 0 3 * * *");
+        assert!(!job.run_on_start);
+    }
+
+    #[actix_web::test]
+    async fn run_on_start_job_runs_at_startup_and_stays_scheduled() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::time::Duration;
+
+        static RUNS: AtomicUsize = AtomicUsize::new(0);
+        let sched = JobScheduler::new().await.unwrap();
+        add_async_job_with(&sched, "cache-warmer", "0 0 4 * * *", true, || async {
+            RUNS.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        })
+        .await
+        .unwrap();
+        assert_eq!(RUNS.load(Ordering::SeqCst), 0);
+
+        run_startup_jobs();
+        for _ in 0..100 {
+            if RUNS.load(Ordering::SeqCst) > 0 {
+                break;
+            }
+            actix_web::rt::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(RUNS.load(Ordering::SeqCst), 1);
+
+        sched.start().await.unwrap();
+        let jobs = jobs(&sched).await;
+        let job = jobs.iter().find(|job| job.name == "cache-warmer").unwrap();
+        assert!(job.run_on_start);
+        assert!(job.next_run.is_some());
+    }
+}
@@ -0,0 +1,244 @@
+use crate::{AppData, auth::REFRESH_TOKEN_TTL, error::ErrorCode};
+use actix_web::{
+    Error, FromRequest, HttpMessage, HttpRequest, HttpResponse,
+    body::MessageBody,
+    dev::{Payload, ServiceRequest, ServiceResponse},
+    error::InternalError,
+    http::Method,
+    middleware::Next,
+    web,
+};
+use futures::future::{Ready, ready};
+use log::warn;
+use rand::{RngCore, rng};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::fmt::Write;
+
+/// Header scripts send the CSRF token in, e.g. for `fetch` calls with `DELETE`.
+pub const CSRF_HEADER: &str = "x-csrf-token";
+
+/// Name of the hidden form field carrying the CSRF token.
+pub const CSRF_FIELD: &str = "csrf";
+
+tokio::task_local! {
+    /// Token of the request being handled, for the `csrf` template variable.
+    static CSRF_TOKEN: String;
+}
+
+/// The CSRF token of the request being handled, if called from within a handler.
+pub(crate) fn current_token() -> Option<String> {
+    CSRF_TOKEN.try_with(Clone::clone).ok()
+}
+
+/// Token submitted in the `csrf` field of a url-encoded form body.
+struct SubmittedToken(Option<String>);
+
+#[derive(Deserialize)]
+struct CsrfForm {
+    csrf: Option<String>,
+}
+
+/// Hands out the CSRF token: the one in the CSRF cookie, or a new one that is set as
+/// the cookie on the response. Templates get it as `csrf`.
+///
+/// The token in a url-encoded form body is read here, where the body can still be
+/// put back for the handler's `web::Form`.
+pub(crate) async fn issue(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let Some(data) = req.app_data::<web::Data<AppData>>().cloned() else {
+        return next.call(req).await;
+    };
+
+    let cookie_name = data.cookie.csrf_name();
+    let existing = req
+        .cookie(&cookie_name)
+        .map(|cookie| cookie.value().to_string())
+        .filter(|token| token.len() == 64 && token.bytes().all(|b| b.is_ascii_hexdigit()));
+    let token = existing.clone().unwrap_or_else(new_token);
+
+    if !is_safe(req.method()) && req.content_type() == "application/x-www-form-urlencoded" {
+        let body = req.extract::<web::Bytes>().await?;
+        let submitted = std::str::from_utf8(&body)
+            .ok()
+            .and_then(|query| web::Query::<CsrfForm>::from_query(query).ok())
+            .and_then(|form| form.into_inner().csrf);
+        req.extensions_mut().insert(SubmittedToken(submitted));
+        req.set_payload(body.into());
+    }
+
+    let mut res = CSRF_TOKEN.scope(token.clone(), next.call(req)).await?;
+    if existing.is_none() {
+        let cookie = crate::auth::session_cookie(
+            res.request(),
+            &data,
+            &cookie_name,
+            token,
+            REFRESH_TOKEN_TTL,
+        );
+        if let Err(e) = res.response_mut().add_cookie(&cookie) {
+            warn!("Could not set the CSRF cookie: {e}");
+        }
+    }
+    Ok(res)
+}
+
+fn new_token() -> String {
+    let mut bytes = [0u8; 32];
+    rng().fill_bytes(&mut bytes);
+    bytes.iter().fold(String::new(), |mut s, b| {
+        let _ = write!(s, "{b:02x}");
+        s
+    })
+}
+
+fn is_safe(method: &Method) -> bool {
+    matches!(
+        *method,
+        Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE
+    )
+}
+
+/// Double-submit CSRF check for state-changing handlers: the request must carry the
+/// token of the CSRF cookie in the `csrf` form field or the `X-CSRF-Token` header.
+/// A cross-site form can make the browser send the cookie, but can't read it to fill
+/// in the field.
+///
+/// Forms embed `<input type="hidden" name="csrf" value="{{ csrf }}">`, scripts send
+/// the header. `GET`, `HEAD`, `OPTIONS` and `TRACE` requests always pass; a missing or
+/// wrong token gets the themed `403 Forbidden` page.
+#[derive(Debug)]
+pub struct CsrfGuard;
+
+impl FromRequest for CsrfGuard {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        if is_safe(req.method()) {
+            return ready(Ok(CsrfGuard));
+        }
+
+        let name = req
+            .app_data::<web::Data<AppData>>()
+            .map(|data| data.cookie.csrf_name());
+        let expected = name
+            .and_then(|name| req.cookie(&name))
+            .map(|cookie| Sha256::digest(cookie.value().as_bytes()));
+        let header = req
+            .headers()
+            .get(CSRF_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
+        let submitted = header.or_else(|| {
+            req.extensions()
+                .get::<SubmittedToken>()
+                .and_then(|token| token.0.clone())
+        });
+
+        let valid = match (expected, submitted) {
+            (Some(expected), Some(submitted)) => {
+                expected == Sha256::digest(submitted.trim().as_bytes())
+            }
+            _ => false,
+        };
+        if valid {
+            return ready(Ok(CsrfGuard));
+        }
+
+        warn!(
+            "Rejected {} {} without a valid CSRF token",
+            req.method(),
+            req.path()
+        );
+        let mut res = HttpResponse::Forbidden().finish();
+        res.extensions_mut()
+            .insert("The form has expired, please reload the page and try again.".to_string());
+        res.extensions_mut().insert(ErrorCode("CSRF_INVALID"));
+        ready(Err(InternalError::from_response(
+            "CSRF token missing or invalid",
+            res,
+        )
+        .into()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use actix_web::{
+        App,
+        cookie::Cookie,
+        http::StatusCode,
+        middleware::from_fn,
+        test::{TestRequest, call_service, init_service, read_body},
+    };
+
+    const TOKEN: &str = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+
+    #[actix_web::test]
+    async fn guard_checks_form_field_and_header_against_the_cookie() {
+        let data = web::Data::new(testing::app_data().await);
+        let app = init_service(
+            App::new()
+                .app_data(data.clone())
+                .wrap(from_fn(issue))
+                .route(
+                    "/",
+                    web::route().to(|_: CsrfGuard| async {
+                        HttpResponse::Ok().body(current_token().unwrap_or_default())
+                    }),
+                ),
+        )
+        .await;
+        let cookie = || Cookie::new("csrf_token", TOKEN);
+
+        // Safe requests pass and get a cookie with the token templates see
+        let res = call_service(&app, TestRequest::get().uri("/").to_request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let issued = res
+            .response()
+            .cookies()
+            .find(|c| c.name() == "csrf_token")
+            .unwrap()
+            .value()
+            .to_string();
+        assert_eq!(read_body(res).await, issued);
+
+        let form = TestRequest::post()
+            .uri("/")
+            .cookie(cookie())
+            .set_form([("csrf", TOKEN), ("name", "Ada")]);
+        let res = call_service(&app, form.to_request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(res.response().cookies().next().is_none());
+
+        let header = TestRequest::delete()
+            .uri("/")
+            .cookie(cookie())
+            .insert_header((CSRF_HEADER, TOKEN));
+        let res = call_service(&app, header.to_request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        for req in [
+            TestRequest::post().uri("/").cookie(cookie()),
+            TestRequest::post()
+                .uri("/")
+                .cookie(cookie())
+                .set_form([("csrf", "wrong")]),
+            TestRequest::post()
+                .uri("/")
+                .insert_header((CSRF_HEADER, TOKEN)),
+        ] {
+            let res = call_service(&app, req.to_request()).await;
+            assert_eq!(res.status(), StatusCode::FORBIDDEN);
+            assert_eq!(
+                res.response().extensions().get::<ErrorCode>(),
+                Some(&ErrorCode("CSRF_INVALID"))
+            );
+        }
+    }
+}
@@ -0,0 +1,76 @@
+use crate::{AppData, auth::AuthUser};
+use actix_web::{Error, FromRequest, HttpRequest, dev::Payload, web};
+use futures::future::LocalBoxFuture;
+
+/// `AppData` and the current user in one extractor.
+///
+/// The auth part is any extractor: `Ctx` (or `Ctx<AuthUser>`) requires a login,
+/// `Ctx<Role<role::User>>` or `Ctx<AdminUser>` additionally require a role and
+/// `Ctx<OptionalAuthUser>` also accepts anonymous requests.
+pub struct Ctx<A = AuthUser> {
+    pub data: web::Data<AppData>,
+    pub user: A,
+}
+
+impl<A> FromRequest for Ctx<A>
+where
+    A: FromRequest + 'static,
+    A::Future: 'static,
+{
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let data = web::Data::<AppData>::from_request(req, payload);
+        let user = A::from_request(req, payload);
+
+        Box::pin(async move {
+            let data = data.await?;
+            let user = user.await.map_err(Into::into)?;
+            Ok(Ctx { data, user })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{auth::OptionalAuthUser, structs::UserRole, testing};
+    use actix_web::{
+        App, HttpResponse,
+        http::StatusCode,
+        test::{TestRequest, call_service, init_service, read_body},
+    };
+
+    #[actix_web::test]
+    async fn ctx_populates_data_and_user() {
+        let data = testing::app_data().await;
+        let req = testing::signed_in(TestRequest::get().uri("/"), &data, 3, UserRole::User);
+        let app = init_service(App::new().app_data(web::Data::new(data)).route(
+            "/",
+            web::get().to(|ctx: Ctx| async move {
+                HttpResponse::Ok().body(format!("{} {}", ctx.data.domain, ctx.user.claims.sub))
+            }),
+        ))
+        .await;
+
+        let res = call_service(&app, req.to_request()).await;
+        assert_eq!(read_body(res).await, "localhost 3");
+    }
+
+    #[actix_web::test]
+    async fn optional_ctx_accepts_anonymous_requests() {
+        let data = testing::app_data().await;
+        let app = init_service(App::new().app_data(web::Data::new(data)).route(
+            "/",
+            web::get().to(|ctx: Ctx<OptionalAuthUser>| async move {
+                HttpResponse::Ok().body(format!("{} {}", ctx.data.domain, ctx.user.0.is_some()))
+            }),
+        ))
+        .await;
+
+        let res = call_service(&app, TestRequest::get().uri("/").to_request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(read_body(res).await, "localhost false");
+    }
+}
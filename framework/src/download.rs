@@ -0,0 +1,118 @@
+use actix_web::{
+    HttpResponse,
+    error::BlockingError,
+    http::header::{ACCEPT_RANGES, ContentDisposition},
+    web::{self, Bytes},
+};
+use futures::Stream;
+
+/// Response that makes the browser save `body` as `filename`.
+#[must_use]
+pub fn download(filename: &str, content_type: &str, body: impl Into<Bytes>) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type(content_type)
+        .insert_header(ContentDisposition::attachment(filename))
+        .body(body.into())
+}
+
+/// Like `download`, but `render` builds the body on the blocking thread pool, so
+/// serializing a large report (e.g. a CSV of many rows) doesn't stall the async worker.
+/// Small bodies are cheaper to build inline with `download`.
+///
+/// # Errors
+///
+/// Returns `BlockingError` if the blocking task can't be run.
+pub async fn download_blocking<F, B>(
+    filename: &str,
+    content_type: &str,
+    render: F,
+) -> Result<HttpResponse, BlockingError>
+where
+    F: FnOnce() -> B + Send + 'static,
+    B: Into<Bytes> + Send + 'static,
+{
+    let body = web::block(render).await?;
+    Ok(download(filename, content_type, body))
+}
+
+/// Like `download`, but sends the chunks of `stream` as they are produced, so large
+/// exports never have to be held in memory. The length isn't known up front, so the
+/// body goes out with `Transfer-Encoding: chunked` and range requests are refused.
+///
+/// An error from the stream aborts the response mid-body.
+#[must_use]
+pub fn download_stream<S, E>(filename: &str, content_type: &str, stream: S) -> HttpResponse
+where
+    S: Stream<Item = Result<Bytes, E>> + 'static,
+    E: Into<Box<dyn std::error::Error>> + 'static,
+{
+    HttpResponse::Ok()
+        .content_type(content_type)
+        .insert_header(ContentDisposition::attachment(filename))
+        .insert_header((ACCEPT_RANGES, "none"))
+        .streaming(stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{
+        body::{BodySize, MessageBody, to_bytes},
+        http::header::{CONTENT_DISPOSITION, CONTENT_TYPE},
+    };
+    use futures::stream;
+    use std::fmt::Write;
+
+    fn csv(rows: usize) -> String {
+        (0..rows).fold(String::new(), |mut csv, row| {
+            let _ = writeln!(csv, "{row},row {row}");
+            csv
+        })
+    }
+
+    #[actix_web::test]
+    async fn streamed_export_arrives_complete_and_in_order() {
+        let rows = stream::unfold(0, |row| async move {
+            if row == 1000 {
+                return None;
+            }
+            actix_web::rt::task::yield_now().await;
+            let line = Bytes::from(format!("{row},row {row}\n"));
+            Some((Ok::<_, std::io::Error>(line), row + 1))
+        });
+
+        let res = download_stream("export.csv", "text/csv", rows);
+        assert_eq!(res.headers().get(ACCEPT_RANGES).unwrap(), "none");
+        assert_eq!(res.body().size(), BodySize::Stream);
+
+        assert_eq!(to_bytes(res.into_body()).await.unwrap(), csv(1000));
+    }
+
+    #[actix_web::test]
+    async fn blocking_export_leaves_the_worker_free_and_matches_download() {
+        // The render waits for the async side to run, which it couldn't if the render
+        // occupied the (single) worker thread
+        let (started, wait) = std::sync::mpsc::channel();
+        let export = download_blocking("export.csv", "text/csv", move || {
+            wait.recv_timeout(std::time::Duration::from_secs(10))
+                .expect("the export blocked the async worker");
+            csv(100_000)
+        });
+        let signal = async move {
+            actix_web::rt::task::yield_now().await;
+            started.send(()).unwrap();
+        };
+
+        let (res, ()) = futures::future::join(export, signal).await;
+        let res = res.unwrap();
+
+        let expected = download("export.csv", "text/csv", csv(100_000));
+        for name in [CONTENT_TYPE, CONTENT_DISPOSITION] {
+            assert_eq!(res.headers().get(&name), expected.headers().get(&name));
+        }
+        assert_eq!(
+            to_bytes(res.into_body()).await.unwrap(),
+            to_bytes(expected.into_body()).await.unwrap()
+        );
+    }
+}
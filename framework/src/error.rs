@@ -14,6 +14,9 @@ pub enum AppError {
     #[error("JSON error: {0}")]
     Serde(#[from] serde_json::Error),
 
+    #[error("Template error: {0}")]
+    Template(#[from] tera::Error),
+
     #[error("Not Found: {0}")]
     NotFound(String),
 
@@ -32,6 +35,71 @@ pub enum AppError {
 
 pub type AppResult<T = HttpResponse> = Result<T, AppError>;
 
+/// Stable, machine-readable code of an error response, e.g. `AUTH_REQUIRED`.
+///
+/// Included as `code` in JSON error bodies and in the error page context.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ErrorCode(pub &'static str);
+
+/// Developer-facing description of an error, shown instead of the user message on
+/// dev error pages (e.g. the full Tera error chain of `AppError::Template`).
+#[derive(Debug, Clone)]
+pub(crate) struct ErrorDetail(pub String);
+
+impl ErrorCode {
+    /// Code for error responses that didn't come from an `AppError`.
+    #[must_use]
+    pub fn for_status(status: StatusCode) -> Self {
+        ErrorCode(match status {
+            StatusCode::BAD_REQUEST => "BAD_REQUEST",
+            StatusCode::UNAUTHORIZED => "AUTH_REQUIRED",
+            StatusCode::FORBIDDEN => "FORBIDDEN",
+            StatusCode::NOT_FOUND => "NOT_FOUND",
+            StatusCode::METHOD_NOT_ALLOWED => "METHOD_NOT_ALLOWED",
+            StatusCode::UNPROCESSABLE_ENTITY => "VALIDATION_FAILED",
+            StatusCode::TOO_MANY_REQUESTS => "RATE_LIMITED",
+            StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE => "REQUEST_TOO_LARGE",
+            StatusCode::SERVICE_UNAVAILABLE => "SERVICE_UNAVAILABLE",
+            StatusCode::GATEWAY_TIMEOUT => "TIMEOUT",
+            _ => "INTERNAL_ERROR",
+        })
+    }
+}
+
+/// Errors that abort `FrameworkApp::run` during startup.
+#[derive(Error, Debug)]
+pub enum FrameworkError {
+    #[error(
+        "Migration {version} was already applied but its file has been modified since. \
+         Restore the original file and add a new migration instead."
+    )]
+    MigrationChecksum { version: i64 },
+
+    #[error("Migration error: {0}")]
+    Migration(#[from] sqlx::migrate::MigrateError),
+
+    #[error("Template {path} is not valid UTF-8")]
+    TemplateEncoding { path: String },
+
+    #[error("Template {name} could not be parsed: {source}")]
+    TemplateParse { name: String, source: tera::Error },
+
+    #[error(
+        "Invalid rate limit of {burst_size} requests per {seconds_per_request}s, \
+         both must be at least 1"
+    )]
+    RateLimitConfig {
+        seconds_per_request: u64,
+        burst_size: u32,
+    },
+
+    #[error("Invalid JSON Schema: {0}")]
+    JsonSchema(String),
+
+    #[error("Invalid EMBED_ORIGINS entry {0:?}, expected an origin like https://example.com")]
+    EmbedOrigin(String),
+}
+
 impl From<String> for AppError {
     fn from(s: String) -> Self {
         AppError::Internal(s)
@@ -45,11 +113,28 @@ impl From<&str> for AppError {
 }
 
 impl AppError {
+    /// Stable code for API clients; unlike the message it never changes wording.
+    #[must_use]
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Db(_) => "DATABASE_ERROR",
+            Self::Reqwest(_) => "UPSTREAM_ERROR",
+            Self::Serde(_) => "SERIALIZATION_ERROR",
+            Self::Template(_) => "TEMPLATE_ERROR",
+            Self::NotFound(_) => "NOT_FOUND",
+            Self::Auth(_) => "AUTH_INVALID",
+            Self::NoAuth => "AUTH_REQUIRED",
+            Self::Internal(_) => "INTERNAL_ERROR",
+            Self::User(_) => "USER_ERROR",
+        }
+    }
+
     pub fn user_message(&self) -> String {
         match self {
             Self::Db(_) => "A database error occurred.".into(),
             Self::Reqwest(_) => "Communication with an external service failed.".into(),
             Self::Serde(_) => "Processing data failed.".into(),
+            Self::Template(_) => "Rendering the page failed.".into(),
             Self::NoAuth => "Access denied.".into(),
             Self::NotFound(msg) | Self::Auth(msg) | Self::Internal(msg) | Self::User(msg) => {
                 msg.clone()
@@ -71,10 +156,27 @@ impl ResponseError for AppError {
         log::error!("AppError ({}): {}", self.status_code(), self);
         let mut res = HttpResponse::new(self.status_code());
         res.extensions_mut().insert(self.user_message());
+        res.extensions_mut().insert(ErrorCode(self.code()));
+        if let Self::Template(e) = self {
+            res.extensions_mut()
+                .insert(ErrorDetail(error_chain(e).join(": ")));
+        }
         res
     }
 }
 
+/// The messages of `err` and its causes, outermost first; Tera's own message rarely
+/// says what went wrong.
+pub(crate) fn error_chain(err: &dyn std::error::Error) -> Vec<String> {
+    let mut chain = vec![err.to_string()];
+    let mut cause = err.source();
+    while let Some(e) = cause {
+        chain.push(e.to_string());
+        cause = e.source();
+    }
+    chain
+}
+
 pub trait ResultExt<T> {
     #[allow(async_fn_in_trait)]
     async fn render(self, data: &web::Data<AppData>, template: &str) -> HttpResponse;
@@ -88,3 +190,32 @@ impl<T: Serialize> ResultExt<T> for Result<T, AppError> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render_manually() -> AppResult<String> {
+        Ok(tera::Tera::one_off(
+            "{{ missing }}",
+            &tera::Context::new(),
+            false,
+        )?)
+    }
+
+    #[test]
+    fn tera_error_becomes_template_error_with_its_chain() {
+        let err = render_manually().unwrap_err();
+        assert!(matches!(err, AppError::Template(_)));
+        assert_eq!(err.code(), "TEMPLATE_ERROR");
+
+        let res = err.error_response();
+        assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(
+            res.extensions().get::<String>().unwrap(),
+            "Rendering the page failed."
+        );
+        let detail = res.extensions().get::<ErrorDetail>().unwrap().0.clone();
+        assert!(detail.contains("Variable `missing` not found"), "{detail}");
+    }
+}
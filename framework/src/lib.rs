@@ -1,27 +1,66 @@
 #![deny(warnings, unused_imports, dead_code, clippy::all, clippy::pedantic)]
 
 use actix_web::{
-    App, HttpMessage, HttpResponse, HttpServer,
+    App, HttpMessage, HttpRequest, HttpResponse, HttpServer, ResponseError,
     body::MessageBody,
-    dev::ServiceResponse,
-    http::StatusCode,
-    middleware::{DefaultHeaders, ErrorHandlerResponse, ErrorHandlers, NormalizePath},
-    web,
+    cookie::SameSite,
+    dev::{ServiceRequest, ServiceResponse},
+    http::{
+        StatusCode,
+        header::{
+            self, EntityTag, HeaderMap, HeaderName, HeaderValue, IfNoneMatch, TryIntoHeaderValue,
+        },
+    },
+    middleware::{ErrorHandlerResponse, ErrorHandlers, NormalizePath, from_fn},
+    mime, web,
 };
+use chrono::{NaiveDateTime, TimeZone};
+use chrono_tz::Tz;
 use dotenv::dotenv;
+use error::{AppError, AppResult, ErrorCode, ErrorDetail, FrameworkError};
 use include_dir::Dir;
-use log::{debug, error, info};
-use sqlx::sqlite::SqlitePool;
-use std::{env, fs};
+use log::{debug, error, info, warn};
+use middleware::{MiddlewareFn, MiddlewareNext, Middlewares};
+use settings::Settings;
+use sha2::{Digest, Sha256};
+use sqlx::{Execute, Executor, Row, Sqlite, sqlite::SqlitePool};
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    env, fs,
+    sync::{
+        Arc, PoisonError, RwLock,
+        atomic::{AtomicBool, Ordering},
+    },
+};
 use tera::{Context, Tera};
 use tokio_cron_scheduler::JobScheduler;
 
+pub mod audit;
 pub mod auth;
+pub mod client_ip;
+mod compressed;
+pub mod concurrency_limit;
 pub mod cron;
+pub mod csrf;
+pub mod ctx;
+pub mod download;
 pub mod error;
+pub mod list;
+mod macros;
+pub mod mail;
+pub mod middleware;
+pub mod migrations;
+pub mod nav;
+pub mod nonce;
 pub mod prelude;
 pub mod rate_limiter;
+pub mod settings;
 pub mod structs;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+pub mod transaction;
+pub mod validation;
 
 #[derive(Copy, Clone, PartialEq, serde::Serialize)]
 pub enum Env {
@@ -29,15 +68,335 @@ pub enum Env {
     Prod,
 }
 
+impl std::fmt::Display for Env {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Env::Dev => write!(f, "dev"),
+            Env::Prod => write!(f, "prod"),
+        }
+    }
+}
+
+/// Settings that can change while the server is running. They are re-read from the
+/// `.env` file (or the process environment) when the process receives `SIGHUP`.
+pub struct RuntimeSettings {
+    pub maintenance: AtomicBool,
+    /// Flags registered with `FrameworkApp::feature`, before `FEATURE_*` overrides.
+    feature_defaults: HashMap<String, bool>,
+    features: RwLock<HashMap<String, bool>>,
+}
+
+impl RuntimeSettings {
+    fn from_env(feature_defaults: HashMap<String, bool>) -> Self {
+        let features = features_from(&feature_defaults, env::vars());
+        Self {
+            maintenance: AtomicBool::new(is_enabled(env::var("MAINTENANCE").ok().as_deref())),
+            feature_defaults,
+            features: RwLock::new(features),
+        }
+    }
+
+    #[must_use]
+    pub fn maintenance(&self) -> bool {
+        self.maintenance.load(Ordering::Relaxed)
+    }
+
+    /// Whether feature flag `name` is on; unknown flags are off.
+    #[must_use]
+    pub fn feature(&self, name: &str) -> bool {
+        self.features
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(name)
+            .copied()
+            .unwrap_or(false)
+    }
+
+    /// All feature flags by name.
+    #[must_use]
+    pub fn features(&self) -> HashMap<String, bool> {
+        self.features
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// Re-read `MAINTENANCE`, the `FEATURE_*` flags and the log level (`RUST_LOG`, plain
+    /// level only).
+    /// The log level can only be lowered below the level the logger was started with.
+    pub fn reload(&self) {
+        // `dotenv()` never overrides variables that are already set, so read the file directly
+        #[allow(deprecated)]
+        let file_vars: HashMap<String, String> = dotenv::dotenv_iter()
+            .map(|iter| iter.filter_map(Result::ok).collect())
+            .unwrap_or_default();
+        let var = |key: &str| file_vars.get(key).cloned().or_else(|| env::var(key).ok());
+
+        let maintenance = is_enabled(var("MAINTENANCE").as_deref());
+        self.maintenance.store(maintenance, Ordering::Relaxed);
+
+        let features = features_from(&self.feature_defaults, env::vars().chain(file_vars.clone()));
+        *self
+            .features
+            .write()
+            .unwrap_or_else(PoisonError::into_inner) = features;
+
+        if let Some(level) = var("RUST_LOG").and_then(|v| v.parse::<log::LevelFilter>().ok()) {
+            log::set_max_level(level);
+        }
+
+        info!(
+            "Settings reloaded: maintenance={maintenance}, log level={}",
+            log::max_level()
+        );
+    }
+}
+
+/// `defaults` with the `FEATURE_<NAME>=true|false` variables applied, by lowercase name
+/// (`FEATURE_NEW_DASHBOARD` is `new_dashboard`). Later variables win.
+fn features_from(
+    defaults: &HashMap<String, bool>,
+    vars: impl IntoIterator<Item = (String, String)>,
+) -> HashMap<String, bool> {
+    let mut features = defaults.clone();
+    for (key, value) in vars {
+        if let Some(name) = key.strip_prefix("FEATURE_") {
+            features.insert(name.to_lowercase(), is_enabled(Some(&value)));
+        }
+    }
+    features
+}
+
+/// Headers added to every response that doesn't already set them.
+///
+/// With `embed_origins` the pages may be framed by those origins only: they replace
+/// `'none'` in `frame-ancestors` and `X-Frame-Options` (which can't list origins) is left out.
+fn default_headers(
+    env: Env,
+    server: Option<&str>,
+    powered_by: Option<&str>,
+    embed_origins: &[String],
+) -> HeaderMap {
+    let csp = if env == Env::Dev {
+        "default-src 'self'; \
+         script-src 'self' 'unsafe-inline' 'unsafe-eval'; \
+         style-src 'self' 'unsafe-inline'; \
+         font-src 'self'; \
+         img-src 'self' data:; \
+         connect-src 'self' ws://localhost:4321 http://localhost:4321 ws://127.0.0.1:4321 http://127.0.0.1:4321 ws://0.0.0.0:4321 http://0.0.0.0:4321; \
+         frame-ancestors {ancestors}; \
+         base-uri 'self'; \
+         form-action 'self';"
+    } else {
+        "default-src 'self'; \
+         script-src 'self'; \
+         style-src 'self'; \
+         font-src 'self'; \
+         img-src 'self' data:; \
+         frame-ancestors {ancestors}; \
+         base-uri 'self'; \
+         form-action 'self';"
+    };
+    let csp = if embed_origins.is_empty() {
+        csp.replace("{ancestors}", "'none'")
+    } else {
+        csp.replace("{ancestors}", &embed_origins.join(" "))
+    };
+
+    let mut headers = HeaderMap::new();
+    let mut add = |name: HeaderName, value: &str| match HeaderValue::from_str(value) {
+        Ok(value) => {
+            headers.insert(name, value);
+        }
+        Err(_) => error!("Invalid value for header {name}: {value:?}"),
+    };
+
+    add(header::X_CONTENT_TYPE_OPTIONS, "nosniff");
+    if embed_origins.is_empty() {
+        add(header::X_FRAME_OPTIONS, "DENY");
+    }
+    add(header::REFERRER_POLICY, "strict-origin-when-cross-origin");
+    add(header::CONTENT_SECURITY_POLICY, &csp);
+    if let Some(value) = server {
+        add(header::SERVER, value);
+    }
+    if let Some(value) = powered_by {
+        add(HeaderName::from_static("x-powered-by"), value);
+    }
+
+    headers
+}
+
+/// The comma-separated `EMBED_ORIGINS`. Each entry goes into the CSP as is, so anything
+/// but a plain origin (`https://example.com`, `https://*.example.com:8443`) or `'self'`
+/// is rejected rather than risking a header that browsers drop or misread.
+fn parse_embed_origins(value: &str) -> Result<Vec<String>, FrameworkError> {
+    let is_origin = |origin: &str| {
+        let Some((scheme, rest)) = origin.split_once("://") else {
+            return false;
+        };
+        let (host, port) = rest.split_once(':').unwrap_or((rest, "443"));
+        let host = host.strip_prefix("*.").unwrap_or(host);
+
+        matches!(scheme, "http" | "https")
+            && !host.is_empty()
+            && host
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-')
+            && port.parse::<u16>().is_ok()
+    };
+
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|origin| !origin.is_empty())
+        .map(|origin| {
+            if origin == "'self'" || is_origin(origin) {
+                Ok(origin.to_string())
+            } else {
+                Err(FrameworkError::EmbedOrigin(origin.to_string()))
+            }
+        })
+        .collect()
+}
+
+/// Account lockout from `LOGIN_MAX_FAILURES` and `LOGIN_LOCKOUT_SECONDS`.
+fn lockout_policy() -> auth::LockoutPolicy {
+    let default = auth::LockoutPolicy::default();
+    auth::LockoutPolicy {
+        max_failures: env::var("LOGIN_MAX_FAILURES")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .filter(|n| *n > 0)
+            .unwrap_or(default.max_failures),
+        cooldown: env::var("LOGIN_LOCKOUT_SECONDS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .map_or(default.cooldown, std::time::Duration::from_secs),
+    }
+}
+
+/// Session cookie attributes from the `COOKIE_*` variables; the defaults match a single
+/// app on `DOMAIN`, or one `embedded` cross-site (`EMBED_ORIGINS`).
+fn cookie_config(env: Env, embedded: bool) -> auth::CookieConfig {
+    auth::CookieConfig {
+        name: env::var("COOKIE_NAME")
+            .ok()
+            .filter(|name| !name.is_empty())
+            .unwrap_or_else(|| "token".to_string()),
+        same_site: match env::var("COOKIE_SAME_SITE")
+            .unwrap_or_default()
+            .to_ascii_lowercase()
+            .as_str()
+        {
+            "strict" => SameSite::Strict,
+            "lax" => SameSite::Lax,
+            "none" => SameSite::None,
+            _ if embedded => SameSite::None,
+            _ => SameSite::Strict,
+        },
+        // Browsers drop `SameSite=None` cookies that aren't `Secure`, so embedding
+        // needs it even in dev
+        secure: env::var("COOKIE_SECURE")
+            .ok()
+            .map_or(env != Env::Dev || embedded, |v| is_enabled(Some(&v))),
+        domain: env::var("COOKIE_DOMAIN").ok().filter(|d| !d.is_empty()),
+        path: env::var("COOKIE_PATH")
+            .ok()
+            .filter(|path| !path.is_empty())
+            .unwrap_or_else(|| "/".to_string()),
+    }
+}
+
+/// Whether the `Accept` header ranks `application/json` above `text/html`.
+pub(crate) fn prefers_json(req: &HttpRequest) -> bool {
+    let Ok(accept) = <header::Accept as header::Header>::parse(req) else {
+        return false;
+    };
+
+    accept
+        .ranked()
+        .into_iter()
+        .find(|m| *m == mime::TEXT_HTML || *m == mime::APPLICATION_JSON)
+        .is_some_and(|m| m == mime::APPLICATION_JSON)
+}
+
+fn strip_port(host: &str) -> &str {
+    if let Some(end) = host.strip_prefix('[').and_then(|h| h.find(']')) {
+        return &host[..end + 2];
+    }
+    host.rsplit_once(':').map_or(host, |(name, _)| name)
+}
+
+fn is_enabled(value: Option<&str>) -> bool {
+    matches!(value, Some("1" | "true"))
+}
+
 pub struct AppData {
     pub tera: Tera,
     pub db: SqlitePool,
     pub env: Env,
     pub domain: String,
     pub jwt_secret: String,
+    /// Secrets `JWT_SECRET` was rotated away from (`JWT_PREVIOUS_SECRETS`, comma-separated).
+    /// Tokens signed with them are still accepted; new tokens always use `jwt_secret`.
+    pub jwt_previous_secrets: Arc<Vec<String>>,
+    /// Seconds of clock skew tolerated when checking a token's `exp`.
+    pub jwt_leeway: u64,
+    /// Lifetime of session tokens, from `JWT_TTL_SECONDS` (default one hour).
+    pub jwt_ttl: std::time::Duration,
+    pub scheduler: JobScheduler,
+    pub runtime: Arc<RuntimeSettings>,
+    pub settings: Arc<Settings>,
+    /// Timezone timestamps are shown in, from `DISPLAY_TIMEZONE` (default UTC).
+    pub timezone: Tz,
+    /// Versions of the migrations this binary ships with.
+    pub migrations: Arc<Vec<i64>>,
+    /// Origins allowed to frame the app, from `EMBED_ORIGINS` (comma-separated).
+    pub embed_origins: Arc<Vec<String>>,
+    /// Whether `X-Forwarded-For` from a reverse proxy is trusted (`TRUST_PROXY`), see `ClientIp`.
+    pub trust_proxy: bool,
+    /// Path the app is served under behind a proxy (`BASE_PATH`, e.g. `/app`), empty
+    /// for the root. Embedded assets are served below it, matching Astro's `base`.
+    pub base_path: String,
+    /// Name and attributes of the session cookies, from the `COOKIE_*` variables.
+    pub cookie: auth::CookieConfig,
+    /// Path patterns registered with `FrameworkApp::public_paths`.
+    pub public_paths: Arc<Vec<String>>,
+    /// Cost factors for new password hashes, from `FrameworkApp::argon2_params`.
+    pub argon2_params: auth::Argon2Params,
+    /// When failed logins lock an account, from the `LOGIN_*` variables.
+    pub lockout: auth::LockoutPolicy,
+    /// Base context of every template, from `FrameworkApp::global_context`.
+    global_context: Option<Arc<GlobalContextFn>>,
+    /// Sender from `FrameworkApp::mailer`, see `send_mail`.
+    mailer: Option<Arc<mail::MailerFn>>,
+    /// Filters and functions from `FrameworkApp::tera_filter`/`tera_function`, for the
+    /// per-request `Tera` of the dev server path.
+    tera_extensions: Arc<TeraExtensions>,
 }
 
 impl AppData {
+    /// Whether a sender was registered with `FrameworkApp::mailer`.
+    #[must_use]
+    pub fn can_send_mail(&self) -> bool {
+        self.mailer.is_some()
+    }
+
+    /// Send `mail` with the sender registered through `FrameworkApp::mailer`.
+    ///
+    /// # Errors
+    ///
+    /// Returns `AppError::Internal` if no mailer is registered or sending fails.
+    pub async fn send_mail(&self, mail: mail::Mail) -> AppResult<()> {
+        let Some(mailer) = &self.mailer else {
+            return Err(AppError::Internal("No mailer configured".to_string()));
+        };
+        mailer(mail)
+            .await
+            .map_err(|e| AppError::Internal(format!("Failed to send mail: {e}")))
+    }
+
     pub async fn render(&self, template: &str) -> HttpResponse {
         self.render_template(template, &serde_json::json!({})).await
     }
@@ -50,20 +409,293 @@ impl AppData {
         self.render_template(template, context).await
     }
 
+    /// `value` as a `200 OK` JSON response, for API endpoints next to the HTML pages.
+    #[must_use]
+    pub fn render_json<T: serde::Serialize>(&self, value: &T) -> HttpResponse {
+        self.render_json_status(StatusCode::OK, value)
+    }
+
+    /// Like `render_json`, with `status` instead of `200 OK`. A `value` that can't be
+    /// serialized gives the usual `{ "status", "code", "error" }` body of
+    /// `AppError::Serde` with a 500 instead.
+    #[must_use]
+    pub fn render_json_status<T: serde::Serialize>(
+        &self,
+        status: StatusCode,
+        value: &T,
+    ) -> HttpResponse {
+        match serde_json::to_string(value) {
+            Ok(body) => HttpResponse::build(status)
+                .content_type(mime::APPLICATION_JSON)
+                .body(body),
+            Err(e) => {
+                let err = AppError::from(e);
+                error!("Failed to serialize JSON response: {err}");
+                json_error(
+                    err.status_code(),
+                    ErrorCode(err.code()),
+                    &err.user_message(),
+                )
+            }
+        }
+    }
+
+    /// `data` as JSON (see `render_json`) when the `Accept` header ranks
+    /// `application/json` above `text/html`, otherwise `template` rendered with it as
+    /// the context. Browsers, and clients sending no `Accept` or `*/*`, get HTML. The
+    /// response carries `Vary: Accept` so caches keep both apart.
+    pub async fn render_negotiated<T: serde::Serialize>(
+        &self,
+        req: &HttpRequest,
+        template: &str,
+        data: &T,
+    ) -> HttpResponse {
+        let mut res = if prefers_json(req) {
+            self.render_json(data)
+        } else {
+            self.render_template(template, data).await
+        };
+        res.headers_mut()
+            .append(header::VARY, HeaderValue::from_static("Accept"));
+        res
+    }
+
+    /// Like `render_tpl`, but leaves failures to the caller instead of answering with a
+    /// 500: `AppError::NotFound` if there is no such template and `AppError::Template`
+    /// if rendering it fails.
+    ///
+    /// # Errors
+    ///
+    /// See above; in dev also `AppError::Reqwest` if the Astro dev server can't be reached.
+    pub async fn try_render_tpl<T: serde::Serialize>(
+        &self,
+        template: &str,
+        context: &T,
+    ) -> AppResult {
+        let context = template_context(self, context)?;
+        let not_found = || AppError::NotFound(format!("Template {template} not found"));
+
+        let html = if self.env == Env::Dev {
+            let response = fetch_astro_page(template).await?;
+            if response.status() == reqwest::StatusCode::NOT_FOUND {
+                return Err(not_found());
+            }
+            let source = response.error_for_status()?.text().await?;
+
+            let mut tera_temp = new_tera(&self.tera_extensions);
+            tera_temp.add_raw_template(template, &source)?;
+            tera_temp.render(template, &context)?
+        } else {
+            match self
+                .tera
+                .render(&resolve_template_name(&self.tera, template), &context)
+            {
+                Err(e) if matches!(e.kind, tera::ErrorKind::TemplateNotFound(_)) => {
+                    return Err(not_found());
+                }
+                result => result?,
+            }
+        };
+
+        Ok(HttpResponse::Ok().content_type("text/html").body(html))
+    }
+
+    /// Like `render_tpl`, but tags the response with an `ETag` and answers
+    /// `304 Not Modified` when `If-None-Match` already holds the same rendering.
+    /// Only use this for pages whose output depends solely on `context`.
+    pub async fn render_tpl_cached<T: serde::Serialize>(
+        &self,
+        req: &HttpRequest,
+        template: &str,
+        context: &T,
+    ) -> HttpResponse {
+        let res = self.render_template(template, context).await;
+        if res.status() != StatusCode::OK {
+            return res;
+        }
+
+        let (res, body) = res.into_parts();
+        let bytes = match body.try_into_bytes() {
+            Ok(bytes) => bytes,
+            Err(body) => return res.set_body(body),
+        };
+
+        let etag = EntityTag::new_strong(format!("{:x}", Sha256::digest(&bytes)));
+        let matches = match req.get_header::<IfNoneMatch>() {
+            Some(IfNoneMatch::Any) => true,
+            Some(IfNoneMatch::Items(tags)) => tags.iter().any(|tag| tag.weak_eq(&etag)),
+            None => false,
+        };
+
+        let mut res = if matches {
+            HttpResponse::NotModified().finish()
+        } else {
+            res.set_body(bytes).map_into_boxed_body()
+        };
+        if let Ok(value) = etag.try_into_value() {
+            res.headers_mut().insert(header::ETAG, value);
+        }
+        res
+    }
+
+    /// Domain attribute for cookies set on this request.
+    ///
+    /// Returns `COOKIE_DOMAIN` if set. Otherwise returns `DOMAIN` when the request host is that domain or one of its subdomains.
+    /// Otherwise (an IP, an alternate hostname) the browser would drop a cookie
+    /// scoped to `DOMAIN`, so `None` is returned and the cookie should be host-only.
+    #[must_use]
+    pub fn cookie_domain(&self, req: &HttpRequest) -> Option<&str> {
+        if let Some(domain) = &self.cookie.domain {
+            return Some(domain);
+        }
+
+        let conn = req.connection_info();
+        let host = strip_port(conn.host());
+        let domain = self.domain.as_str();
+
+        let matches = host.eq_ignore_ascii_case(domain)
+            || host
+                .len()
+                .checked_sub(domain.len() + 1)
+                .and_then(|i| host.get(i..))
+                .is_some_and(|suffix| {
+                    suffix.starts_with('.') && suffix[1..].eq_ignore_ascii_case(domain)
+                });
+
+        if matches {
+            Some(domain)
+        } else {
+            // Every cookie of such a request ends up here, so only the first one is logged
+            static WARNED: AtomicBool = AtomicBool::new(false);
+            if !WARNED.swap(true, Ordering::Relaxed) {
+                warn!(
+                    "Request host {host} does not match DOMAIN {domain}, using host-only cookies"
+                );
+            }
+            None
+        }
+    }
+
+    /// Whether `query` returns at least one row, e.g.
+    /// `data.exists(sqlx::query!("SELECT id FROM users WHERE email = ?", email))`.
+    ///
+    /// # Errors
+    ///
+    /// Returns the database error if the query fails.
+    pub async fn exists<'q, E>(&self, query: E) -> Result<bool, sqlx::Error>
+    where
+        E: 'q + Execute<'q, Sqlite>,
+    {
+        Ok(self.db.fetch_optional(query).await?.is_some())
+    }
+
+    /// The number in the first column of the first row of `query`, e.g.
+    /// `data.count(sqlx::query!("SELECT COUNT(*) FROM users WHERE role = ?", role))`.
+    ///
+    /// # Errors
+    ///
+    /// Returns the database error if the query fails or doesn't return an integer.
+    pub async fn count<'q, E>(&self, query: E) -> Result<i64, sqlx::Error>
+    where
+        E: 'q + Execute<'q, Sqlite>,
+    {
+        self.db.fetch_one(query).await?.try_get(0)
+    }
+
+    /// `otpauth://` URI enrolling a user's TOTP `secret`, listed under `DOMAIN`.
+    #[must_use]
+    pub fn totp_provisioning_uri(&self, secret: &str, account: &str) -> String {
+        auth::totp_provisioning_uri(secret, account, &self.domain)
+    }
+
+    /// Validate a session token against the current and previous JWT secrets.
+    ///
+    /// # Errors
+    ///
+    /// See `auth::decode_jwt`.
+    pub fn verify_jwt(&self, token: &str) -> Result<auth::Claims, auth::JwtError> {
+        auth::decode_jwt_rotated(
+            token,
+            &self.jwt_secret,
+            &self.jwt_previous_secrets,
+            self.jwt_leeway,
+        )
+    }
+
+    /// Whether feature flag `name` (e.g. `new_dashboard`) is on. Templates get all flags
+    /// as `features`.
+    #[must_use]
+    pub fn feature(&self, name: &str) -> bool {
+        self.runtime.feature(name)
+    }
+
+    /// Whether `path` matches one of the `public_paths` patterns.
+    #[must_use]
+    pub fn is_public_path(&self, path: &str) -> bool {
+        self.public_paths
+            .iter()
+            .any(|pattern| match pattern.strip_suffix('*') {
+                Some(prefix) => path.starts_with(prefix),
+                None => path == pattern,
+            })
+    }
+
+    /// Whether an error on `req` gets the logged-in error pages: the request has a
+    /// valid session token and its path isn't public.
+    fn logged_in_error_page(&self, req: &HttpRequest) -> bool {
+        !self.is_public_path(req.path()) && crate::auth::read_jwt(req).is_ok()
+    }
+
+    /// Format a UTC timestamp from the database in the display timezone.
+    #[must_use]
+    pub fn local_time(&self, utc: &NaiveDateTime) -> String {
+        self.timezone
+            .from_utc_datetime(utc)
+            .format("%Y-%m-%d %H:%M %Z")
+            .to_string()
+    }
+
+    /// A setting from the `settings` table, served from the cache.
+    #[must_use]
+    pub fn setting(&self, key: &str) -> Option<String> {
+        self.settings.get(key)
+    }
+
+    /// Store a setting in the `settings` table.
+    ///
+    /// # Errors
+    ///
+    /// Returns the database error if the value can't be written.
+    pub async fn set_setting(&self, key: &str, value: &str) -> Result<(), sqlx::Error> {
+        self.settings.set(&self.db, key, value).await
+    }
+
+    /// Render the themed error page with `status` and `message` from a handler, picking
+    /// the template by login state like the error middleware does. Clients asking for
+    /// JSON get the `{ "status", "code", "error" }` body instead.
+    pub async fn render_error(
+        &self,
+        status: StatusCode,
+        message: &str,
+        req: &HttpRequest,
+    ) -> HttpResponse {
+        let code = ErrorCode::for_status(status);
+        if prefers_json(req) {
+            return json_error(status, code, message);
+        }
+
+        let is_logged_in = self.logged_in_error_page(req);
+        render_status_page(self, status, is_logged_in, code, message.to_string(), None).await
+    }
+
     pub async fn render_template<T: serde::Serialize>(
         &self,
         template_name: &str,
         context_data: &T,
     ) -> HttpResponse {
         if self.env == Env::Dev {
-            let path = if template_name == "index" {
-                String::new()
-            } else {
-                template_name.replace("_", "/")
-            };
-            let url = format!("http://localhost:4321/{}", path);
-
-            let astro_html = match reqwest::get(&url).await {
+            let url = astro_page_url(template_name);
+            let astro_html = match fetch_astro_page(template_name).await {
                 Ok(response) => {
                     if response.status().is_success() {
                         match response.text().await {
@@ -86,29 +718,9 @@ impl AppData {
                 }
             };
 
-            let mut tera_temp = Tera::default();
-            if let Err(err) = tera_temp.add_raw_template(template_name, &astro_html) {
-                error!("Failed to add Astro HTML as Tera template: {}", err);
-                return HttpResponse::InternalServerError().body("Failed to add template");
-            }
-
-            let context = match Context::from_serialize(context_data) {
-                Ok(ctx) => ctx,
-                Err(err) => {
-                    error!("Context serialization error: {}", err);
-                    return HttpResponse::InternalServerError().body("Context serialization error");
-                }
-            };
-
-            match tera_temp.render(template_name, &context) {
-                Ok(html) => HttpResponse::Ok().content_type("text/html").body(html),
-                Err(err) => {
-                    error!("Template rendering error: {}", err);
-                    HttpResponse::InternalServerError().body("Template rendering error")
-                }
-            }
+            self.render_dev_source(template_name, &astro_html, context_data)
         } else {
-            let context = match Context::from_serialize(context_data) {
+            let context = match template_context(self, context_data) {
                 Ok(ctx) => ctx,
                 Err(err) => {
                     error!("Context serialization error: {}", err);
@@ -116,7 +728,7 @@ impl AppData {
                 }
             };
 
-            let template_name = template_name.replace("_", "/");
+            let template_name = resolve_template_name(&self.tera, template_name);
             match self.tera.render(&template_name, &context) {
                 Ok(html) => HttpResponse::Ok().content_type("text/html").body(html),
                 Err(err) => {
@@ -126,9 +738,244 @@ impl AppData {
             }
         }
     }
+
+    /// Render `source`, a page fetched from the Astro dev server, as template
+    /// `template_name`. Errors show the template error overlay.
+    fn render_dev_source<T: serde::Serialize>(
+        &self,
+        template_name: &str,
+        source: &str,
+        context_data: &T,
+    ) -> HttpResponse {
+        // The Astro HTML is the (trusted) template source, never context, so only the
+        // values interpolated into it get escaped
+        let mut tera_temp = new_tera(&self.tera_extensions);
+        if let Err(err) = tera_temp.add_raw_template(template_name, source) {
+            error!("Failed to add Astro HTML as Tera template: {err}");
+            return template_error_overlay(template_name, &err, source);
+        }
+
+        let context = match template_context(self, context_data) {
+            Ok(ctx) => ctx,
+            Err(err) => {
+                error!("Context serialization error: {err}");
+                return HttpResponse::InternalServerError().body("Context serialization error");
+            }
+        };
+
+        match tera_temp.render(template_name, &context) {
+            Ok(html) => HttpResponse::Ok().content_type("text/html").body(html),
+            Err(err) => {
+                error!("Template rendering error: {err}");
+                template_error_overlay(template_name, &err, source)
+            }
+        }
+    }
+}
+
+/// `context` for a template on top of the `FrameworkApp::global_context` values, plus
+/// the request's CSRF token as `csrf`, the feature flags as `features`
+/// (`{% if features.new_dashboard %}`) and, in impersonation sessions, the admin's id
+/// as `impersonator`, unless either context sets them itself.
+fn template_context<T: serde::Serialize>(data: &AppData, context: &T) -> tera::Result<Context> {
+    let claims = auth::current_claims();
+    let mut context = match &data.global_context {
+        Some(global_context) => {
+            let mut base = Context::from_value(global_context(data, claims.as_ref()))?;
+            base.extend(Context::from_serialize(context)?);
+            base
+        }
+        None => Context::from_serialize(context)?,
+    };
+    if !context.contains_key("csrf")
+        && let Some(token) = csrf::current_token()
+    {
+        context.insert("csrf", &token);
+    }
+    if !context.contains_key("features") {
+        context.insert("features", &data.runtime.features());
+    }
+    if !context.contains_key("impersonator")
+        && let Some(impersonator) = claims.and_then(|claims| claims.impersonator)
+    {
+        context.insert("impersonator", &impersonator);
+    }
+    Ok(context)
+}
+
+/// Template names are page paths without the extension, with `/` between segments
+/// (`users`, `public/error`). The older spelling with `_` instead of `/`
+/// (`public_error`) still works, but only for names that don't exist as written, so
+/// a page named `user_profile` is always found as `user_profile`.
+fn legacy_template_name(template_name: &str) -> Option<String> {
+    template_name
+        .contains('_')
+        .then(|| template_name.replace('_', "/"))
+}
+
+/// The registered template `template_name` refers to, see `legacy_template_name`.
+fn resolve_template_name<'a>(tera: &Tera, template_name: &'a str) -> Cow<'a, str> {
+    if tera.get_template_names().any(|name| name == template_name) {
+        return Cow::Borrowed(template_name);
+    }
+    legacy_template_name(template_name).map_or(Cow::Borrowed(template_name), Cow::Owned)
+}
+
+/// Dev server URL of the page behind `template_name`.
+fn astro_page_url(template_name: &str) -> String {
+    let path = if template_name == "index" {
+        ""
+    } else {
+        template_name
+    };
+    format!("http://localhost:4321/{path}")
+}
+
+/// Fetch the page behind `template_name` from the Astro dev server, preferring a
+/// dev-only override page under `dev/` if it has one, and falling back to the
+/// `legacy_template_name`.
+async fn fetch_astro_page(template_name: &str) -> reqwest::Result<reqwest::Response> {
+    let response = fetch_astro_path(template_name).await;
+    match (&response, legacy_template_name(template_name)) {
+        (Ok(found), Some(legacy)) if found.status() == reqwest::StatusCode::NOT_FOUND => {
+            fetch_astro_path(&legacy).await
+        }
+        _ => response,
+    }
+}
+
+async fn fetch_astro_path(template_name: &str) -> reqwest::Result<reqwest::Response> {
+    let url = astro_page_url(template_name);
+    let dev_url = url.replacen(":4321/", ":4321/dev/", 1);
+
+    match reqwest::get(&dev_url).await {
+        Ok(response) if response.status() != reqwest::StatusCode::NOT_FOUND => Ok(response),
+        _ => reqwest::get(&url).await,
+    }
+}
+
+/// Dev-only 500 page for a failed Tera parse or render: the template name, the full
+/// error chain (parse errors include line, column and the offending line) and, for
+/// unknown variables, the template lines that use them.
+fn template_error_overlay(template_name: &str, err: &tera::Error, source: &str) -> HttpResponse {
+    let chain = crate::error::error_chain(err);
+
+    // Tera quotes unknown variables in backticks: "Variable `user.name` not found ..."
+    let snippet: Vec<String> = chain
+        .iter()
+        .find_map(|msg| msg.split('`').nth(1))
+        .map(|name| {
+            source
+                .lines()
+                .enumerate()
+                .filter(|(_, line)| line.contains(name))
+                .take(10)
+                .map(|(i, line)| format!("{:>5} | {}", i + 1, line.trim()))
+                .collect()
+        })
+        .unwrap_or_default();
+
+    let errors = chain
+        .iter()
+        .map(|msg| ["<pre>", &tera::escape_html(msg), "</pre>"].concat())
+        .collect::<String>();
+    let used_here = if snippet.is_empty() {
+        String::new()
+    } else {
+        format!(
+            "<h2>Used here</h2><pre>{}</pre>",
+            tera::escape_html(&snippet.join("\n"))
+        )
+    };
+    let html = format!(
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><title>Template error</title>\
+         <style>body{{font-family:sans-serif;margin:2rem;background:#1e1e1e;color:#eee}}\
+         h1{{color:#ff6b6b}}pre{{background:#2d2d2d;padding:1rem;overflow:auto}}</style>\
+         </head><body><h1>Template error in <code>{}</code></h1>{errors}{used_here}</body></html>",
+        tera::escape_html(template_name)
+    );
+
+    let mut res = HttpResponse::InternalServerError()
+        .content_type("text/html; charset=utf-8")
+        .body(html);
+    res.extensions_mut().insert(ErrorPageRendered);
+    res
 }
 
 type ConfigureFn = Box<dyn Fn(&mut web::ServiceConfig) + Send + Sync + 'static>;
+type GlobalContextFn =
+    dyn Fn(&AppData, Option<&auth::Claims>) -> serde_json::Value + Send + Sync + 'static;
+struct ScopeDef {
+    path: &'static str,
+    configure: ConfigureFn,
+    middlewares: Arc<Vec<MiddlewareFn>>,
+}
+
+/// The app's own routes: the `FrameworkApp::scope`s, then every `configure` function
+/// in the order they were registered.
+fn register_routes(
+    cfg: &mut web::ServiceConfig,
+    scopes: &[ScopeDef],
+    configure_fns: &[ConfigureFn],
+) {
+    for scope in scopes {
+        cfg.service(
+            web::scope(scope.path)
+                .configure(&scope.configure)
+                .wrap(Middlewares(scope.middlewares.clone())),
+        );
+    }
+
+    for configure_fn in configure_fns {
+        cfg.configure(configure_fn);
+    }
+}
+
+/// Serve the embedded `dist_dir` below `base_path`: `/_astro/*` and, as the default
+/// service, every other path that isn't a route. In dev the Astro dev server is asked
+/// first.
+fn register_assets(
+    cfg: &mut web::ServiceConfig,
+    dist_dir: &'static Dir<'static>,
+    base_path: &str,
+    env: Env,
+    compress_assets: bool,
+) {
+    let asset_base = base_path.to_string();
+    let root_base = base_path.to_string();
+    cfg.service(web::scope(&format!("{base_path}/_astro")).route(
+        "/{path:.*}",
+        web::route().to(move |req: actix_web::HttpRequest| {
+            let base_path = asset_base.clone();
+            async move {
+                if env == Env::Dev
+                    && let Ok(res) = forward_to_dev_server(&req).await
+                {
+                    return Ok(res);
+                }
+                let path = asset_path(&base_path, req.path());
+                serve_from_dist(dist_dir, &req, path, compress_assets).await
+            }
+        }),
+    ))
+    .default_service(web::to(move |req: actix_web::HttpRequest| {
+        let base_path = root_base.clone();
+        async move {
+            if env == Env::Dev
+                && let Ok(res) = forward_to_dev_server(&req).await
+            {
+                return Ok(res);
+            }
+
+            let path = asset_path(&base_path, req.path());
+            match serve_from_dist(dist_dir, &req, path, compress_assets).await {
+                Ok(res) => Ok(res),
+                Err(_) => Ok::<HttpResponse, actix_web::Error>(HttpResponse::NotFound().finish()),
+            }
+        }
+    }));
+}
+
 type CronjobsFn = Box<
     dyn FnOnce(
         JobScheduler,
@@ -138,27 +985,76 @@ type CronjobsFn = Box<
     >,
 >;
 
+/// Connect the cron pool and add the app's jobs to `sched`. Returns whether cron is
+/// usable; a failure is an error unless `optional`, then the server runs without cron.
+async fn setup_cron(
+    cronjobs_fn: Option<CronjobsFn>,
+    sched: &JobScheduler,
+    database_url: &str,
+    optional: bool,
+) -> std::io::Result<bool> {
+    let Some(cronjobs_fn) = cronjobs_fn else {
+        return Ok(true);
+    };
+    let setup: Result<(), Box<dyn std::error::Error>> = async {
+        let cron_db_pool = SqlitePool::connect(database_url).await?;
+        (cronjobs_fn)(sched.clone(), cron_db_pool).await
+    }
+    .await;
+
+    match setup {
+        Ok(()) => Ok(true),
+        Err(e) if optional => {
+            error!("Failed to set up cron jobs, starting without cron: {e}");
+            Ok(false)
+        }
+        Err(e) => {
+            error!("Failed to set up cron jobs: {e}");
+            Err(std::io::Error::other(e.to_string()))
+        }
+    }
+}
+
 pub struct FrameworkApp {
     dist_dir: &'static Dir<'static>,
-    configure_fn: Option<ConfigureFn>,
+    configure_fns: Vec<ConfigureFn>,
+    scopes: Vec<ScopeDef>,
     cronjobs_fn: Option<CronjobsFn>,
+    middlewares: Vec<MiddlewareFn>,
+    migrations_dir: Option<&'static Dir<'static>>,
+    public_paths: Vec<String>,
+    argon2_params: auth::Argon2Params,
+    features: HashMap<String, bool>,
+    tera_extensions: TeraExtensions,
+    global_context: Option<Arc<GlobalContextFn>>,
+    mailer: Option<Arc<mail::MailerFn>>,
 }
 
 impl FrameworkApp {
     pub fn new(dist_dir: &'static Dir<'static>) -> Self {
         Self {
             dist_dir,
-            configure_fn: None,
+            configure_fns: Vec::new(),
+            scopes: Vec::new(),
             cronjobs_fn: None,
+            middlewares: Vec::new(),
+            migrations_dir: None,
+            public_paths: Vec::new(),
+            argon2_params: auth::Argon2Params::default(),
+            features: HashMap::new(),
+            tera_extensions: TeraExtensions::default(),
+            global_context: None,
+            mailer: None,
         }
     }
 
-    /// Register a route configuration function (like `services::configure`)
+    /// Register a route configuration function (like `services::configure`).
+    /// Can be called several times; the functions are applied in order.
     pub fn configure<F>(mut self, f: F) -> Self
     where
         F: Fn(&mut web::ServiceConfig) + Send + Sync + 'static,
     {
-        self.configure_fn = Some(Box::new(f));
+        self.configure_fns.push(Box::new(f));
         self
     }
 
@@ -172,16 +1068,192 @@ impl FrameworkApp {
         self
     }
 
-    /// Start the framework: loads env, database, cron, and HTTP server
-    pub async fn run(self) -> std::io::Result<()> {
-        env_logger::init_from_env(env_logger::Env::new().default_filter_or("debug"));
-        load_env_file();
+    /// Run migrations embedded in the binary (e.g. `include_dir!("$CARGO_MANIFEST_DIR/migrations")`)
+    /// instead of reading them from `./migrations` at runtime. A `MIGRATIONS_DIR` set in
+    /// the environment still takes precedence.
+    #[must_use]
+    pub fn migrations(mut self, dir: &'static Dir<'static>) -> Self {
+        self.migrations_dir = Some(dir);
+        self
+    }
 
-        info!("Starting application...");
+    /// Paths that always get the `public/` error pages, even for a request that carries
+    /// a valid (maybe stale) session token. A pattern is an exact path, or a prefix
+    /// ending in `*` (e.g. `/blog/*`).
+    #[must_use]
+    pub fn public_paths(mut self, patterns: &[&str]) -> Self {
+        self.public_paths
+            .extend(patterns.iter().map(|pattern| (*pattern).to_string()));
+        self
+    }
 
-        let domain = env::var("DOMAIN").expect("DOMAIN not set in .env file");
-        let jwt_secret = env::var("JWT_SECRET").expect("JWT_SECRET not set in .env file");
-        let database_url = env::var("DATABASE_URL").expect("DATABASE_URL not set in .env file");
+    /// Cost factors for new password hashes; see `Argon2Params`.
+    #[must_use]
+    pub fn argon2_params(mut self, params: auth::Argon2Params) -> Self {
+        self.argon2_params = params;
+        self
+    }
+
+    /// Declare feature flag `name`, on or off by default. `FEATURE_<NAME>=true|false`
+    /// overrides it (and can also set undeclared flags), re-read on `SIGHUP`; see
+    /// `AppData::feature`.
+    #[must_use]
+    pub fn feature(mut self, name: &str, enabled: bool) -> Self {
+        self.features.insert(name.to_lowercase(), enabled);
+        self
+    }
+
+    /// Register a Tera filter for all templates, e.g. `{{ price | currency }}`:
+    ///
+    /// ```ignore
+    /// app.tera_filter("currency", |value: &Value, _: &HashMap<String, Value>| {
+    ///     Ok(Value::String(format!("{:.2} €", value.as_f64().unwrap_or_default())))
+    /// })
+    /// ```
+    #[must_use]
+    pub fn tera_filter(mut self, name: &str, filter: impl tera::Filter + 'static) -> Self {
+        self.tera_extensions
+            .filters
+            .push((name.to_string(), Arc::new(filter)));
+        self
+    }
+
+    /// Register a Tera function for all templates, e.g. `{{ asset(path="logo.svg") }}`.
+    #[must_use]
+    pub fn tera_function(mut self, name: &str, function: impl tera::Function + 'static) -> Self {
+        self.tera_extensions
+            .functions
+            .push((name.to_string(), Arc::new(function)));
+        self
+    }
+
+    /// Values every template gets, e.g. the app name, the current year or the signed-in
+    /// user. `f` returns a JSON object and runs on each render with the claims of the
+    /// request's session, if any; the context passed to the render call is merged on
+    /// top, so its keys win over global ones of the same name:
+    ///
+    /// ```ignore
+    /// app.global_context(|_, claims| {
+    ///     json!({ "app_name": "Acme", "user_id": claims.map(|claims| claims.sub) })
+    /// })
+    /// ```
+    #[must_use]
+    pub fn global_context<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&AppData, Option<&auth::Claims>) -> serde_json::Value + Send + Sync + 'static,
+    {
+        self.global_context = Some(Arc::new(f));
+        self
+    }
+
+    /// Register how mail is sent (SMTP, an HTTP API, ...), used by `AppData::send_mail`:
+    ///
+    /// ```ignore
+    /// app.mailer(|mail: Mail| async move { smtp::send(&mail.to, &mail.subject, &mail.body).await })
+    /// ```
+    #[must_use]
+    pub fn mailer<F, Fut>(mut self, f: F) -> Self
+    where
+        F: Fn(mail::Mail) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), String>> + Send + 'static,
+    {
+        self.mailer = Some(Arc::new(move |mail| Box::pin(f(mail))));
+        self
+    }
+
+    /// Register a custom middleware, applied to every response (including error pages).
+    /// Middleware registered first runs outermost.
+    #[must_use]
+    pub fn middleware<F, Fut>(mut self, f: F) -> Self
+    where
+        F: Fn(ServiceRequest, MiddlewareNext) -> Fut + Send + Sync + 'static,
+        Fut: std::future::Future<Output = Result<ServiceResponse, actix_web::Error>> + 'static,
+    {
+        self.middlewares
+            .push(Box::new(move |req, next| Box::pin(f(req, next))));
+        self
+    }
+
+    /// Register routes under a path prefix with a middleware that only applies to them,
+    /// e.g. an `/api` scope with its own error handling or rate limit.
+    #[must_use]
+    pub fn scope<C, F, Fut>(mut self, path: &'static str, configure: C, middleware: F) -> Self
+    where
+        C: Fn(&mut web::ServiceConfig) + Send + Sync + 'static,
+        F: Fn(ServiceRequest, MiddlewareNext) -> Fut + Send + Sync + 'static,
+        Fut: std::future::Future<Output = Result<ServiceResponse, actix_web::Error>> + 'static,
+    {
+        let middleware: MiddlewareFn = Box::new(move |req, next| Box::pin(middleware(req, next)));
+        self.scopes.push(ScopeDef {
+            path,
+            configure: Box::new(configure),
+            middlewares: Arc::new(vec![middleware]),
+        });
+        self
+    }
+
+    /// Like `run`, but sets up the async runtime itself, for a plain `fn main`. See
+    /// `framework_main!` for the shortest way to use it.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error of `run`.
+    pub fn start(self) -> std::io::Result<()> {
+        actix_web::rt::System::new().block_on(self.run())
+    }
+
+    /// Start the framework: loads env, database, cron, and HTTP server
+    pub async fn run(self) -> std::io::Result<()> {
+        env_logger::init_from_env(env_logger::Env::new().default_filter_or("debug"));
+        load_env_file();
+
+        info!("Starting application...");
+
+        let domain = env::var("DOMAIN").expect("DOMAIN not set in .env file");
+        let jwt_secret = env::var("JWT_SECRET").expect("JWT_SECRET not set in .env file");
+        let jwt_previous_secrets: Arc<Vec<String>> = Arc::new(
+            env::var("JWT_PREVIOUS_SECRETS")
+                .unwrap_or_default()
+                .split(',')
+                .map(str::trim)
+                .filter(|secret| !secret.is_empty())
+                .map(String::from)
+                .collect(),
+        );
+        let timezone = match env::var("DISPLAY_TIMEZONE") {
+            Ok(name) => name.parse::<Tz>().unwrap_or_else(|e| {
+                error!("Invalid DISPLAY_TIMEZONE, falling back to UTC: {e}");
+                Tz::UTC
+            }),
+            Err(_) => Tz::UTC,
+        };
+        let embed_origins = parse_embed_origins(&env::var("EMBED_ORIGINS").unwrap_or_default())
+            .map_err(|e| {
+                error!("{e}");
+                std::io::Error::other(e)
+            })?;
+        let embed_origins = Arc::new(embed_origins);
+        let jwt_ttl = match env::var("JWT_TTL_SECONDS") {
+            Ok(value) => match value.parse() {
+                Ok(secs) if secs > 0 => std::time::Duration::from_secs(secs),
+                // A zero lifetime would issue tokens that are expired on arrival
+                _ => {
+                    error!("Invalid JWT_TTL_SECONDS={value}, falling back to one hour");
+                    auth::DEFAULT_JWT_TTL
+                }
+            },
+            Err(_) => auth::DEFAULT_JWT_TTL,
+        };
+        let trust_proxy = is_enabled(env::var("TRUST_PROXY").ok().as_deref());
+        let base_path = match env::var("BASE_PATH").unwrap_or_default().trim_matches('/') {
+            "" => String::new(),
+            path => format!("/{path}"),
+        };
+        let jwt_leeway = env::var("JWT_LEEWAY_SECONDS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(0);
+        let database_url = env::var("DATABASE_URL").expect("DATABASE_URL not set in .env file");
         let db_file = database_url.trim_start_matches("sqlite:");
 
         if let Some(dir) = std::path::Path::new(db_file).parent() {
@@ -196,14 +1268,27 @@ impl FrameworkApp {
             .await
             .expect("Failed to create database pool");
 
-        let migrations_path =
-            env::var("MIGRATIONS_DIR").unwrap_or_else(|_| "./migrations".to_string());
-        sqlx::migrate::Migrator::new(std::path::Path::new(&migrations_path))
-            .await
-            .expect("Failed to load migrations")
-            .run(&db_pool)
+        // MIGRATIONS_DIR=<path> reads the migrations from disk, even if some are embedded
+        let migrations_path = env::var("MIGRATIONS_DIR").ok();
+        let migrator = match (migrations_path, self.migrations_dir) {
+            (None, Some(dir)) => {
+                sqlx::migrate::Migrator::new(migrations::EmbeddedMigrations(dir)).await
+            }
+            (path, _) => {
+                let path = path.unwrap_or_else(|| "./migrations".to_string());
+                sqlx::migrate::Migrator::new(std::path::Path::new(&path)).await
+            }
+        }
+        .expect("Failed to load migrations");
+        migrations::run(&migrator, &db_pool).await.map_err(|e| {
+            error!("Failed to run database migrations: {e}");
+            std::io::Error::other(e)
+        })?;
+        let migration_count = migrations::applied(&db_pool)
             .await
-            .expect("Failed to run database migrations");
+            .as_ref()
+            .map_or(0, Vec::len);
+        let migration_versions = Arc::new(migrations::versions(&migrator));
 
         sqlx::query("PRAGMA foreign_keys = 1;")
             .execute(&db_pool)
@@ -215,8 +1300,21 @@ impl FrameworkApp {
             .await
             .expect("Failed to set WAL mode");
 
-        let mut tera = Tera::default();
-        add_templates(&mut tera, self.dist_dir);
+        let settings = Arc::new(Settings::default());
+        match settings.refresh(&db_pool).await {
+            Ok(()) => settings::refresh_periodically(
+                settings.clone(),
+                db_pool.clone(),
+                std::time::Duration::from_secs(
+                    env::var("SETTINGS_REFRESH_SECONDS")
+                        .ok()
+                        .and_then(|v| v.parse().ok())
+                        .filter(|n| *n > 0)
+                        .unwrap_or(60),
+                ),
+            ),
+            Err(e) => info!("Database settings disabled: {e}"),
+        }
 
         let env = match env::var("ENV") {
             Ok(val) => match val.as_str() {
@@ -226,70 +1324,120 @@ impl FrameworkApp {
             Err(_) => Env::Prod,
         };
 
+        let cookie = cookie_config(env, !embed_origins.is_empty());
+        let lockout = lockout_policy();
+
+        let public_paths = Arc::new(self.public_paths);
+        let global_context = self.global_context;
+        let mailer = self.mailer;
+        let argon2_params = self.argon2_params;
+        let tera_extensions = Arc::new(self.tera_extensions);
+
+        let mut tera = new_tera(&tera_extensions);
+        // STRICT_TEMPLATES=true aborts on templates that aren't valid UTF-8 instead of
+        // registering them with the invalid bytes replaced
+        let strict_templates = is_enabled(env::var("STRICT_TEMPLATES").ok().as_deref());
+        add_templates(&mut tera, self.dist_dir, env, strict_templates).map_err(|e| {
+            error!("Failed to load templates: {e}");
+            std::io::Error::other(e)
+        })?;
+
         // Cron scheduler
         let mut sched = JobScheduler::new()
             .await
             .expect("Failed to create job scheduler");
 
-        if let Some(cronjobs_fn) = self.cronjobs_fn {
-            let cron_db_pool = SqlitePool::connect(&database_url)
-                .await
-                .expect("Failed to create cron database pool");
+        // CRON_OPTIONAL=true keeps the server running without cron if the setup fails
+        let cron_optional = is_enabled(env::var("CRON_OPTIONAL").ok().as_deref());
+        let cron_ready = setup_cron(self.cronjobs_fn, &sched, &database_url, cron_optional).await?;
 
-            (cronjobs_fn)(sched.clone(), cron_db_pool)
+        let has_jobs = cron_ready
+            && sched
+                .time_till_next_job()
                 .await
-                .expect("Failed to add cron jobs");
-        }
-
-        let has_jobs = sched
-            .time_till_next_job()
-            .await
-            .expect("Failed to check for jobs")
-            .is_some();
+                .expect("Failed to check for jobs")
+                .is_some();
 
         if has_jobs {
             sched.start().await.expect("Failed to start cron scheduler");
             info!("Cron scheduler started.");
         } else {
+            cron::clear_startup_jobs();
             info!("No cronjobs. Cron scheduler not started.");
         }
 
         let dist_dir = self.dist_dir;
-        let configure_fn = self.configure_fn.map(std::sync::Arc::new);
-
-        HttpServer::new(move || {
-            let mut default_headers = DefaultHeaders::new()
-                .add(("X-Content-Type-Options", "nosniff"))
-                .add(("X-Frame-Options", "DENY"))
-                .add(("Referrer-Policy", "strict-origin-when-cross-origin"));
-
-            if env == Env::Dev {
-                default_headers = default_headers.add((
-                    "Content-Security-Policy",
-                    "default-src 'self'; \
-                     script-src 'self' 'unsafe-inline' 'unsafe-eval'; \
-                     style-src 'self' 'unsafe-inline'; \
-                     font-src 'self'; \
-                     img-src 'self' data:; \
-                     connect-src 'self' ws://localhost:4321 http://localhost:4321 ws://127.0.0.1:4321 http://127.0.0.1:4321 ws://0.0.0.0:4321 http://0.0.0.0:4321; \
-                     frame-ancestors 'none'; \
-                     base-uri 'self'; \
-                     form-action 'self';",
-                ));
-            } else {
-                default_headers = default_headers.add((
-                    "Content-Security-Policy",
-                    "default-src 'self'; \
-                     script-src 'self'; \
-                     style-src 'self'; \
-                     font-src 'self'; \
-                     img-src 'self' data:; \
-                     frame-ancestors 'none'; \
-                     base-uri 'self'; \
-                     form-action 'self';",
-                ));
-            }
+        // COMPRESS_ASSETS=true serves embedded files brotli/gzip compressed (cached in memory)
+        let compress_assets = is_enabled(env::var("COMPRESS_ASSETS").ok().as_deref());
+        let configure_fns = Arc::new(self.configure_fns);
+        let scopes = Arc::new(self.scopes);
+        // SERVER_HEADER=<value> sets the `Server` header, SERVER_HEADER= (empty) strips it.
+        let server_header = env::var("SERVER_HEADER").ok();
+        let powered_by = env::var("X_POWERED_BY").ok().filter(|v| !v.is_empty());
+
+        let mut middlewares = self.middlewares;
+        if server_header.as_deref() == Some("") {
+            middlewares.push(Box::new(|req, next| {
+                Box::pin(middleware::strip_server_header(req, next))
+            }));
+        }
+        // LARGE_RESPONSE_BYTES=<n> warns about responses with a larger body
+        if let Some(limit) = env::var("LARGE_RESPONSE_BYTES")
+            .ok()
+            .and_then(|v| v.parse::<u64>().ok())
+        {
+            middlewares.push(Box::new(move |req, next| {
+                Box::pin(middleware::warn_large_response(limit, req, next))
+            }));
+        }
+        // SLIDING_SESSION_FRACTION=<0..1> renews session tokens with less of their lifetime left
+        if let Some(fraction) = env::var("SLIDING_SESSION_FRACTION")
+            .ok()
+            .and_then(|v| v.parse::<f64>().ok())
+            .filter(|fraction| *fraction > 0.0 && *fraction <= 1.0)
+        {
+            middlewares.push(Box::new(move |req, next| {
+                Box::pin(middleware::sliding_session(fraction, req, next))
+            }));
+        }
+        let middlewares = std::sync::Arc::new(middlewares);
+
+        let runtime = Arc::new(RuntimeSettings::from_env(self.features));
+        #[cfg(unix)]
+        reload_on_sighup(runtime.clone());
+
+        let request_timeout = std::time::Duration::from_secs(
+            env::var("REQUEST_TIMEOUT_SECONDS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(30),
+        );
 
+        // MAX_URL_LENGTH / MAX_HEADER_BYTES=<n> answer larger requests with 431
+        let request_limits = middleware::RequestLimits {
+            url: env::var("MAX_URL_LENGTH")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(8 * 1024),
+            headers: env::var("MAX_HEADER_BYTES")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(16 * 1024),
+        };
+
+        let bind_addr = format!(
+            "0.0.0.0:{}",
+            env::var("PORT").unwrap_or_else(|_| "8080".to_string())
+        );
+        let template_count = tera.get_template_names().count();
+        let security_headers = Arc::new(default_headers(
+            env,
+            server_header.as_deref().filter(|v| !v.is_empty()),
+            powered_by.as_deref(),
+            &embed_origins,
+        ));
+
+        let server = HttpServer::new(move || {
             let mut app = App::new()
                 .app_data(web::Data::new(AppData {
                     tera: tera.clone(),
@@ -297,64 +1445,190 @@ impl FrameworkApp {
                     env: env.clone(),
                     domain: domain.clone(),
                     jwt_secret: jwt_secret.clone(),
+                    jwt_previous_secrets: jwt_previous_secrets.clone(),
+                    jwt_leeway,
+                    jwt_ttl,
+                    scheduler: sched.clone(),
+                    runtime: runtime.clone(),
+                    settings: settings.clone(),
+                    timezone,
+                    migrations: migration_versions.clone(),
+                    embed_origins: embed_origins.clone(),
+                    trust_proxy,
+                    base_path: base_path.clone(),
+                    cookie: cookie.clone(),
+                    public_paths: public_paths.clone(),
+                    argon2_params,
+                    lockout,
+                    global_context: global_context.clone(),
+                    mailer: mailer.clone(),
+                    tera_extensions: tera_extensions.clone(),
+                }))
+                .wrap(from_fn(move |req, next| {
+                    middleware::timeout(request_timeout, req, next)
                 }))
+                .wrap(from_fn(middleware::maintenance))
+                .wrap(from_fn(middleware::auto_methods))
                 .wrap(NormalizePath::trim())
+                .wrap(from_fn(move |req, next| {
+                    middleware::request_limits(request_limits, req, next)
+                }))
                 .wrap(
                     ErrorHandlers::new()
                         .handler(StatusCode::INTERNAL_SERVER_ERROR, render_error_page)
                         .handler(StatusCode::NOT_FOUND, render_error_page)
                         .handler(StatusCode::UNAUTHORIZED, render_error_page)
-                        .handler(StatusCode::FORBIDDEN, render_error_page),
+                        .handler(StatusCode::FORBIDDEN, render_error_page)
+                        .handler(StatusCode::METHOD_NOT_ALLOWED, render_error_page)
+                        .handler(StatusCode::TOO_MANY_REQUESTS, render_error_page)
+                        .handler(
+                            StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+                            render_error_page,
+                        )
+                        .handler(StatusCode::SERVICE_UNAVAILABLE, render_error_page)
+                        .handler(StatusCode::GATEWAY_TIMEOUT, render_error_page),
                 )
-                .wrap(default_headers);
+                .wrap(from_fn(csrf::issue))
+                .wrap(from_fn(auth::scope_claims))
+                .wrap(Middlewares(middlewares.clone()))
+                .wrap(from_fn({
+                    let headers = security_headers.clone();
+                    move |req, next| middleware::security_headers(headers.clone(), req, next)
+                }));
 
-            if let Some(ref configure_fn) = configure_fn {
-                let cf = configure_fn.clone();
-                app = app.configure(move |cfg| (cf)(cfg));
-            }
+            app = app.route("/ready", web::get().to(migrations::ready));
 
-            app.service(web::scope("/_astro").route(
-                "/{path:.*}",
-                web::get().to(move |req: actix_web::HttpRequest| async move {
-                    if env == Env::Dev {
-                        if let Ok(res) = forward_to_dev_server(&req).await {
-                            return Ok(res);
-                        }
-                    }
-                    let path = req.path().trim_start_matches('/');
-                    serve_from_dist(dist_dir, path, req.method().as_str()).await
-                }),
-            ))
-            .default_service(web::to(move |req: actix_web::HttpRequest| async move {
-                if env == Env::Dev {
-                    if let Ok(res) = forward_to_dev_server(&req).await {
-                        return Ok(res);
-                    }
-                }
+            app = app.configure(|cfg| register_routes(cfg, &scopes, &configure_fns));
 
-                let path = req.path().trim_start_matches('/');
-                match serve_from_dist(dist_dir, path, req.method().as_str()).await {
-                    Ok(res) => Ok(res),
-                    Err(_) => {
-                        Ok::<HttpResponse, actix_web::Error>(HttpResponse::NotFound().finish())
-                    }
-                }
-            }))
+            app.configure(|cfg| register_assets(cfg, dist_dir, &base_path, env, compress_assets))
         })
-        .bind(format!(
-            "0.0.0.0:{}",
-            env::var("PORT").unwrap_or_else(|_| "8080".to_string())
-        ))?
-        .run()
-        .await
+        .bind(&bind_addr)?;
+
+        StartupSummary {
+            env,
+            bind_addr: &bind_addr,
+            database: db_file,
+            migrations: migration_count,
+            templates: template_count,
+            cron: has_jobs,
+        }
+        .log();
+
+        let server = server.run();
+        cron::run_startup_jobs();
+        server.await
+    }
+}
+
+/// What `run` resolved at startup, logged as a banner for a quick sanity check.
+struct StartupSummary<'a> {
+    env: Env,
+    bind_addr: &'a str,
+    database: &'a str,
+    migrations: usize,
+    templates: usize,
+    cron: bool,
+}
+
+impl StartupSummary<'_> {
+    fn log(&self) {
+        info!("----------------------------------------");
+        info!("Environment: {}", self.env);
+        info!("Listening on: {}", self.bind_addr);
+        info!("Database: {}", self.database);
+        info!("Migrations applied: {}", self.migrations);
+        info!("Templates registered: {}", self.templates);
+        info!("Cron: {}", if self.cron { "active" } else { "inactive" });
+        info!("----------------------------------------");
+    }
+}
+
+/// A `Tera` for the app's templates.
+///
+/// Tera only auto-escapes templates whose name ends in `.html`, but ours are registered
+/// by route (`login`, `users/edit`), so escaping is switched on for every name. Context
+/// values are therefore always HTML-escaped; trusted markup must be opted in with
+/// `{{ value | safe }}` in the template, where it's visible in review.
+///
+/// `extensions` (from `FrameworkApp::tera_filter`/`tera_function`) are registered
+/// here too, so the per-request instances of the dev server path get them like the
+/// prod one.
+fn new_tera(extensions: &TeraExtensions) -> Tera {
+    let mut tera = Tera::default();
+    tera.autoescape_on(vec![""]);
+    for (name, filter) in &extensions.filters {
+        tera.register_filter(name, SharedFilter(filter.clone()));
+    }
+    for (name, function) in &extensions.functions {
+        tera.register_function(name, SharedFunction(function.clone()));
+    }
+    tera
+}
+
+/// Custom filters and functions for every `Tera` from `new_tera`, collected by
+/// `FrameworkApp` and kept in `AppData`.
+#[derive(Default)]
+struct TeraExtensions {
+    filters: Vec<(String, Arc<dyn tera::Filter>)>,
+    functions: Vec<(String, Arc<dyn tera::Function>)>,
+}
+
+struct SharedFilter(Arc<dyn tera::Filter>);
+
+impl tera::Filter for SharedFilter {
+    fn filter(
+        &self,
+        value: &serde_json::Value,
+        args: &HashMap<String, serde_json::Value>,
+    ) -> tera::Result<serde_json::Value> {
+        self.0.filter(value, args)
+    }
+
+    fn is_safe(&self) -> bool {
+        self.0.is_safe()
+    }
+}
+
+struct SharedFunction(Arc<dyn tera::Function>);
+
+impl tera::Function for SharedFunction {
+    fn call(&self, args: &HashMap<String, serde_json::Value>) -> tera::Result<serde_json::Value> {
+        self.0.call(args)
+    }
+
+    fn is_safe(&self) -> bool {
+        self.0.is_safe()
+    }
+}
+
+/// Register all `.html` files as templates. Pages under `dev/` are dev-only overrides:
+/// in `Env::Dev` they replace the base template of the same name, in prod they are skipped.
+fn add_templates(tera: &mut Tera, dir: &Dir, env: Env, strict: bool) -> Result<(), FrameworkError> {
+    register_templates(tera, dir, false, strict)?;
+    if env == Env::Dev {
+        register_templates(tera, dir, true, strict)?;
     }
+    Ok(())
 }
 
-fn add_templates(tera: &mut Tera, dir: &Dir) {
+fn register_templates(
+    tera: &mut Tera,
+    dir: &Dir,
+    overrides: bool,
+    strict: bool,
+) -> Result<(), FrameworkError> {
     for file in dir.files() {
         if let Some(ext) = file.path().extension() {
             if ext == "html" {
-                let path = file.path().to_str().unwrap().replace("\\", "/");
+                let path = file.path().to_string_lossy().replace('\\', "/");
+                let (path, is_override) = match path.strip_prefix("dev/") {
+                    Some(base) => (base.to_string(), true),
+                    None => (path, false),
+                };
+                if is_override != overrides {
+                    continue;
+                }
+
                 let name = if path == "index.html" {
                     "index".to_string()
                 } else if let Some(stripped) = path.strip_suffix("/index.html") {
@@ -362,18 +1636,37 @@ fn add_templates(tera: &mut Tera, dir: &Dir) {
                 } else if let Some(stripped) = path.strip_suffix(".html") {
                     stripped.to_string()
                 } else {
-                    path
+                    path.clone()
                 };
 
                 debug!("Registering template: {}", name);
-                let content = file.contents_utf8().unwrap();
-                tera.add_raw_template(&name, content).unwrap();
+                let content = match file.contents_utf8() {
+                    Some(content) => Cow::Borrowed(content),
+                    None if strict => return Err(FrameworkError::TemplateEncoding { path }),
+                    None => {
+                        warn!("Template {path} is not valid UTF-8, replacing the invalid bytes");
+                        String::from_utf8_lossy(file.contents())
+                    }
+                };
+                let content = content.strip_prefix('\u{feff}').unwrap_or(&content);
+                tera.add_raw_template(&name, content)
+                    .map_err(|source| FrameworkError::TemplateParse { name, source })?;
             }
         }
     }
     for subd in dir.dirs() {
-        add_templates(tera, subd);
+        register_templates(tera, subd, overrides, strict)?;
     }
+    Ok(())
+}
+
+/// The path of an embedded asset for a request path: without `base_path` (when the
+/// request is below it) and the leading slash.
+pub(crate) fn asset_path<'a>(base_path: &str, path: &'a str) -> &'a str {
+    path.strip_prefix(base_path)
+        .filter(|rest| rest.is_empty() || rest.starts_with('/'))
+        .unwrap_or(path)
+        .trim_start_matches('/')
 }
 
 async fn forward_to_dev_server(req: &actix_web::HttpRequest) -> actix_web::Result<HttpResponse> {
@@ -407,70 +1700,123 @@ async fn forward_to_dev_server(req: &actix_web::HttpRequest) -> actix_web::Resul
     Ok(res.body(body))
 }
 
+/// Content types `mime_guess` gets wrong (or doesn't know) on some versions. A wrong
+/// type breaks module scripts and WASM under `X-Content-Type-Options: nosniff`.
+const CONTENT_TYPE_OVERRIDES: &[(&str, &str)] = &[
+    ("mjs", "text/javascript"),
+    ("wasm", "application/wasm"),
+    ("webmanifest", "application/manifest+json"),
+];
+
+fn content_type_for(path: &str) -> &'static str {
+    let extension = std::path::Path::new(path)
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or_default();
+
+    CONTENT_TYPE_OVERRIDES
+        .iter()
+        .find(|(ext, _)| ext.eq_ignore_ascii_case(extension))
+        .map(|(_, content_type)| *content_type)
+        .or_else(|| mime_guess::from_path(path).first_raw())
+        .unwrap_or("application/octet-stream")
+}
+
+/// Astro fingerprints everything under `_astro/`, so those files never change under
+/// the same name. HTML must be revalidated to pick up new asset names after a deploy;
+/// other unhashed files (favicons, manifests) get a short lifetime.
+fn cache_control_for(path: &str, content_type: &str) -> &'static str {
+    if path.starts_with("_astro/") {
+        "public, max-age=31536000, immutable"
+    } else if content_type.starts_with("text/html") {
+        "no-cache"
+    } else {
+        "public, max-age=3600"
+    }
+}
+
 async fn serve_from_dist(
     dist_dir: &Dir<'_>,
+    req: &HttpRequest,
     path: &str,
-    method: &str,
+    compress: bool,
 ) -> actix_web::Result<HttpResponse> {
-    if method != "GET" && method != "HEAD" {
-        return Ok(HttpResponse::MethodNotAllowed().finish());
-    }
-
     let file = dist_dir
         .get_file(path)
         .ok_or_else(|| actix_web::error::ErrorNotFound("File not found"))?;
 
-    let content_type = mime_guess::from_path(path)
-        .first_raw()
-        .unwrap_or("application/octet-stream");
-
-    Ok(HttpResponse::Ok()
-        .content_type(content_type)
-        .insert_header((
-            "Content-Security-Policy",
-            "default-src 'self'; \
-             script-src 'self'; \
-             style-src 'self'; \
-             font-src 'self'; \
-             img-src 'self' data:; \
-             frame-ancestors 'none'; \
-             base-uri 'self'; \
-             form-action 'self';",
-        ))
-        .insert_header(("X-Content-Type-Options", "nosniff"))
-        .insert_header(("X-Frame-Options", "DENY"))
-        .insert_header(("Referrer-Policy", "strict-origin-when-cross-origin"))
-        .body(file.contents().to_vec()))
+    let method = req.method().as_str();
+    if method != "GET" && method != "HEAD" {
+        return Ok(HttpResponse::MethodNotAllowed()
+            .insert_header((header::ALLOW, "GET, HEAD"))
+            .finish());
+    }
+
+    let content_type = content_type_for(path);
+
+    let mut res = HttpResponse::Ok();
+    res.content_type(content_type)
+        .insert_header((header::CACHE_CONTROL, cache_control_for(path, content_type)));
+    if !compress {
+        return Ok(res.body(file.contents().to_vec()));
+    }
+
+    res.insert_header((header::VARY, "Accept-Encoding"));
+    match compressed::ASSETS.get(req, path, content_type, file.contents()) {
+        Some((encoding, body)) => Ok(res
+            .insert_header((header::CONTENT_ENCODING, encoding.as_str()))
+            .body(body)),
+        None => Ok(res.body(file.contents().to_vec())),
+    }
 }
 
 fn render_error_page<B>(res: ServiceResponse<B>) -> actix_web::Result<ErrorHandlerResponse<B>>
 where
     B: MessageBody + 'static,
 {
+    // JSON error bodies are meant for API clients, and pages from `render_error`
+    // already carry the handler's message; keep them as they are
+    let is_json = res
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("application/json"));
+    if is_json || res.response().extensions().contains::<ErrorPageRendered>() {
+        return Ok(ErrorHandlerResponse::Response(res.map_into_left_body()));
+    }
+
     let (req, res) = res.into_parts();
     let data = req.app_data::<web::Data<AppData>>().cloned().unwrap();
     let status = res.status();
 
-    let is_logged_in = crate::auth::read_jwt(&req).is_ok();
-
-    let template = match status {
-        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => {
-            if is_logged_in {
-                "noauth"
-            } else {
-                "public_noauth"
-            }
-        }
-        _ => {
-            if is_logged_in {
-                "error"
-            } else {
-                "public_error"
-            }
-        }
-    };
+    let is_logged_in = data.logged_in_error_page(&req);
+    // Headers telling the client what to do next, e.g. the rate limiter's `Retry-After`
+    let kept_headers: Vec<_> = [
+        header::ALLOW,
+        header::RETRY_AFTER,
+        header::HeaderName::from_static("x-ratelimit-after"),
+    ]
+    .into_iter()
+    .filter_map(|name| Some((name.clone(), res.headers().get(&name)?.clone())))
+    .collect();
+    let retry_after = res
+        .headers()
+        .get(header::RETRY_AFTER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    let code = res
+        .extensions()
+        .get::<ErrorCode>()
+        .copied()
+        .unwrap_or_else(|| ErrorCode::for_status(status));
+    let json = prefers_json(&req);
 
-    let error_msg = req.extensions().get::<String>().cloned();
+    let error_msg = res
+        .extensions()
+        .get::<ErrorDetail>()
+        .map(|detail| detail.0.clone())
+        .or_else(|| res.extensions().get::<String>().cloned())
+        .or_else(|| req.extensions().get::<String>().cloned());
     if let Some(ref msg) = error_msg {
         error!("Error [{}]: {}", status, msg);
     }
@@ -490,24 +1836,1246 @@ where
     };
 
     Ok(ErrorHandlerResponse::Future(Box::pin(async move {
-        let ctx = serde_json::json!({
-            "status": status.as_u16(),
-            "error": display_error,
-        });
-
-        let res_template = data.render_template(template, &ctx).await;
-        let mut res = res_template;
-        *res.status_mut() = status;
-
+        let mut res = if json {
+            json_error(status, code, &display_error)
+        } else {
+            render_status_page(
+                &data,
+                status,
+                is_logged_in,
+                code,
+                display_error,
+                retry_after,
+            )
+            .await
+        };
+        for (name, value) in kept_headers {
+            res.headers_mut().insert(name, value);
+        }
         let res = ServiceResponse::new(req, res).map_into_right_body();
 
         Ok(res)
     })))
 }
 
+fn error_template(status: StatusCode, is_logged_in: bool) -> &'static str {
+    match status {
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => {
+            if is_logged_in {
+                "noauth"
+            } else {
+                "public/noauth"
+            }
+        }
+        _ => {
+            if is_logged_in {
+                "error"
+            } else {
+                "public/error"
+            }
+        }
+    }
+}
+
+/// Marks a response that already is a themed error page.
+struct ErrorPageRendered;
+
+/// Render the themed error page for `status` with the given message, and with
+/// `retry_after` (seconds) for responses that tell the client to wait.
+pub(crate) async fn render_status_page(
+    data: &AppData,
+    status: StatusCode,
+    is_logged_in: bool,
+    code: ErrorCode,
+    error: String,
+    retry_after: Option<u64>,
+) -> HttpResponse {
+    let ctx = serde_json::json!({
+        "status": status.as_u16(),
+        "code": code.0,
+        "error": error,
+        "retry_after": retry_after,
+    });
+
+    let mut res = data
+        .render_template(error_template(status, is_logged_in), &ctx)
+        .await;
+    *res.status_mut() = status;
+    res.extensions_mut().insert(ErrorPageRendered);
+    res
+}
+
+/// `{ "status", "code", "error" }` body for clients that asked for JSON.
+pub(crate) fn json_error(status: StatusCode, code: ErrorCode, error: &str) -> HttpResponse {
+    HttpResponse::build(status).json(serde_json::json!({
+        "status": status.as_u16(),
+        "code": code.0,
+        "error": error,
+    }))
+}
+
+#[cfg(unix)]
+fn reload_on_sighup(runtime: Arc<RuntimeSettings>) {
+    // installed before returning, so a SIGHUP sent right after no longer terminates the process
+    let mut hangup = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()) {
+        Ok(signal) => signal,
+        Err(e) => {
+            error!("Failed to install SIGHUP handler: {e}");
+            return;
+        }
+    };
+
+    actix_web::rt::spawn(async move {
+        while hangup.recv().await.is_some() {
+            runtime.reload();
+        }
+    });
+}
+
 fn load_env_file() {
     match dotenv() {
         Ok(path) => debug!(".env file loaded from: {:?}", path),
         Err(_) => debug!("No .env file found, relying on system environment variables."),
     }
 }
+
+/// Defines `main` for an app: embeds the Astro build from `src/frontend/dist` and runs
+/// `FrameworkApp` on its own runtime. The optional closure configures the app:
+///
+/// ```no_run
+/// use full_stack_engine::prelude::*;
+///
+/// #[get("/")]
+/// async fn index() -> &'static str {
+///     "Hello"
+/// }
+///
+/// framework_main!(dist: "$CARGO_MANIFEST_DIR/src", |app| {
+///     app.configure(|cfg| {
+///         cfg.service(index);
+///     })
+/// });
+/// ```
+///
+/// `dist:` takes another `include_dir!` path for the build; without it
+/// `framework_main!()` or `framework_main!(|app| ...)` use the default location.
+#[macro_export]
+macro_rules! framework_main {
+    () => {
+        $crate::framework_main!(|app| app);
+    };
+    ($setup:expr) => {
+        $crate::framework_main!(dist: "$CARGO_MANIFEST_DIR/src/frontend/dist", $setup);
+    };
+    (dist: $dist:tt) => {
+        $crate::framework_main!(dist: $dist, |app| app);
+    };
+    (dist: $dist:tt, $setup:expr) => {
+        fn main() -> ::std::io::Result<()> {
+            use $crate::prelude::include_dir;
+            static DIST_DIR: include_dir::Dir<'_> = include_dir::include_dir!($dist);
+
+            let setup: fn($crate::FrameworkApp) -> $crate::FrameworkApp = $setup;
+            setup($crate::FrameworkApp::new(&DIST_DIR)).start()
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{TestRequest, call_service, init_service, read_body};
+    use include_dir::{DirEntry, File};
+
+    static EMPTY_DIR: Dir<'_> = Dir::new("", &[]);
+
+    async fn data_with_templates(templates: &[(&str, &str)]) -> web::Data<AppData> {
+        let mut data = testing::app_data().await;
+        data.tera
+            .add_raw_templates(templates.iter().copied())
+            .unwrap();
+        web::Data::new(data)
+    }
+
+    #[actix_web::test]
+    async fn feature_flags_come_from_env_and_reach_templates() {
+        let defaults = HashMap::from([("beta".to_string(), true), ("old".to_string(), true)]);
+        let vars = [
+            ("FEATURE_NEW_DASHBOARD", "true"),
+            ("FEATURE_OLD", "false"),
+            ("OTHER", "true"),
+        ]
+        .map(|(k, v)| (k.to_string(), v.to_string()));
+        let features = features_from(&defaults, vars);
+        assert_eq!(
+            features,
+            HashMap::from([
+                ("beta".to_string(), true),
+                ("old".to_string(), false),
+                ("new_dashboard".to_string(), true),
+            ])
+        );
+
+        let data = data_with_templates(&[(
+            "page",
+            "{% if features.new_dashboard %}new{% else %}old{% endif %}",
+        )])
+        .await;
+        assert!(!data.feature("new_dashboard"));
+        let res = data.render("page").await;
+        assert_eq!(
+            actix_web::body::to_bytes(res.into_body()).await.unwrap(),
+            "old"
+        );
+
+        *data.runtime.features.write().unwrap() = features;
+        assert!(data.feature("new_dashboard"));
+        assert!(!data.feature("unknown"));
+        let res = data.render("page").await;
+        assert_eq!(
+            actix_web::body::to_bytes(res.into_body()).await.unwrap(),
+            "new"
+        );
+    }
+
+    #[actix_web::test]
+    async fn impersonator_reaches_every_template_of_the_session() {
+        let data = data_with_templates(&[(
+            "page",
+            "{% if impersonator %}as {{ impersonator }}{% else %}self{% endif %}",
+        )])
+        .await;
+        let app = init_service(
+            App::new()
+                .app_data(data.clone())
+                .wrap(from_fn(auth::scope_claims))
+                .route(
+                    "/",
+                    web::get()
+                        .to(|data: web::Data<AppData>| async move { data.render("page").await }),
+                ),
+        )
+        .await;
+        let request = |impersonator| {
+            let jwt = auth::encode_jwt(
+                1,
+                structs::UserRole::User,
+                impersonator,
+                testing::JWT_SECRET,
+                data.jwt_ttl,
+            )
+            .unwrap();
+            TestRequest::get()
+                .uri("/")
+                .cookie(actix_web::cookie::Cookie::new("token", jwt))
+                .to_request()
+        };
+
+        let res = call_service(&app, request(Some(2))).await;
+        assert_eq!(read_body(res).await, "as 2");
+        let res = call_service(&app, request(None)).await;
+        assert_eq!(read_body(res).await, "self");
+        let res = call_service(&app, TestRequest::get().uri("/").to_request()).await;
+        assert_eq!(read_body(res).await, "self");
+    }
+
+    #[actix_web::test]
+    async fn rate_limited_request_gets_themed_page_with_retry_after() {
+        let data =
+            data_with_templates(&[("public/error", "{{ status }} retry in {{ retry_after }}s")])
+                .await;
+        let app = init_service(
+            App::new()
+                .app_data(data)
+                .wrap(rate_limiter::auth_rate_limiter_with(60, 1).unwrap())
+                .wrap(
+                    ErrorHandlers::new().handler(StatusCode::TOO_MANY_REQUESTS, render_error_page),
+                )
+                .route("/login", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let request = || {
+            TestRequest::get()
+                .uri("/login")
+                .peer_addr("192.0.2.1:40000".parse().unwrap())
+                .to_request()
+        };
+
+        let res = call_service(&app, request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let res = call_service(&app, request()).await;
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(res.headers().get(header::RETRY_AFTER).unwrap(), "60");
+        assert_eq!(read_body(res).await, "429 retry in 60s");
+    }
+
+    #[actix_web::test]
+    async fn render_json_sets_status_and_falls_back_to_the_error_shape() {
+        let data = testing::app_data().await;
+
+        let res = data.render_json(&serde_json::json!({"id": 1}));
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            res.headers().get(header::CONTENT_TYPE).unwrap(),
+            "application/json"
+        );
+        assert_eq!(
+            actix_web::body::to_bytes(res.into_body()).await.unwrap(),
+            r#"{"id":1}"#
+        );
+
+        let res = data.render_json_status(StatusCode::CREATED, &[1, 2]);
+        assert_eq!(res.status(), StatusCode::CREATED);
+
+        // JSON object keys must be strings
+        let unserializable = HashMap::from([((1, 2), "pair")]);
+        let res = data.render_json_status(StatusCode::CREATED, &unserializable);
+        assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let body: serde_json::Value =
+            serde_json::from_slice(&actix_web::body::to_bytes(res.into_body()).await.unwrap())
+                .unwrap();
+        assert_eq!(body["status"], 500);
+        assert_eq!(body["code"], "SERIALIZATION_ERROR");
+    }
+
+    #[actix_web::test]
+    async fn negotiated_render_answers_json_only_when_preferred() {
+        let data = data_with_templates(&[("user", "<h1>{{ name }}</h1>")]).await;
+        let render = |accept: Option<&str>| {
+            let mut req = TestRequest::get();
+            if let Some(accept) = accept {
+                req = req.insert_header((header::ACCEPT, accept));
+            }
+            let req = req.to_http_request();
+            let data = data.clone();
+            async move {
+                let res = data
+                    .render_negotiated(&req, "user", &serde_json::json!({"name": "Ada"}))
+                    .await;
+                assert_eq!(res.headers().get(header::VARY).unwrap(), "Accept");
+                actix_web::body::to_bytes(res.into_body()).await.unwrap()
+            }
+        };
+
+        assert_eq!(render(None).await, "<h1>Ada</h1>");
+        assert_eq!(render(Some("*/*")).await, "<h1>Ada</h1>");
+        assert_eq!(
+            render(Some("text/html,application/xhtml+xml,*/*;q=0.8")).await,
+            "<h1>Ada</h1>"
+        );
+        assert_eq!(
+            render(Some("text/html;q=0.5, application/json")).await,
+            r#"{"name":"Ada"}"#
+        );
+        assert_eq!(render(Some("application/json")).await, r#"{"name":"Ada"}"#);
+    }
+
+    #[actix_web::test]
+    async fn tera_extensions_reach_prod_and_dev_templates_of_their_app_only() {
+        use serde_json::Value;
+
+        let app = FrameworkApp::new(&EMPTY_DIR)
+            .tera_filter("shout", |value: &Value, _: &HashMap<String, Value>| {
+                Ok(Value::String(
+                    value.as_str().unwrap_or_default().to_uppercase(),
+                ))
+            })
+            .tera_function("greeting", |_: &HashMap<String, Value>| {
+                Ok(Value::String("Hello".to_string()))
+            });
+        let source = "{{ greeting() }} {{ name | shout }}";
+        let context = serde_json::json!({"name": "ada"});
+
+        let mut data = testing::app_data().await;
+        data.tera_extensions = Arc::new(app.tera_extensions);
+        data.tera = new_tera(&data.tera_extensions);
+        data.tera.add_raw_template("page", source).unwrap();
+        for res in [
+            data.render_tpl("page", &context).await,
+            data.render_dev_source("page", source, &context),
+        ] {
+            assert_eq!(res.status(), StatusCode::OK);
+            assert_eq!(
+                actix_web::body::to_bytes(res.into_body()).await.unwrap(),
+                "Hello ADA"
+            );
+        }
+
+        // Another app doesn't get them
+        let mut other = testing::app_data().await;
+        other.tera.add_raw_template("page", source).unwrap();
+        let res = other.render_tpl("page", &context).await;
+        assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[actix_web::test]
+    async fn global_context_sees_the_session_and_yields_to_render_context() {
+        let mut data = testing::app_data().await;
+        data.tera
+            .add_raw_template("page", "{{ app_name }} {{ title }} {{ user_id }}")
+            .unwrap();
+        data.global_context = FrameworkApp::new(&EMPTY_DIR)
+            .global_context(|_, claims| {
+                serde_json::json!({
+                    "app_name": "Acme",
+                    "title": "Home",
+                    "user_id": claims.map_or(0, |claims| claims.sub),
+                })
+            })
+            .global_context;
+        let data = web::Data::new(data);
+        let app = init_service(
+            App::new()
+                .app_data(data.clone())
+                .wrap(from_fn(auth::scope_claims))
+                .route(
+                    "/",
+                    web::get().to(|data: web::Data<AppData>| async move {
+                        data.render_tpl("page", &serde_json::json!({"title": "Users"}))
+                            .await
+                    }),
+                ),
+        )
+        .await;
+
+        let req = testing::signed_in(
+            TestRequest::get().uri("/"),
+            &data,
+            7,
+            structs::UserRole::User,
+        );
+        let res = call_service(&app, req.to_request()).await;
+        assert_eq!(read_body(res).await, "Acme Users 7");
+
+        let res = call_service(&app, TestRequest::get().uri("/").to_request()).await;
+        assert_eq!(read_body(res).await, "Acme Users 0");
+    }
+
+    #[actix_web::test]
+    async fn cached_render_answers_matching_etag_with_304() {
+        let data = data_with_templates(&[("page", "Hello {{ name }}")]).await;
+        let app = init_service(App::new().app_data(data).route(
+            "/",
+            web::get().to(|req: HttpRequest, data: web::Data<AppData>| async move {
+                data.render_tpl_cached(&req, "page", &serde_json::json!({"name": "Ada"}))
+                    .await
+            }),
+        ))
+        .await;
+
+        let res = call_service(&app, TestRequest::get().uri("/").to_request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let etag = res.headers().get(header::ETAG).unwrap().clone();
+        assert_eq!(read_body(res).await, "Hello Ada");
+
+        let req = TestRequest::get()
+            .uri("/")
+            .insert_header((header::IF_NONE_MATCH, etag.clone()))
+            .to_request();
+        let res = call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(res.headers().get(header::ETAG), Some(&etag));
+        assert!(read_body(res).await.is_empty());
+
+        let req = TestRequest::get()
+            .uri("/")
+            .insert_header((header::IF_NONE_MATCH, "\"stale\""))
+            .to_request();
+        let res = call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn registered_middleware_applies_to_all_responses() {
+        let framework = FrameworkApp::new(&EMPTY_DIR).middleware(|req, next| async move {
+            let mut res = next.call(req).await?;
+            res.headers_mut().insert(
+                HeaderName::from_static("x-custom"),
+                HeaderValue::from_static("yes"),
+            );
+            Ok(res)
+        });
+        let app = init_service(
+            App::new()
+                .wrap(Middlewares(Arc::new(framework.middlewares)))
+                .route("/a", web::get().to(HttpResponse::Ok))
+                .route("/b", web::post().to(HttpResponse::Created)),
+        )
+        .await;
+
+        for req in [
+            TestRequest::get().uri("/a"),
+            TestRequest::post().uri("/b"),
+            TestRequest::get().uri("/missing"),
+        ] {
+            let res = call_service(&app, req.to_request()).await;
+            assert_eq!(res.headers().get("x-custom").unwrap(), "yes");
+        }
+    }
+
+    /// Keeps the messages logged while it is installed as the logger.
+    struct CapturedLogs(std::sync::Mutex<Vec<String>>);
+
+    impl log::Log for CapturedLogs {
+        fn enabled(&self, _: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            self.0
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .push(record.args().to_string());
+        }
+
+        fn flush(&self) {}
+    }
+
+    /// The process-wide logger of the tests, installed on first use. Tests run in
+    /// parallel, so look for their own messages rather than comparing all of them.
+    fn captured_logs() -> &'static CapturedLogs {
+        static LOGS: CapturedLogs = CapturedLogs(std::sync::Mutex::new(Vec::new()));
+        static INSTALL: std::sync::Once = std::sync::Once::new();
+        INSTALL.call_once(|| {
+            log::set_logger(&LOGS).unwrap();
+            log::set_max_level(log::LevelFilter::Info);
+        });
+        &LOGS
+    }
+
+    #[test]
+    fn startup_banner_logs_bind_port() {
+        let logs = captured_logs();
+
+        StartupSummary {
+            env: Env::Prod,
+            bind_addr: "0.0.0.0:18080",
+            database: "./data/sqlite.db",
+            migrations: 3,
+            templates: 12,
+            cron: false,
+        }
+        .log();
+
+        let logs = logs.0.lock().unwrap().join("\n");
+        assert!(logs.contains("Listening on: 0.0.0.0:18080"));
+        assert!(logs.contains("Environment: prod"));
+        assert!(logs.contains("Migrations applied: 3"));
+        assert!(logs.contains("Cron: inactive"));
+    }
+
+    #[actix_web::test]
+    async fn configured_server_header_is_sent() {
+        let headers = Arc::new(default_headers(Env::Prod, Some("edge"), Some("Rust"), &[]));
+        let app = init_service(
+            App::new()
+                .wrap(from_fn(move |req, next| {
+                    middleware::security_headers(headers.clone(), req, next)
+                }))
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let res = call_service(&app, TestRequest::get().uri("/").to_request()).await;
+        assert_eq!(res.headers().get(header::SERVER).unwrap(), "edge");
+        assert_eq!(res.headers().get("x-powered-by").unwrap(), "Rust");
+    }
+
+    #[actix_web::test]
+    async fn empty_server_header_strips_it() {
+        let headers = Arc::new(default_headers(Env::Prod, None, None, &[]));
+        let strip: Vec<MiddlewareFn> = vec![Box::new(|req, next| {
+            Box::pin(middleware::strip_server_header(req, next))
+        })];
+        let app = init_service(
+            App::new()
+                .wrap(Middlewares(Arc::new(strip)))
+                .wrap(from_fn(move |req, next| {
+                    middleware::security_headers(headers.clone(), req, next)
+                }))
+                .route(
+                    "/",
+                    web::get().to(|| async {
+                        HttpResponse::Ok()
+                            .insert_header((header::SERVER, "handler"))
+                            .finish()
+                    }),
+                ),
+        )
+        .await;
+
+        let res = call_service(&app, TestRequest::get().uri("/").to_request()).await;
+        assert!(res.headers().get(header::SERVER).is_none());
+        assert!(res.headers().get("x-powered-by").is_none());
+    }
+
+    #[test]
+    fn dev_override_replaces_base_template_in_dev_only() {
+        static TEMPLATES: Dir<'_> = Dir::new(
+            "",
+            &[
+                DirEntry::File(File::new("index.html", b"base")),
+                DirEntry::Dir(Dir::new(
+                    "dev",
+                    &[DirEntry::File(File::new("dev/index.html", b"dev banner"))],
+                )),
+            ],
+        );
+
+        for (env, expected) in [(Env::Dev, "dev banner"), (Env::Prod, "base")] {
+            let mut tera = Tera::default();
+            add_templates(&mut tera, &TEMPLATES, env, true).unwrap();
+            let rendered = tera.render("index", &Context::new()).unwrap();
+            assert_eq!(rendered, expected);
+        }
+    }
+
+    #[test]
+    fn template_names_resolve_as_written_before_the_underscore_spelling() {
+        let mut tera = Tera::default();
+        tera.add_raw_templates([
+            ("users", ""),
+            ("user_profile", ""),
+            ("public/error", ""),
+            ("admin/user_list", ""),
+            ("admin/user/list", ""),
+        ])
+        .unwrap();
+
+        for (name, resolved) in [
+            ("users", "users"),
+            ("user_profile", "user_profile"),
+            ("public/error", "public/error"),
+            ("public_error", "public/error"),
+            ("admin/user_list", "admin/user_list"),
+            ("admin_user_list", "admin/user/list"),
+            ("missing_page", "missing/page"),
+        ] {
+            assert_eq!(resolve_template_name(&tera, name), resolved, "{name}");
+        }
+    }
+
+    #[test]
+    fn non_utf8_template_is_replaced_lossily_unless_strict() {
+        static TEMPLATES: Dir<'_> = Dir::new(
+            "",
+            &[
+                DirEntry::File(File::new("latin1.html", b"Gr\xfc\xdfe")),
+                DirEntry::File(File::new("bom.html", b"\xef\xbb\xbfHallo")),
+            ],
+        );
+
+        let mut tera = Tera::default();
+        add_templates(&mut tera, &TEMPLATES, Env::Prod, false).unwrap();
+        let render = |name| tera.render(name, &Context::new()).unwrap();
+        assert_eq!(render("latin1"), "Gr\u{fffd}\u{fffd}e");
+        assert_eq!(render("bom"), "Hallo");
+
+        assert!(matches!(
+            add_templates(&mut Tera::default(), &TEMPLATES, Env::Prod, true),
+            Err(FrameworkError::TemplateEncoding { path }) if path == "latin1.html"
+        ));
+    }
+
+    #[cfg(unix)]
+    #[actix_web::test]
+    async fn sighup_reloads_maintenance_flag() {
+        let runtime = Arc::new(RuntimeSettings {
+            maintenance: AtomicBool::new(false),
+            feature_defaults: HashMap::new(),
+            features: RwLock::new(HashMap::new()),
+        });
+        reload_on_sighup(runtime.clone());
+
+        // SAFETY: no other test reads or writes `MAINTENANCE`
+        unsafe { env::set_var("MAINTENANCE", "true") };
+        let status = std::process::Command::new("kill")
+            .args(["-HUP", &std::process::id().to_string()])
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        for _ in 0..100 {
+            if runtime.maintenance() {
+                break;
+            }
+            actix_web::rt::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        unsafe { env::remove_var("MAINTENANCE") };
+        assert!(runtime.maintenance());
+    }
+
+    #[actix_web::test]
+    async fn mismatched_host_gets_a_host_only_cookie() {
+        let mut data = testing::app_data().await;
+        data.domain = "example.com".to_string();
+        let request = |host: &str| {
+            TestRequest::default()
+                .insert_header((header::HOST, host))
+                .to_http_request()
+        };
+
+        for host in ["example.com", "app.example.com:8080"] {
+            assert_eq!(data.cookie_domain(&request(host)), Some("example.com"));
+        }
+        for host in ["10.0.0.1:8080", "badexample.com", "example.org"] {
+            let req = request(host);
+            assert_eq!(data.cookie_domain(&req), None);
+            let cookie = auth::session_cookie(
+                &req,
+                &data,
+                "token",
+                "jwt".to_string(),
+                std::time::Duration::from_hours(1),
+            );
+            assert_eq!(cookie.domain(), None);
+        }
+    }
+
+    #[actix_web::test]
+    async fn auth_redirect_carries_security_headers() {
+        let data = web::Data::new(testing::app_data().await);
+        let headers = Arc::new(default_headers(Env::Prod, None, None, &[]));
+        let expected = headers.clone();
+        let app = init_service(
+            App::new()
+                .app_data(data)
+                .wrap(from_fn(move |req, next| {
+                    middleware::security_headers(headers.clone(), req, next)
+                }))
+                .route(
+                    "/private",
+                    web::get().to(|_: auth::AuthUser| async { HttpResponse::Ok().finish() }),
+                ),
+        )
+        .await;
+
+        for (uri, status) in [
+            ("/private", StatusCode::FOUND),
+            ("/missing", StatusCode::NOT_FOUND),
+        ] {
+            let res = call_service(&app, TestRequest::get().uri(uri).to_request()).await;
+            assert_eq!(res.status(), status);
+            for (name, value) in expected.iter() {
+                assert_eq!(res.headers().get(name), Some(value), "{name} on {uri}");
+            }
+        }
+    }
+
+    #[actix_web::test]
+    async fn post_to_static_file_is_themed_405_with_allow() {
+        static DIST: Dir<'_> = Dir::new("", &[DirEntry::File(File::new("style.css", b"body {}"))]);
+        let data = data_with_templates(&[("public/error", "{{ status }} {{ code }}")]).await;
+        let app = init_service(
+            App::new()
+                .app_data(data)
+                .wrap(
+                    ErrorHandlers::new().handler(StatusCode::METHOD_NOT_ALLOWED, render_error_page),
+                )
+                .default_service(web::to(|req: HttpRequest| async move {
+                    let path = req.path().trim_start_matches('/').to_string();
+                    serve_from_dist(&DIST, &req, &path, false).await
+                })),
+        )
+        .await;
+
+        let res = call_service(&app, TestRequest::post().uri("/style.css").to_request()).await;
+
+        assert_eq!(res.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(res.headers().get(header::ALLOW).unwrap(), "GET, HEAD");
+        assert_eq!(read_body(res).await, "405 METHOD_NOT_ALLOWED");
+    }
+
+    #[actix_web::test]
+    async fn stored_utc_time_renders_in_the_display_timezone() {
+        let mut data = testing::app_data().await;
+        data.timezone = chrono_tz::Europe::Berlin;
+        let stored = chrono::NaiveDate::from_ymd_opt(2024, 7, 1)
+            .unwrap()
+            .and_hms_opt(12, 30, 0)
+            .unwrap();
+
+        assert_eq!(data.local_time(&stored), "2024-07-01 14:30 CEST");
+    }
+
+    #[actix_web::test]
+    async fn no_auth_error_carries_code_auth_required() {
+        let data = data_with_templates(&[("public/noauth", "{{ status }} {{ code }}")]).await;
+        let app = init_service(
+            App::new()
+                .app_data(data)
+                .wrap(ErrorHandlers::new().handler(StatusCode::UNAUTHORIZED, render_error_page))
+                .route(
+                    "/",
+                    web::get().to(|| async { Err::<HttpResponse, _>(AppError::NoAuth) }),
+                ),
+        )
+        .await;
+
+        let req = TestRequest::get()
+            .uri("/")
+            .insert_header((header::ACCEPT, "application/json"))
+            .to_request();
+        let res = call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        let body: serde_json::Value = serde_json::from_slice(&read_body(res).await).unwrap();
+        assert_eq!(body["status"], 401);
+        assert_eq!(body["code"], "AUTH_REQUIRED");
+
+        let req = TestRequest::get()
+            .uri("/")
+            .insert_header((header::ACCEPT, "text/html"))
+            .to_request();
+        let res = call_service(&app, req).await;
+        assert_eq!(read_body(res).await, "401 AUTH_REQUIRED");
+    }
+
+    #[actix_web::test]
+    async fn large_response_logs_a_warning() {
+        let logs = captured_logs();
+        let warn_large: Vec<MiddlewareFn> = vec![Box::new(|req, next| {
+            Box::pin(middleware::warn_large_response(1024, req, next))
+        })];
+        let app = init_service(
+            App::new()
+                .wrap(Middlewares(Arc::new(warn_large)))
+                .route("/small", web::get().to(|| async { "x".repeat(1024) }))
+                .route("/large", web::get().to(|| async { "x".repeat(1025) })),
+        )
+        .await;
+
+        for uri in ["/small", "/large"] {
+            call_service(&app, TestRequest::get().uri(uri).to_request()).await;
+        }
+
+        let logs = logs.0.lock().unwrap().join("\n");
+        assert!(logs.contains("Large response: GET /large returned 1025 bytes (limit 1024)"));
+        assert!(!logs.contains("GET /small"));
+    }
+
+    #[actix_web::test]
+    async fn every_configure_call_registers_its_routes() {
+        let framework = FrameworkApp::new(&EMPTY_DIR)
+            .configure(|cfg| {
+                cfg.route("/auth", web::get().to(|| async { "auth" }));
+            })
+            .configure(|cfg| {
+                cfg.route("/admin", web::get().to(|| async { "admin" }));
+            });
+        let app = init_service(App::new().configure(|cfg| {
+            register_routes(cfg, &framework.scopes, &framework.configure_fns);
+        }))
+        .await;
+
+        for uri in ["/auth", "/admin"] {
+            let res = call_service(&app, TestRequest::get().uri(uri).to_request()).await;
+            assert_eq!(res.status(), StatusCode::OK, "{uri}");
+            assert_eq!(read_body(res).await, uri.trim_start_matches('/'));
+        }
+    }
+
+    #[actix_web::test]
+    async fn scope_middleware_only_wraps_its_routes() {
+        let framework = FrameworkApp::new(&EMPTY_DIR)
+            .scope(
+                "/api",
+                |cfg| {
+                    cfg.route("/items", web::get().to(HttpResponse::Ok));
+                },
+                |req, next| async move {
+                    let mut res = next.call(req).await?;
+                    res.headers_mut().insert(
+                        HeaderName::from_static("x-api"),
+                        HeaderValue::from_static("yes"),
+                    );
+                    Ok(res)
+                },
+            )
+            .configure(|cfg| {
+                cfg.route("/items", web::get().to(HttpResponse::Ok));
+            });
+        let app = init_service(App::new().configure(|cfg| {
+            register_routes(cfg, &framework.scopes, &framework.configure_fns);
+        }))
+        .await;
+
+        let res = call_service(&app, TestRequest::get().uri("/api/items").to_request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers().get("x-api").unwrap(), "yes");
+
+        let res = call_service(&app, TestRequest::get().uri("/items").to_request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(res.headers().get("x-api").is_none());
+    }
+
+    #[actix_web::test]
+    async fn failed_cron_setup_only_stops_startup_when_required() {
+        let sched = JobScheduler::new().await.unwrap();
+        let unreachable = "sqlite:/nonexistent/cron.db";
+        let cronjobs = || {
+            FrameworkApp::new(&EMPTY_DIR)
+                .cronjobs(|_, _| async { Ok(()) })
+                .cronjobs_fn
+        };
+
+        assert!(
+            !setup_cron(cronjobs(), &sched, unreachable, true)
+                .await
+                .unwrap()
+        );
+        assert!(
+            setup_cron(cronjobs(), &sched, unreachable, false)
+                .await
+                .is_err()
+        );
+
+        let failing = FrameworkApp::new(&EMPTY_DIR)
+            .cronjobs(|_, _| async { Err("no jobs today".into()) })
+            .cronjobs_fn;
+        assert!(
+            !setup_cron(failing, &sched, "sqlite::memory:", true)
+                .await
+                .unwrap()
+        );
+        assert!(setup_cron(None, &sched, unreachable, false).await.unwrap());
+    }
+
+    static ASSETS: Dir<'_> = Dir::new(
+        "",
+        &[
+            DirEntry::File(File::new("app.mjs", b"export {};")),
+            DirEntry::File(File::new("module.wasm", b"\0asm")),
+            DirEntry::File(File::new("site.webmanifest", b"{}")),
+            DirEntry::File(File::new("index.html", b"<!doctype html>")),
+            DirEntry::Dir(Dir::new(
+                "_astro",
+                &[
+                    DirEntry::File(File::new("_astro/index.B1x9f.js", b"1")),
+                    DirEntry::File(File::new("_astro/foo.js", b"foo")),
+                ],
+            )),
+        ],
+    );
+
+    async fn serve_asset(dist_dir: &Dir<'_>, path: &str) -> HttpResponse {
+        let req = TestRequest::get()
+            .uri(&format!("/{path}"))
+            .to_http_request();
+        serve_from_dist(dist_dir, &req, path, false).await.unwrap()
+    }
+
+    #[actix_web::test]
+    async fn assets_are_served_below_the_base_path() {
+        let app = init_service(
+            App::new().configure(|cfg| register_assets(cfg, &ASSETS, "/app", Env::Prod, false)),
+        )
+        .await;
+
+        for (path, body) in [
+            ("/app/_astro/foo.js", "foo"),
+            ("/app/app.mjs", "export {};"),
+            ("/app/index.html", "<!doctype html>"),
+        ] {
+            let res = call_service(&app, TestRequest::get().uri(path).to_request()).await;
+            assert_eq!(res.status(), StatusCode::OK, "{path}");
+            assert_eq!(read_body(res).await, body, "{path}");
+        }
+
+        let res = call_service(&app, TestRequest::get().uri("/app/missing.js").to_request()).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_web::test]
+    async fn module_scripts_and_wasm_get_their_content_types() {
+        for (path, content_type) in [
+            ("app.mjs", "text/javascript"),
+            ("module.wasm", "application/wasm"),
+            ("site.webmanifest", "application/manifest+json"),
+        ] {
+            let res = serve_asset(&ASSETS, path).await;
+            assert_eq!(
+                res.headers().get(header::CONTENT_TYPE).unwrap(),
+                content_type,
+                "{path}"
+            );
+        }
+    }
+
+    #[actix_web::test]
+    async fn fingerprinted_assets_are_immutable_and_html_revalidates() {
+        for (path, cache_control) in [
+            (
+                "_astro/index.B1x9f.js",
+                "public, max-age=31536000, immutable",
+            ),
+            ("index.html", "no-cache"),
+            ("site.webmanifest", "public, max-age=3600"),
+        ] {
+            let res = serve_asset(&ASSETS, path).await;
+            assert_eq!(
+                res.headers().get(header::CACHE_CONTROL).unwrap(),
+                cache_control,
+                "{path}"
+            );
+        }
+    }
+
+    #[actix_web::test]
+    async fn render_error_uses_noauth_template_for_logged_in_403() {
+        let data = data_with_templates(&[
+            ("noauth", "noauth {{ status }}: {{ error }}"),
+            ("public/noauth", "public/noauth {{ status }}: {{ error }}"),
+        ])
+        .await;
+        let req = TestRequest::get().uri("/reports").app_data(data.clone());
+
+        let signed_in =
+            testing::signed_in(req, &data, 1, structs::UserRole::User).to_http_request();
+        let res = data
+            .render_error(StatusCode::FORBIDDEN, "Reports are for admins.", &signed_in)
+            .await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        assert_eq!(
+            actix_web::body::to_bytes(res.into_body()).await.unwrap(),
+            "noauth 403: Reports are for admins."
+        );
+
+        let anonymous = TestRequest::get().uri("/reports").to_http_request();
+        let res = data
+            .render_error(StatusCode::FORBIDDEN, "Reports are for admins.", &anonymous)
+            .await;
+        assert_eq!(
+            actix_web::body::to_bytes(res.into_body()).await.unwrap(),
+            "public/noauth 403: Reports are for admins."
+        );
+    }
+
+    #[actix_web::test]
+    async fn public_path_gets_public_error_page_despite_valid_token() {
+        let mut data = testing::app_data().await;
+        data.tera
+            .add_raw_templates([
+                ("error", "error {{ status }}"),
+                ("public/error", "public/error {{ status }}"),
+            ])
+            .unwrap();
+        data.public_paths = Arc::new(vec!["/login".to_string(), "/blog/*".to_string()]);
+        let data = web::Data::new(data);
+
+        for (path, template) in [
+            ("/login", "public/error"),
+            ("/blog/first-post", "public/error"),
+            ("/login/extra", "error"),
+            ("/dashboard", "error"),
+        ] {
+            let req = TestRequest::get().uri(path).app_data(data.clone());
+            let req = testing::signed_in(req, &data, 1, structs::UserRole::User).to_http_request();
+            let res = data
+                .render_error(StatusCode::INTERNAL_SERVER_ERROR, "Oops", &req)
+                .await;
+            assert_eq!(
+                actix_web::body::to_bytes(res.into_body()).await.unwrap(),
+                format!("{template} 500"),
+                "{path}"
+            );
+        }
+    }
+
+    #[actix_web::test]
+    async fn try_render_tpl_surfaces_typed_errors() {
+        let data = data_with_templates(&[
+            ("page", "Hello {{ name }}"),
+            ("broken", "Hello {{ missing }}"),
+        ])
+        .await;
+        let context = serde_json::json!({"name": "Ada"});
+
+        let res = data.try_render_tpl("page", &context).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            actix_web::body::to_bytes(res.into_body()).await.unwrap(),
+            "Hello Ada"
+        );
+
+        assert!(matches!(
+            data.try_render_tpl("nope", &context).await,
+            Err(AppError::NotFound(_))
+        ));
+        assert!(matches!(
+            data.try_render_tpl("broken", &context).await,
+            Err(AppError::Template(_))
+        ));
+    }
+
+    /// `framework_main!` expands to a `main` with the usual signature, with and
+    /// without a setup closure.
+    mod minimal_main {
+        crate::framework_main!(dist: "$CARGO_MANIFEST_DIR/src");
+
+        #[test]
+        fn framework_main_defines_main() {
+            let _: fn() -> std::io::Result<()> = main;
+        }
+    }
+
+    mod configured_main {
+        use actix_web::{HttpResponse, web};
+
+        crate::framework_main!(dist: "$CARGO_MANIFEST_DIR/src", |app| {
+            app.public_paths(&["/"]).configure(|cfg| {
+                cfg.route("/", web::get().to(HttpResponse::Ok));
+            })
+        });
+
+        #[test]
+        fn framework_main_with_setup_defines_main() {
+            let _: fn() -> std::io::Result<()> = main;
+        }
+    }
+
+    #[actix_web::test]
+    async fn embedding_mode_relaxes_cookie_and_frame_ancestors() {
+        let origins = parse_embed_origins(" https://portal.example.com, 'self' ").unwrap();
+        let headers = default_headers(Env::Prod, None, None, &origins);
+        let csp = headers
+            .get(header::CONTENT_SECURITY_POLICY)
+            .unwrap()
+            .to_str()
+            .unwrap();
+        assert!(csp.contains("frame-ancestors https://portal.example.com 'self';"));
+        assert!(!headers.contains_key(header::X_FRAME_OPTIONS));
+
+        let mut data = testing::app_data().await;
+        data.cookie = cookie_config(Env::Dev, true);
+        let req = TestRequest::default().to_http_request();
+        let cookie = auth::session_cookie(
+            &req,
+            &data,
+            "token",
+            "jwt".to_string(),
+            std::time::Duration::from_hours(1),
+        );
+        assert_eq!(cookie.same_site(), Some(SameSite::None));
+        assert_eq!(cookie.secure(), Some(true));
+
+        let headers = default_headers(Env::Prod, None, None, &[]);
+        let csp = headers
+            .get(header::CONTENT_SECURITY_POLICY)
+            .unwrap()
+            .to_str()
+            .unwrap();
+        assert!(csp.contains("frame-ancestors 'none';"));
+        assert_eq!(headers.get(header::X_FRAME_OPTIONS).unwrap(), "DENY");
+        assert_eq!(cookie_config(Env::Dev, false).same_site, SameSite::Strict);
+    }
+
+    #[test]
+    fn default_cookie_config_matches_the_fixed_attributes() {
+        let cookie = cookie_config(Env::Prod, false);
+        assert_eq!(cookie.name, "token");
+        assert_eq!(cookie.same_site, SameSite::Strict);
+        assert!(cookie.secure);
+        assert_eq!(cookie.domain, None);
+        assert_eq!(cookie.path, "/");
+        assert!(!cookie_config(Env::Dev, false).secure);
+    }
+
+    #[test]
+    fn invalid_embed_origin_is_rejected() {
+        for origin in [
+            "portal.example.com",
+            "https://portal.example.com/path",
+            "https://a.example.com; script-src *",
+            "ftp://files.example.com",
+            "https://example.com:http",
+        ] {
+            assert!(
+                matches!(
+                    parse_embed_origins(&format!("https://ok.example.com,{origin}")),
+                    Err(FrameworkError::EmbedOrigin(entry)) if entry == origin.trim()
+                ),
+                "{origin}"
+            );
+        }
+        assert_eq!(
+            parse_embed_origins("https://*.example.com:8443,").unwrap(),
+            vec!["https://*.example.com:8443"]
+        );
+    }
+
+    #[actix_web::test]
+    async fn dev_render_error_shows_template_and_detail() {
+        let data = testing::app_data().await;
+        let source = "<h1>Hello</h1>\n<p>{{ user.name }}</p>";
+
+        let res = data.render_dev_source("dashboard", source, &serde_json::json!({}));
+        assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let body = actix_web::body::to_bytes(res.into_body()).await.unwrap();
+        let body = std::str::from_utf8(&body).unwrap();
+        assert!(body.contains("Template error in <code>dashboard</code>"));
+        assert!(body.contains("user.name"));
+        assert!(body.contains("2 | &lt;p&gt;{{ user.name }}&lt;&#x2F;p&gt;"));
+
+        // Production keeps the details in the log
+        let data = data_with_templates(&[("dashboard", source)]).await;
+        let res = data
+            .render_template("dashboard", &serde_json::json!({}))
+            .await;
+        assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(
+            actix_web::body::to_bytes(res.into_body())
+                .await
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[actix_web::test]
+    async fn exists_and_count_see_present_and_absent_rows() {
+        let data = testing::app_data().await;
+        sqlx::query("CREATE TABLE notes (id INTEGER PRIMARY KEY, owner INTEGER NOT NULL)")
+            .execute(&data.db)
+            .await
+            .unwrap();
+        sqlx::query("INSERT INTO notes (owner) VALUES (1), (1), (2)")
+            .execute(&data.db)
+            .await
+            .unwrap();
+
+        let owned_by = |owner: i64| sqlx::query("SELECT id FROM notes WHERE owner = ?").bind(owner);
+        assert!(data.exists(owned_by(1)).await.unwrap());
+        assert!(!data.exists(owned_by(3)).await.unwrap());
+
+        let count_of =
+            |owner: i64| sqlx::query("SELECT COUNT(*) FROM notes WHERE owner = ?").bind(owner);
+        assert_eq!(data.count(count_of(1)).await.unwrap(), 2);
+        assert_eq!(data.count(count_of(3)).await.unwrap(), 0);
+    }
+
+    #[actix_web::test]
+    async fn script_in_context_value_is_escaped() {
+        let data = data_with_templates(&[
+            ("users/edit", "<p>{{ name }}</p>"),
+            ("trusted", "{{ html | safe }}"),
+        ])
+        .await;
+        let name = "<script>alert(1)</script>";
+
+        let res = data
+            .render_template("users/edit", &serde_json::json!({ "name": name }))
+            .await;
+        assert_eq!(
+            actix_web::body::to_bytes(res.into_body()).await.unwrap(),
+            "<p>&lt;script&gt;alert(1)&lt;&#x2F;script&gt;</p>"
+        );
+
+        let res = data
+            .render_template("trusted", &serde_json::json!({ "html": "<b>bold</b>" }))
+            .await;
+        assert_eq!(
+            actix_web::body::to_bytes(res.into_body()).await.unwrap(),
+            "<b>bold</b>"
+        );
+    }
+}
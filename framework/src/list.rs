@@ -0,0 +1,301 @@
+use actix_web::{
+    Error, FromRequest, HttpRequest, HttpResponse,
+    dev::Payload,
+    error::ErrorBadRequest,
+    http::header::{HeaderName, LINK},
+    web,
+};
+use futures::future::{Ready, ready};
+use serde::{Deserialize, Serialize};
+use std::marker::PhantomData;
+
+pub const DEFAULT_PER_PAGE: u32 = 25;
+pub const MAX_PER_PAGE: u32 = 100;
+pub const MAX_SEARCH_LENGTH: usize = 200;
+
+/// The columns a list endpoint may be sorted by.
+///
+/// Only names listed in `COLUMNS` ever reach the SQL, so sorting stays injection-safe.
+pub trait ColumnSet {
+    const COLUMNS: &'static [&'static str];
+    const DEFAULT_SORT: &'static str;
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+impl SortOrder {
+    #[must_use]
+    pub fn as_sql(self) -> &'static str {
+        match self {
+            SortOrder::Asc => "ASC",
+            SortOrder::Desc => "DESC",
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct RawListParams {
+    page: Option<u32>,
+    per_page: Option<u32>,
+    sort: Option<String>,
+    order: Option<SortOrder>,
+    q: Option<String>,
+}
+
+/// Validated `?page=&per_page=&sort=&order=&q=` parameters for list endpoints.
+pub struct ListParams<C: ColumnSet> {
+    pub page: u32,
+    pub per_page: u32,
+    pub sort: &'static str,
+    pub order: SortOrder,
+    pub search: Option<String>,
+    columns: PhantomData<C>,
+}
+
+impl<C: ColumnSet> ListParams<C> {
+    #[must_use]
+    pub fn limit(&self) -> i64 {
+        i64::from(self.per_page)
+    }
+
+    #[must_use]
+    pub fn offset(&self) -> i64 {
+        i64::from(self.page - 1) * i64::from(self.per_page)
+    }
+
+    /// `ORDER BY` clause body, e.g. `created_at DESC`.
+    #[must_use]
+    pub fn order_by(&self) -> String {
+        format!("{} {}", self.sort, self.order.as_sql())
+    }
+
+    fn parse(query: &str) -> Result<Self, String> {
+        let raw = web::Query::<RawListParams>::from_query(query)
+            .map_err(|e| e.to_string())?
+            .into_inner();
+
+        let page = raw.page.unwrap_or(1);
+        if page == 0 {
+            return Err("page must be at least 1".to_string());
+        }
+
+        let per_page = raw.per_page.unwrap_or(DEFAULT_PER_PAGE);
+        if per_page == 0 || per_page > MAX_PER_PAGE {
+            return Err(format!("per_page must be between 1 and {MAX_PER_PAGE}"));
+        }
+
+        let sort = match raw.sort {
+            Some(sort) => C::COLUMNS
+                .iter()
+                .copied()
+                .find(|col| *col == sort)
+                .ok_or_else(|| format!("Unknown sort column: {sort}"))?,
+            None => C::DEFAULT_SORT,
+        };
+
+        let search = raw
+            .q
+            .map(|q| q.trim().to_string())
+            .filter(|q| !q.is_empty());
+        if search
+            .as_ref()
+            .is_some_and(|q| q.chars().count() > MAX_SEARCH_LENGTH)
+        {
+            return Err(format!(
+                "Search term must be at most {MAX_SEARCH_LENGTH} characters"
+            ));
+        }
+
+        Ok(ListParams {
+            page,
+            per_page,
+            sort,
+            order: raw.order.unwrap_or_default(),
+            search,
+            columns: PhantomData,
+        })
+    }
+}
+
+impl<C: ColumnSet> FromRequest for ListParams<C> {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(Self::parse(req.query_string()).map_err(ErrorBadRequest))
+    }
+}
+
+/// One page of a list endpoint, serialized as `{ items, page, per_page, total, total_pages }`.
+#[derive(Serialize, Debug)]
+pub struct Paginated<T> {
+    pub items: Vec<T>,
+    pub page: u32,
+    pub per_page: u32,
+    pub total: i64,
+    pub total_pages: u32,
+}
+
+impl<T: Serialize> Paginated<T> {
+    /// `total` is the number of matching rows across all pages.
+    #[must_use]
+    pub fn new<C: ColumnSet>(items: Vec<T>, params: &ListParams<C>, total: i64) -> Self {
+        let per_page = i64::from(params.per_page);
+        let total_pages = u32::try_from((total.max(0) + per_page - 1) / per_page)
+            .unwrap_or(u32::MAX)
+            .max(1);
+
+        Paginated {
+            items,
+            page: params.page,
+            per_page: params.per_page,
+            total,
+            total_pages,
+        }
+    }
+
+    /// RFC 8288 `Link` header value with `first`, `prev`, `next` and `last` relations,
+    /// keeping the rest of the request's query string.
+    #[must_use]
+    pub fn link_header(&self, req: &HttpRequest) -> String {
+        let url = |page: u32| {
+            let mut query: Vec<&str> = req
+                .query_string()
+                .split('&')
+                .filter(|pair| !pair.is_empty() && !pair.starts_with("page="))
+                .collect();
+            let page = format!("page={page}");
+            query.push(&page);
+            format!("{}?{}", req.path(), query.join("&"))
+        };
+
+        let mut links = vec![format!("<{}>; rel=\"first\"", url(1))];
+        if self.page > 1 {
+            let prev = (self.page - 1).min(self.total_pages);
+            links.push(format!("<{}>; rel=\"prev\"", url(prev)));
+        }
+        if self.page < self.total_pages {
+            links.push(format!("<{}>; rel=\"next\"", url(self.page + 1)));
+        }
+        links.push(format!("<{}>; rel=\"last\"", url(self.total_pages)));
+
+        links.join(", ")
+    }
+
+    /// The page as JSON, with `Link` and `X-Total-Count` headers for generic API clients.
+    #[must_use]
+    pub fn respond(&self, req: &HttpRequest) -> HttpResponse {
+        HttpResponse::Ok()
+            .insert_header((LINK, self.link_header(req)))
+            .insert_header((HeaderName::from_static("x-total-count"), self.total))
+            .json(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{
+        App,
+        http::StatusCode,
+        test::{TestRequest, call_service, init_service},
+    };
+
+    struct NoteColumns;
+
+    impl ColumnSet for NoteColumns {
+        const COLUMNS: &'static [&'static str] = &["title", "created_at"];
+        const DEFAULT_SORT: &'static str = "created_at";
+    }
+
+    fn parse(query: &str) -> Result<ListParams<NoteColumns>, String> {
+        ListParams::parse(query)
+    }
+
+    #[test]
+    fn defaults_without_parameters() {
+        let params = parse("").unwrap();
+        assert_eq!(params.page, 1);
+        assert_eq!(params.per_page, DEFAULT_PER_PAGE);
+        assert_eq!(params.order_by(), "created_at ASC");
+        assert_eq!(params.search, None);
+    }
+
+    #[test]
+    fn accepts_known_sort_column() {
+        let params = parse("page=3&per_page=10&sort=title&order=desc&q=%20milk%20").unwrap();
+        assert_eq!(params.order_by(), "title DESC");
+        assert_eq!(params.offset(), 20);
+        assert_eq!(params.limit(), 10);
+        assert_eq!(params.search.as_deref(), Some("milk"));
+    }
+
+    #[test]
+    fn rejects_unknown_sort_column() {
+        let err = parse("sort=password").err().unwrap();
+        assert_eq!(err, "Unknown sort column: password");
+        assert!(parse("sort=title;DROP TABLE notes").is_err());
+    }
+
+    #[test]
+    fn rejects_out_of_range_values() {
+        assert!(parse("page=0").is_err());
+        assert!(parse(&format!("per_page={}", MAX_PER_PAGE + 1)).is_err());
+        assert!(parse(&format!("q={}", "x".repeat(MAX_SEARCH_LENGTH + 1))).is_err());
+    }
+
+    #[actix_web::test]
+    async fn extractor_answers_unknown_sort_column_with_400() {
+        let app = init_service(App::new().route(
+            "/notes",
+            web::get().to(|params: ListParams<NoteColumns>| async move {
+                HttpResponse::Ok().body(params.order_by())
+            }),
+        ))
+        .await;
+
+        let res = call_service(
+            &app,
+            TestRequest::get().uri("/notes?sort=title").to_request(),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let res = call_service(
+            &app,
+            TestRequest::get().uri("/notes?sort=password").to_request(),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn middle_page_links_to_all_neighbours() {
+        let req = TestRequest::get()
+            .uri("/api/notes?q=milk&page=3&per_page=10")
+            .to_http_request();
+        let params = parse(req.query_string()).unwrap();
+        let page = Paginated::new(vec!["note"; 10], &params, 95);
+
+        assert_eq!(
+            page.link_header(&req),
+            "</api/notes?q=milk&per_page=10&page=1>; rel=\"first\", \
+             </api/notes?q=milk&per_page=10&page=2>; rel=\"prev\", \
+             </api/notes?q=milk&per_page=10&page=4>; rel=\"next\", \
+             </api/notes?q=milk&per_page=10&page=10>; rel=\"last\""
+        );
+
+        let res = page.respond(&req);
+        assert_eq!(res.headers().get("x-total-count").unwrap(), "95");
+        assert_eq!(
+            res.headers().get(LINK).unwrap(),
+            page.link_header(&req).as_str()
+        );
+    }
+}
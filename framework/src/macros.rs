@@ -0,0 +1,84 @@
+//! Shorthands for the `AppResult` responses most handlers end with.
+
+/// `303 See Other` to `location`, optionally setting cookies, wrapped in `Ok`.
+///
+/// ```ignore
+/// return redirect!("/login");
+/// redirect!("/", cookie)
+/// ```
+#[macro_export]
+macro_rules! redirect {
+    ($location:expr $(, $cookie:expr)* $(,)?) => {
+        ::core::result::Result::<$crate::prelude::HttpResponse, $crate::error::AppError>::Ok(
+            $crate::prelude::HttpResponse::SeeOther()
+                .append_header(($crate::prelude::LOCATION, $location))
+                $(.cookie($cookie))*
+                .finish(),
+        )
+    };
+}
+
+/// Render `template` without context, wrapped in `Ok`. Must be used in an async handler.
+///
+/// ```ignore
+/// render!(data, "login")
+/// ```
+#[macro_export]
+macro_rules! render {
+    ($data:expr, $template:expr $(,)?) => {
+        ::core::result::Result::<$crate::prelude::HttpResponse, $crate::error::AppError>::Ok(
+            $data.render($template).await,
+        )
+    };
+}
+
+/// Render `template` with a serializable context, wrapped in `Ok`. Must be used in an async handler.
+///
+/// ```ignore
+/// return render_tpl!(data, "login", &json!({"error": "Falsche Daten"}));
+/// ```
+#[macro_export]
+macro_rules! render_tpl {
+    ($data:expr, $template:expr, $context:expr $(,)?) => {
+        ::core::result::Result::<$crate::prelude::HttpResponse, $crate::error::AppError>::Ok(
+            $data.render_tpl($template, $context).await,
+        )
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{error::AppResult, testing};
+    use actix_web::{
+        body::to_bytes,
+        cookie::Cookie,
+        http::{StatusCode, header::LOCATION},
+    };
+
+    #[test]
+    fn redirect_sees_other_with_cookies() {
+        let res: AppResult = redirect!("/login", Cookie::new("a", "1"), Cookie::new("b", "2"));
+        let res = res.unwrap();
+
+        assert_eq!(res.status(), StatusCode::SEE_OTHER);
+        assert_eq!(res.headers().get(LOCATION).unwrap(), "/login");
+        let cookies: Vec<_> = res.cookies().map(|c| c.name().to_string()).collect();
+        assert_eq!(cookies, ["a", "b"]);
+    }
+
+    #[actix_web::test]
+    async fn render_macros_render_the_template() {
+        let mut data = testing::app_data().await;
+        data.tera
+            .add_raw_templates([("plain", "Hello"), ("greeting", "Hello {{ name }}")])
+            .unwrap();
+
+        let res: AppResult = render!(data, "plain");
+        assert_eq!(to_bytes(res.unwrap().into_body()).await.unwrap(), "Hello");
+
+        let res: AppResult = render_tpl!(data, "greeting", &serde_json::json!({"name": "Ada"}));
+        let res = res.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(to_bytes(res.into_body()).await.unwrap(), "Hello Ada");
+    }
+}
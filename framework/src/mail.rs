@@ -0,0 +1,45 @@
+//! Outgoing email. The framework doesn't speak SMTP itself: register a sender with
+//! `FrameworkApp::mailer` and hand it mails through `AppData::send_mail`.
+
+use futures::future::BoxFuture;
+
+/// A plain-text email.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mail {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+/// Sender registered with `FrameworkApp::mailer`.
+pub(crate) type MailerFn =
+    dyn Fn(Mail) -> BoxFuture<'static, Result<(), String>> + Send + Sync + 'static;
+
+#[cfg(test)]
+mod tests {
+    use super::Mail;
+    use crate::{error::AppError, testing};
+
+    fn mail() -> Mail {
+        Mail {
+            to: "user@example.com".to_string(),
+            subject: "Hello".to_string(),
+            body: "Hi there".to_string(),
+        }
+    }
+
+    #[actix_web::test]
+    async fn mails_go_to_the_registered_sender_and_fail_without_one() {
+        let mut data = testing::app_data().await;
+        assert!(!data.can_send_mail());
+        assert!(matches!(
+            data.send_mail(mail()).await,
+            Err(AppError::Internal(_))
+        ));
+
+        let sent = testing::capture_mail(&mut data);
+        assert!(data.can_send_mail());
+        data.send_mail(mail()).await.unwrap();
+        assert_eq!(*sent.lock().unwrap(), vec![mail()]);
+    }
+}
@@ -0,0 +1,666 @@
+use crate::{AppData, error::ErrorCode, structs::UserRole};
+use actix_web::{
+    Error, HttpResponse,
+    body::{BodySize, BoxBody, MessageBody},
+    dev::{Service, ServiceRequest, ServiceResponse, Transform, always_ready},
+    error::InternalError,
+    http::{
+        Method, StatusCode,
+        header::{self, HeaderMap},
+    },
+    middleware::Next,
+    web,
+};
+use chrono::Utc;
+use futures::future::{LocalBoxFuture, Ready, ready};
+use log::warn;
+use std::{rc::Rc, sync::Arc, time::Duration};
+
+pub(crate) type MiddlewareFn = Box<
+    dyn Fn(
+            ServiceRequest,
+            MiddlewareNext,
+        ) -> LocalBoxFuture<'static, Result<ServiceResponse, Error>>
+        + Send
+        + Sync
+        + 'static,
+>;
+
+type CallFn = Rc<dyn Fn(ServiceRequest) -> LocalBoxFuture<'static, Result<ServiceResponse, Error>>>;
+
+/// The remainder of the middleware chain, handed to middleware registered
+/// through `FrameworkApp::middleware`.
+#[derive(Clone)]
+pub struct MiddlewareNext(CallFn);
+
+impl MiddlewareNext {
+    /// Pass the request on to the next middleware (or the handler).
+    ///
+    /// # Errors
+    ///
+    /// Returns whatever error the inner service produced.
+    pub async fn call(&self, req: ServiceRequest) -> Result<ServiceResponse, Error> {
+        (self.0)(req).await
+    }
+}
+
+/// Applies all user-registered middleware, first registered outermost.
+pub(crate) struct Middlewares(pub(crate) Arc<Vec<MiddlewareFn>>);
+
+impl<S, B> Transform<S, ServiceRequest> for Middlewares
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<BoxBody>;
+    type Error = Error;
+    type Transform = MiddlewaresService;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        let service = Rc::new(service);
+        let mut next = MiddlewareNext(Rc::new(move |req| {
+            let service = Rc::clone(&service);
+            Box::pin(async move {
+                service
+                    .call(req)
+                    .await
+                    .map(ServiceResponse::map_into_boxed_body)
+            })
+        }));
+
+        for i in (0..self.0.len()).rev() {
+            let middlewares = Arc::clone(&self.0);
+            let inner = next;
+            next = MiddlewareNext(Rc::new(move |req| (middlewares[i])(req, inner.clone())));
+        }
+
+        ready(Ok(MiddlewaresService(next)))
+    }
+}
+
+pub(crate) struct MiddlewaresService(MiddlewareNext);
+
+impl Service<ServiceRequest> for MiddlewaresService {
+    type Response = ServiceResponse<BoxBody>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    always_ready!();
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        (self.0.0)(req)
+    }
+}
+
+/// Aborts the handler once `duration` has elapsed and answers with the themed
+/// `504 Gateway Timeout` page.
+///
+/// The request can't be cloned before routing, so the page is rendered here and
+/// returned as an error response rather than through the `ErrorHandlers`.
+pub(crate) async fn timeout(
+    duration: Duration,
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse, Error> {
+    let data = req.app_data::<web::Data<AppData>>().cloned();
+    let token = crate::auth::request_token(req.request());
+    let path = req.path().to_string();
+    let json = crate::prefers_json(req.request());
+
+    if let Ok(res) = actix_web::rt::time::timeout(duration, next.call(req)).await {
+        return res.map(ServiceResponse::map_into_boxed_body);
+    }
+
+    warn!(
+        "Request to {} timed out after {}s",
+        path,
+        duration.as_secs()
+    );
+
+    let status = StatusCode::GATEWAY_TIMEOUT;
+    let code = ErrorCode::for_status(status);
+    let message = status.canonical_reason().unwrap_or_default().to_string();
+    let res = match data {
+        Some(_) if json => crate::json_error(status, code, &message),
+        Some(data) => {
+            let is_logged_in = token.is_some_and(|t| data.verify_jwt(&t).is_ok());
+            crate::render_status_page(&data, status, is_logged_in, code, message, None).await
+        }
+        None => HttpResponse::new(status),
+    };
+
+    Err(InternalError::from_response("Request timed out", res).into())
+}
+
+/// Adds the security headers to every outgoing response, unless the response
+/// already sets them.
+///
+/// Unlike `DefaultHeaders` this also covers error responses returned by inner
+/// middleware (such as the timeout page), which only become responses once they
+/// reach the server.
+pub(crate) async fn security_headers(
+    headers: Arc<HeaderMap>,
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse, Error> {
+    match next.call(req).await {
+        Ok(mut res) => {
+            apply_headers(&headers, res.headers_mut());
+            Ok(res.map_into_boxed_body())
+        }
+        Err(err) => {
+            let mut res = err.error_response();
+            apply_headers(&headers, res.headers_mut());
+            Err(InternalError::from_response(err, res).into())
+        }
+    }
+}
+
+fn apply_headers(defaults: &HeaderMap, headers: &mut HeaderMap) {
+    for (name, value) in defaults {
+        if !headers.contains_key(name) {
+            headers.insert(name.clone(), value.clone());
+        }
+    }
+}
+
+/// Removes the `Server` header from every response, including one set by a handler.
+pub(crate) async fn strip_server_header(
+    req: ServiceRequest,
+    next: MiddlewareNext,
+) -> Result<ServiceResponse, Error> {
+    let mut res = next.call(req).await?;
+    res.headers_mut().remove(header::SERVER);
+    Ok(res)
+}
+
+/// Logs a warning when a response body is larger than `limit` bytes, e.g. an
+/// unpaginated listing. Streamed bodies of unknown length aren't measured.
+pub(crate) async fn warn_large_response(
+    limit: u64,
+    req: ServiceRequest,
+    next: MiddlewareNext,
+) -> Result<ServiceResponse, Error> {
+    let method = req.method().clone();
+    let path = req.path().to_string();
+    let res = next.call(req).await?;
+
+    if let BodySize::Sized(size) = res.response().body().size()
+        && size > limit
+    {
+        warn!("Large response: {method} {path} returned {size} bytes (limit {limit})");
+    }
+
+    Ok(res)
+}
+
+/// `HEAD` and `OPTIONS` for every `GET` route, so routes declared with `#[get]`
+/// don't answer them with 404.
+///
+/// Both are routed as they are first, so handlers registered for `HEAD` or
+/// `OPTIONS` themselves take precedence. If nothing answered (404 or 405), `HEAD`
+/// runs the `GET` handler; the server sends its headers without the body.
+/// `OPTIONS` only looks the path up in the route table, without running a
+/// handler, and answers `204 No Content` with `Allow: GET, HEAD, OPTIONS` if a
+/// resource matches.
+pub(crate) async fn auto_methods(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse, Error> {
+    let method = req.method().clone();
+    let res = next.call(req).await?;
+    if !is_missing(res.status()) || (method != Method::HEAD && method != Method::OPTIONS) {
+        return Ok(res.map_into_boxed_body());
+    }
+
+    if method == Method::OPTIONS {
+        let req = res.request();
+        if !req.resource_map().has_resource(req.path()) {
+            return Ok(res.map_into_boxed_body());
+        }
+        let (req, _) = res.into_parts();
+        return Ok(ServiceResponse::new(req, allow_response()));
+    }
+
+    // Route the request again, as `GET` this time
+    let (req, _) = res.into_parts();
+    let mut req = ServiceRequest::from_request(req);
+    req.match_info_mut().reset();
+    req.head_mut().method = Method::GET;
+    next.call(req)
+        .await
+        .map(ServiceResponse::map_into_boxed_body)
+}
+
+fn is_missing(status: StatusCode) -> bool {
+    status == StatusCode::NOT_FOUND || status == StatusCode::METHOD_NOT_ALLOWED
+}
+
+fn allow_response() -> HttpResponse {
+    HttpResponse::NoContent()
+        .insert_header((header::ALLOW, "GET, HEAD, OPTIONS"))
+        .finish()
+}
+
+/// Answers `503 Service Unavailable` while maintenance mode is on. Assets stay
+/// reachable so the themed maintenance page can still load its styles.
+pub(crate) async fn maintenance(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse, Error> {
+    let data = req.app_data::<web::Data<AppData>>();
+    let in_maintenance = data.is_some_and(|data| data.runtime.maintenance());
+    let is_asset = data
+        .is_some_and(|data| crate::asset_path(&data.base_path, req.path()).starts_with("_astro/"));
+
+    if in_maintenance && !is_asset {
+        return Ok(req.into_response(HttpResponse::ServiceUnavailable().finish()));
+    }
+
+    next.call(req)
+        .await
+        .map(ServiceResponse::map_into_boxed_body)
+}
+
+/// Size limits for the request line and headers, see `request_limits`.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct RequestLimits {
+    /// Longest path and query string, in bytes.
+    pub url: usize,
+    /// Most bytes of header names and values, summed over all headers.
+    pub headers: usize,
+}
+
+/// Answers `431 Request Header Fields Too Large` when the URL or the headers
+/// exceed `limits`, before routing or any extractor sees the request.
+///
+/// Requests past actix-http's own buffer limit (32 KiB for the whole head) are
+/// rejected by the server before they get here, without the themed page.
+pub(crate) async fn request_limits(
+    limits: RequestLimits,
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse, Error> {
+    let url_len = req
+        .uri()
+        .path_and_query()
+        .map_or(0, |path_and_query| path_and_query.as_str().len());
+    let header_len: usize = req
+        .headers()
+        .iter()
+        .map(|(name, value)| name.as_str().len() + value.len())
+        .sum();
+
+    if url_len > limits.url || header_len > limits.headers {
+        warn!(
+            "Rejected oversized request to {}: URL {url_len} bytes, headers {header_len} bytes",
+            req.path()
+        );
+        return Ok(req.into_response(
+            HttpResponse::build(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE).finish(),
+        ));
+    }
+
+    next.call(req)
+        .await
+        .map(ServiceResponse::map_into_boxed_body)
+}
+
+/// Sliding session expiry: once less than `fraction` of `AppData::jwt_ttl` is left on
+/// the session cookie's token, the response re-issues it with the full lifetime.
+///
+/// Fresh tokens are left alone, so there's no `Set-Cookie` on every request, and an
+/// idle session still runs out after one lifetime. The role is reloaded from the
+/// database, so disabled users aren't kept logged in. Impersonation sessions are never
+/// renewed; they end with their token.
+pub(crate) async fn sliding_session(
+    fraction: f64,
+    req: ServiceRequest,
+    next: MiddlewareNext,
+) -> Result<ServiceResponse, Error> {
+    let Some(data) = req.app_data::<web::Data<AppData>>().cloned() else {
+        return next.call(req).await;
+    };
+    let claims = req
+        .cookie(&data.cookie.name)
+        .and_then(|_| crate::auth::read_jwt(req.request()).ok())
+        .filter(|claims| claims.impersonator.is_none());
+    let mut res = next.call(req).await?;
+
+    let Some(claims) = claims else {
+        return Ok(res);
+    };
+    let now = Utc::now().timestamp().unsigned_abs();
+    let remaining = Duration::from_secs((claims.exp as u64).saturating_sub(now));
+    let sets_session = res
+        .response()
+        .cookies()
+        .any(|cookie| cookie.name() == data.cookie.name);
+    if remaining >= data.jwt_ttl.mul_f64(fraction) || sets_session || res.status().is_client_error()
+    {
+        return Ok(res);
+    }
+
+    let role: Option<UserRole> = sqlx::query_scalar("SELECT role FROM users WHERE id = ?")
+        .bind(claims.sub)
+        .fetch_optional(&data.db)
+        .await
+        .unwrap_or_else(|e| {
+            warn!(
+                "Could not load user {} to renew the session: {e}",
+                claims.sub
+            );
+            None
+        });
+    let Some(role) = role.filter(|role| *role != UserRole::None) else {
+        return Ok(res);
+    };
+
+    match crate::auth::encode_jwt(claims.sub, role, None, &data.jwt_secret, data.jwt_ttl) {
+        Ok(jwt) => {
+            let cookie = crate::auth::token_cookie(res.request(), &data, jwt);
+            if let Err(e) = res.response_mut().add_cookie(&cookie) {
+                warn!("Could not renew the session cookie: {e}");
+            }
+        }
+        Err(e) => warn!("Could not renew the session token: {e}"),
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{auth, testing};
+    use actix_web::{
+        App,
+        body::to_bytes,
+        cookie::Cookie,
+        middleware::{ErrorHandlers, from_fn},
+        test::{TestRequest, call_service, init_service, try_call_service},
+    };
+
+    #[actix_web::test]
+    async fn slow_handler_times_out_with_504() {
+        let mut data = testing::app_data().await;
+        data.tera
+            .add_raw_template("public/error", "{{ status }} {{ code }}")
+            .unwrap();
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(data))
+                .wrap(from_fn(|req, next| {
+                    timeout(Duration::from_millis(50), req, next)
+                }))
+                .route(
+                    "/slow",
+                    web::get().to(|| async {
+                        actix_web::rt::time::sleep(Duration::from_secs(5)).await;
+                        HttpResponse::Ok().finish()
+                    }),
+                ),
+        )
+        .await;
+
+        let started = std::time::Instant::now();
+        let err = try_call_service(&app, TestRequest::get().uri("/slow").to_request())
+            .await
+            .unwrap_err();
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        let res = err.error_response();
+        assert_eq!(res.status(), StatusCode::GATEWAY_TIMEOUT);
+        assert_eq!(to_bytes(res.into_body()).await.unwrap(), "504 TIMEOUT");
+    }
+
+    #[actix_web::test]
+    async fn oversized_url_or_headers_get_themed_431() {
+        let mut data = testing::app_data().await;
+        data.tera
+            .add_raw_template("public/error", "{{ status }} {{ code }}")
+            .unwrap();
+        let limits = RequestLimits {
+            url: 64,
+            headers: 256,
+        };
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(data))
+                .wrap(from_fn(move |req, next| request_limits(limits, req, next)))
+                .wrap(ErrorHandlers::new().handler(
+                    StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+                    crate::render_error_page,
+                ))
+                .route("/list", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let res = call_service(&app, TestRequest::get().uri("/list?q=short").to_request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let long_query = format!("/list?q={}", "x".repeat(64));
+        let res = call_service(&app, TestRequest::get().uri(&long_query).to_request()).await;
+        assert_eq!(res.status(), StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE);
+        assert_eq!(
+            to_bytes(res.into_body()).await.unwrap(),
+            "431 REQUEST_TOO_LARGE"
+        );
+
+        let req = TestRequest::get()
+            .uri("/list")
+            .insert_header(("x-padding", "x".repeat(256)));
+        let res = call_service(&app, req.to_request()).await;
+        assert_eq!(res.status(), StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE);
+    }
+
+    /// `AppData` with user 1, a `User`, in the database.
+    async fn data_with_user() -> web::Data<AppData> {
+        let data = web::Data::new(testing::app_data().await);
+        sqlx::query("CREATE TABLE users (id INTEGER PRIMARY KEY, role TEXT NOT NULL)")
+            .execute(&data.db)
+            .await
+            .unwrap();
+        sqlx::query("INSERT INTO users (id, role) VALUES (1, 'user')")
+            .execute(&data.db)
+            .await
+            .unwrap();
+        data
+    }
+
+    fn sliding_session_middleware(fraction: f64) -> Middlewares {
+        let sliding: Vec<MiddlewareFn> = vec![Box::new(move |req, next| {
+            Box::pin(sliding_session(fraction, req, next))
+        })];
+        Middlewares(Arc::new(sliding))
+    }
+
+    #[actix_web::test]
+    async fn renewed_cookie_expires_with_its_token() {
+        let data = data_with_user().await;
+        let app = init_service(
+            App::new()
+                .app_data(data.clone())
+                .wrap(sliding_session_middleware(0.5))
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let old = auth::encode_jwt(
+            1,
+            UserRole::User,
+            None,
+            testing::JWT_SECRET,
+            Duration::from_mins(1),
+        )
+        .unwrap();
+
+        let req = TestRequest::get()
+            .uri("/")
+            .cookie(Cookie::new("token", old))
+            .to_request();
+        let res = call_service(&app, req).await;
+
+        let cookie = res
+            .response()
+            .cookies()
+            .find(|c| c.name() == "token")
+            .unwrap();
+        let max_age = cookie.max_age().unwrap().whole_seconds();
+        let claims = data.verify_jwt(cookie.value()).unwrap();
+        let remaining = i64::try_from(claims.exp).unwrap() - Utc::now().timestamp();
+        assert_eq!(max_age, i64::try_from(data.jwt_ttl.as_secs()).unwrap());
+        assert!((remaining - max_age).abs() <= 1);
+    }
+
+    #[actix_web::test]
+    async fn fresh_tokens_and_disabled_users_are_not_renewed() {
+        let data = data_with_user().await;
+        let app = init_service(
+            App::new()
+                .app_data(data.clone())
+                .wrap(sliding_session_middleware(0.5))
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let request = |ttl| {
+            let jwt = auth::encode_jwt(1, UserRole::User, None, testing::JWT_SECRET, ttl).unwrap();
+            TestRequest::get()
+                .uri("/")
+                .cookie(Cookie::new("token", jwt))
+                .to_request()
+        };
+
+        let res = call_service(&app, request(data.jwt_ttl)).await;
+        assert_eq!(res.response().cookies().count(), 0);
+
+        sqlx::query("UPDATE users SET role = 'none' WHERE id = 1")
+            .execute(&data.db)
+            .await
+            .unwrap();
+        let res = call_service(&app, request(Duration::from_mins(1))).await;
+        assert_eq!(res.response().cookies().count(), 0);
+    }
+
+    #[actix_web::test]
+    async fn impersonation_sessions_are_not_renewed() {
+        let data = data_with_user().await;
+        let app = init_service(
+            App::new()
+                .app_data(data.clone())
+                .wrap(sliding_session_middleware(0.5))
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let jwt = auth::encode_jwt(
+            1,
+            UserRole::User,
+            Some(2),
+            testing::JWT_SECRET,
+            Duration::from_mins(1),
+        )
+        .unwrap();
+
+        let req = TestRequest::get()
+            .uri("/")
+            .cookie(Cookie::new("token", jwt))
+            .to_request();
+        let res = call_service(&app, req).await;
+        assert_eq!(res.response().cookies().count(), 0);
+    }
+
+    #[actix_web::test]
+    async fn get_routes_answer_head_and_options() {
+        let app = init_service(App::new().wrap(from_fn(auto_methods)).route(
+            "/users",
+            web::get().to(|| async {
+                HttpResponse::Ok()
+                    .content_type("text/html")
+                    .insert_header(("x-page", "users"))
+                    .body("<h1>Users</h1>")
+            }),
+        ))
+        .await;
+
+        let req = TestRequest::default().method(Method::HEAD).uri("/users");
+        let res = call_service(&app, req.to_request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            res.headers().get(header::CONTENT_TYPE).unwrap(),
+            "text/html"
+        );
+        assert_eq!(res.headers().get("x-page").unwrap(), "users");
+
+        let req = TestRequest::default().method(Method::OPTIONS).uri("/users");
+        let res = call_service(&app, req.to_request()).await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        assert_eq!(
+            res.headers().get(header::ALLOW).unwrap(),
+            "GET, HEAD, OPTIONS"
+        );
+        assert!(to_bytes(res.into_body()).await.unwrap().is_empty());
+
+        let req = TestRequest::default().method(Method::OPTIONS).uri("/nope");
+        let res = call_service(&app, req.to_request()).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        assert!(res.headers().get(header::ALLOW).is_none());
+    }
+
+    #[actix_web::test]
+    async fn options_does_not_run_the_get_handler_and_explicit_routes_win() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        static LOGOUTS: AtomicUsize = AtomicUsize::new(0);
+        let app = init_service(
+            App::new()
+                .wrap(from_fn(auto_methods))
+                .route(
+                    "/logout",
+                    web::get().to(|| async {
+                        LOGOUTS.fetch_add(1, Ordering::SeqCst);
+                        HttpResponse::Ok().finish()
+                    }),
+                )
+                .route(
+                    "/files/{name}",
+                    web::get().to(|| async { HttpResponse::Ok().body("file") }),
+                )
+                .route(
+                    "/files/{name}",
+                    web::head().to(|| async {
+                        HttpResponse::Ok()
+                            .insert_header(("x-route", "head"))
+                            .finish()
+                    }),
+                )
+                .route(
+                    "/files/{name}",
+                    web::method(Method::OPTIONS).to(|| async {
+                        HttpResponse::Ok()
+                            .insert_header(("x-route", "options"))
+                            .finish()
+                    }),
+                ),
+        )
+        .await;
+
+        let req = TestRequest::default()
+            .method(Method::OPTIONS)
+            .uri("/logout");
+        let res = call_service(&app, req.to_request()).await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        assert_eq!(LOGOUTS.load(Ordering::SeqCst), 0);
+
+        let req = TestRequest::default().method(Method::HEAD).uri("/logout");
+        let res = call_service(&app, req.to_request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(LOGOUTS.load(Ordering::SeqCst), 1);
+
+        for (method, route) in [(Method::HEAD, "head"), (Method::OPTIONS, "options")] {
+            let req = TestRequest::default().method(method).uri("/files/a.txt");
+            let res = call_service(&app, req.to_request()).await;
+            assert_eq!(res.status(), StatusCode::OK);
+            assert_eq!(res.headers().get("x-route").unwrap(), route);
+        }
+    }
+}
@@ -0,0 +1,232 @@
+use crate::{AppData, error::FrameworkError};
+use actix_web::{HttpResponse, web};
+use futures::future::BoxFuture;
+use include_dir::Dir;
+use log::error;
+use sqlx::SqlitePool;
+use sqlx::error::BoxDynError;
+use sqlx::migrate::{MigrateError, Migration, MigrationSource, MigrationType, Migrator};
+use std::{borrow::Cow, path::Path};
+
+/// Migrations embedded into the binary with `include_dir!`.
+///
+/// Files follow the same `<VERSION>_<DESCRIPTION>.sql` naming as a `sqlx` migrations directory.
+#[derive(Debug)]
+pub struct EmbeddedMigrations(pub &'static Dir<'static>);
+
+impl MigrationSource<'static> for EmbeddedMigrations {
+    fn resolve(self) -> BoxFuture<'static, Result<Vec<Migration>, BoxDynError>> {
+        Box::pin(async move {
+            let mut migrations = Vec::new();
+
+            for file in self.0.files() {
+                let Some(file_name) = file.path().file_name().and_then(|n| n.to_str()) else {
+                    continue;
+                };
+
+                let Some((version, rest)) = file_name.split_once('_') else {
+                    continue;
+                };
+                let rest = Path::new(rest);
+                if !rest
+                    .extension()
+                    .is_some_and(|ext| ext.eq_ignore_ascii_case("sql"))
+                {
+                    continue;
+                }
+                // `MigrationType` only knows the lowercase suffixes
+                let rest = rest.with_extension("sql");
+                let rest = rest.to_str().unwrap_or_default();
+
+                let version: i64 = version.parse().map_err(|_| {
+                    format!("error parsing migration filename {file_name:?}; expected integer version prefix")
+                })?;
+
+                let migration_type = MigrationType::from_filename(rest);
+                let description = rest
+                    .trim_end_matches(migration_type.suffix())
+                    .replace('_', " ");
+
+                let sql = file
+                    .contents_utf8()
+                    .ok_or_else(|| format!("migration {file_name:?} is not valid UTF-8"))?;
+
+                migrations.push(Migration::new(
+                    version,
+                    Cow::Owned(description),
+                    migration_type,
+                    Cow::Borrowed(sql),
+                    sql.starts_with("-- no-transaction"),
+                ));
+            }
+
+            migrations.sort_by_key(|m| m.version);
+
+            Ok(migrations)
+        })
+    }
+}
+
+/// Apply all pending migrations, reporting edited migrations by version.
+///
+/// # Errors
+///
+/// Returns `FrameworkError::MigrationChecksum` when an applied migration was modified,
+/// or `FrameworkError::Migration` for any other migration failure.
+pub async fn run(migrator: &Migrator, pool: &SqlitePool) -> Result<(), FrameworkError> {
+    migrator.run(pool).await.map_err(|e| match e {
+        MigrateError::VersionMismatch(version) => FrameworkError::MigrationChecksum { version },
+        e => FrameworkError::Migration(e),
+    })
+}
+
+/// Versions of all migrations known to `migrator`.
+#[must_use]
+pub fn versions(migrator: &Migrator) -> Vec<i64> {
+    migrator
+        .iter()
+        .filter(|m| !m.migration_type.is_down_migration())
+        .map(|m| m.version)
+        .collect()
+}
+
+/// Versions of the migrations applied successfully to the database.
+///
+/// # Errors
+///
+/// Returns the database error if the migrations table can't be read.
+pub async fn applied(pool: &SqlitePool) -> Result<Vec<i64>, sqlx::Error> {
+    sqlx::query_scalar("SELECT version FROM _sqlx_migrations WHERE success = 1")
+        .fetch_all(pool)
+        .await
+}
+
+/// Versions from `expected` that haven't been applied successfully to the database.
+///
+/// # Errors
+///
+/// Returns the database error if the migrations table can't be read.
+pub async fn pending(expected: &[i64], pool: &SqlitePool) -> Result<Vec<i64>, sqlx::Error> {
+    let applied = applied(pool).await?;
+
+    Ok(expected
+        .iter()
+        .copied()
+        .filter(|v| !applied.contains(v))
+        .collect())
+}
+
+/// `GET /ready`: `200` once every migration shipped with the binary is applied,
+/// `503` listing the pending versions otherwise.
+pub(crate) async fn ready(data: web::Data<AppData>) -> HttpResponse {
+    let body = match pending(&data.migrations, &data.db).await {
+        Ok(pending) if pending.is_empty() => {
+            return HttpResponse::Ok().json(serde_json::json!({ "status": "ready" }));
+        }
+        Ok(pending) => serde_json::json!({ "status": "not ready", "pending_migrations": pending }),
+        Err(e) => {
+            error!("Readiness check failed: {e}");
+            serde_json::json!({ "status": "not ready", "error": "database unavailable" })
+        }
+    };
+
+    HttpResponse::ServiceUnavailable().json(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use actix_web::{
+        http::StatusCode,
+        test::{TestRequest, call_service, init_service, read_body_json},
+    };
+    use include_dir::{DirEntry, File};
+
+    static MIGRATIONS: Dir<'_> = Dir::new(
+        "",
+        &[
+            DirEntry::File(File::new(
+                "1_notes.sql",
+                b"CREATE TABLE notes (id INTEGER PRIMARY KEY);",
+            )),
+            DirEntry::File(File::new(
+                "2_tags.SQL",
+                b"CREATE TABLE tags (id INTEGER PRIMARY KEY);",
+            )),
+            DirEntry::File(File::new("README.md", b"not a migration")),
+        ],
+    );
+
+    #[actix_web::test]
+    async fn embedded_migrations_run_without_files_on_disk() {
+        let data = testing::app_data_with_migrations(&MIGRATIONS).await;
+
+        assert_eq!(applied(&data.db).await.unwrap(), vec![1, 2]);
+        for table in ["notes", "tags"] {
+            sqlx::query(&format!("SELECT COUNT(*) FROM {table}"))
+                .execute(&data.db)
+                .await
+                .unwrap();
+        }
+    }
+
+    #[actix_web::test]
+    async fn modified_migration_reports_its_version() {
+        static MODIFIED: Dir<'_> = Dir::new(
+            "",
+            &[
+                DirEntry::File(File::new(
+                    "1_notes.sql",
+                    b"CREATE TABLE notes (id INTEGER PRIMARY KEY, title TEXT);",
+                )),
+                DirEntry::File(File::new(
+                    "2_tags.sql",
+                    b"CREATE TABLE tags (id INTEGER PRIMARY KEY);",
+                )),
+            ],
+        );
+        let data = testing::app_data_with_migrations(&MIGRATIONS).await;
+
+        let migrator = Migrator::new(EmbeddedMigrations(&MODIFIED)).await.unwrap();
+        let err = run(&migrator, &data.db).await.unwrap_err();
+
+        assert!(matches!(
+            err,
+            FrameworkError::MigrationChecksum { version: 1 }
+        ));
+    }
+
+    #[actix_web::test]
+    async fn pending_migration_marks_the_app_not_ready() {
+        let mut data = testing::app_data_with_migrations(&MIGRATIONS).await;
+        let data_ready = web::Data::new(testing::app_data_with_migrations(&MIGRATIONS).await);
+        // A newer binary that ships a third migration the database hasn't seen yet.
+        data.migrations = std::sync::Arc::new(vec![1, 2, 3]);
+        let data_behind = web::Data::new(data);
+
+        for (data, status, body) in [
+            (
+                data_ready,
+                StatusCode::OK,
+                serde_json::json!({ "status": "ready" }),
+            ),
+            (
+                data_behind,
+                StatusCode::SERVICE_UNAVAILABLE,
+                serde_json::json!({ "status": "not ready", "pending_migrations": [3] }),
+            ),
+        ] {
+            let app = init_service(
+                actix_web::App::new()
+                    .app_data(data)
+                    .route("/ready", web::get().to(ready)),
+            )
+            .await;
+            let res = call_service(&app, TestRequest::get().uri("/ready").to_request()).await;
+
+            assert_eq!(res.status(), status);
+            assert_eq!(read_body_json::<serde_json::Value, _>(res).await, body);
+        }
+    }
+}
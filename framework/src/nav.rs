@@ -0,0 +1,124 @@
+use crate::{auth::Claims, structs::UserRole};
+use serde::Serialize;
+use serde_json::Value;
+
+/// A navigation link.
+#[derive(Serialize, Debug, Clone)]
+pub struct NavItem {
+    pub label: String,
+    pub href: String,
+    /// Least role that may open the link; not sent to the template.
+    #[serde(skip)]
+    pub role: UserRole,
+}
+
+/// The navigation of the app, declared once with the role each link needs and
+/// rendered with only the links the current user may open:
+///
+/// ```ignore
+/// let nav = NavBuilder::new()
+///     .item("Index", "/", UserRole::User)
+///     .item("Users", "/users", UserRole::Admin);
+/// data.render_tpl("index", &nav.context(&user.claims, json!({}))).await
+/// ```
+///
+/// Templates loop over `nav` instead of checking `role` around admin links.
+#[derive(Debug, Clone, Default)]
+pub struct NavBuilder {
+    items: Vec<NavItem>,
+}
+
+impl NavBuilder {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a link for users with at least `role`.
+    #[must_use]
+    pub fn item(
+        mut self,
+        label: impl Into<String>,
+        href: impl Into<String>,
+        role: UserRole,
+    ) -> Self {
+        self.items.push(NavItem {
+            label: label.into(),
+            href: href.into(),
+            role,
+        });
+        self
+    }
+
+    /// The links `claims` may open, in the order they were added.
+    #[must_use]
+    pub fn build(&self, claims: &Claims) -> Vec<NavItem> {
+        self.items
+            .iter()
+            .filter(|item| claims.role.satisfies(item.role))
+            .cloned()
+            .collect()
+    }
+
+    /// `context` (a JSON object, e.g. `json!({"title": "Users"})`) with the links
+    /// `claims` may open added as `nav`.
+    #[must_use]
+    pub fn context(&self, claims: &Claims, context: Value) -> Value {
+        let mut context = match context {
+            Value::Object(map) => map,
+            _ => serde_json::Map::new(),
+        };
+        context.insert(
+            "nav".to_string(),
+            serde_json::to_value(self.build(claims)).unwrap_or_default(),
+        );
+        Value::Object(context)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn claims(role: UserRole) -> Claims {
+        Claims {
+            sub: 1,
+            role,
+            exp: 0,
+            impersonator: None,
+        }
+    }
+
+    fn nav() -> NavBuilder {
+        NavBuilder::new()
+            .item("Index", "/", UserRole::User)
+            .item("Users", "/users", UserRole::Admin)
+            .item("Profile", "/profile", UserRole::User)
+    }
+
+    #[test]
+    fn user_context_excludes_admin_items() {
+        let context = nav().context(&claims(UserRole::User), json!({"title": "Index"}));
+        assert_eq!(
+            context,
+            json!({
+                "title": "Index",
+                "nav": [
+                    {"label": "Index", "href": "/"},
+                    {"label": "Profile", "href": "/profile"},
+                ],
+            })
+        );
+    }
+
+    #[test]
+    fn admin_sees_every_item_in_order() {
+        let hrefs: Vec<_> = nav()
+            .build(&claims(UserRole::Admin))
+            .into_iter()
+            .map(|item| item.href)
+            .collect();
+        assert_eq!(hrefs, ["/", "/users", "/profile"]);
+    }
+}
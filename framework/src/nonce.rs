@@ -0,0 +1,234 @@
+use hmac::{Hmac, Mac};
+use rand::{RngCore, rng};
+use sha2::Sha256;
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fmt::Write,
+    sync::{Mutex, PoisonError},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// Upper bound for outstanding nonces per store; the oldest are dropped beyond it.
+pub const MAX_NONCES: usize = 10_000;
+
+/// Single-use nonces kept on the server.
+///
+/// Issue one, hand it to the client and `consume` it when it comes back. A nonce is
+/// only accepted once and only within `ttl`, so double submits and replayed requests
+/// are rejected. For forms anyone can load, prefer `FormNonces`, which stores nothing
+/// until a nonce is used.
+///
+/// A nonce can also carry a value that stays on the server, e.g. the user of a login
+/// waiting for its second factor: `issue_with` it and get it back with `take`.
+pub struct NonceStore<T = ()> {
+    ttl: Duration,
+    issued: Mutex<HashMap<String, (Instant, T)>>,
+}
+
+impl<T> NonceStore<T> {
+    #[must_use]
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            issued: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Create and remember a fresh nonce carrying `value`.
+    pub fn issue_with(&self, value: T) -> String {
+        let mut bytes = [0u8; 16];
+        rng().fill_bytes(&mut bytes);
+        let nonce = bytes.iter().fold(String::new(), |mut s, b| {
+            let _ = write!(s, "{b:02x}");
+            s
+        });
+
+        let now = Instant::now();
+        let mut issued = self.issued.lock().unwrap_or_else(PoisonError::into_inner);
+        issued.retain(|_, (at, _)| now.duration_since(*at) < self.ttl);
+        while issued.len() >= MAX_NONCES {
+            let Some(oldest) = issued
+                .iter()
+                .min_by_key(|(_, (at, _))| *at)
+                .map(|(n, _)| n.clone())
+            else {
+                break;
+            };
+            issued.remove(&oldest);
+        }
+        issued.insert(nonce.clone(), (now, value));
+
+        nonce
+    }
+
+    /// The value of `nonce` if it was issued within the TTL and not used before.
+    pub fn take(&self, nonce: &str) -> Option<T> {
+        self.issued
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(nonce)
+            .filter(|(at, _)| at.elapsed() < self.ttl)
+            .map(|(_, value)| value)
+    }
+}
+
+impl NonceStore {
+    /// Create and remember a fresh nonce.
+    pub fn issue(&self) -> String {
+        self.issue_with(())
+    }
+
+    /// Returns `true` if `nonce` was issued within the TTL and not used before.
+    pub fn consume(&self, nonce: &str) -> bool {
+        self.take(nonce).is_some()
+    }
+}
+
+/// Single-use nonces for public forms such as the login page.
+///
+/// A nonce is its issue time and a random part, signed with a key of the store, so
+/// issuing one stores nothing and a flood of page loads can't push out the nonces
+/// of real visitors. `consume` checks the signature and age and remembers the nonce
+/// until it expires, so each one is accepted once within `ttl`.
+pub struct FormNonces {
+    key: [u8; 32],
+    ttl: Duration,
+    used: Mutex<UsedNonces>,
+}
+
+/// Consumed nonces, oldest first, forgotten once they would have expired anyway.
+#[derive(Default)]
+struct UsedNonces {
+    order: VecDeque<(Instant, String)>,
+    nonces: HashSet<String>,
+}
+
+impl FormNonces {
+    #[must_use]
+    pub fn new(ttl: Duration) -> Self {
+        let mut key = [0u8; 32];
+        rng().fill_bytes(&mut key);
+        Self {
+            key,
+            ttl,
+            used: Mutex::new(UsedNonces::default()),
+        }
+    }
+
+    /// A fresh nonce, `<issued at>.<random>.<signature>`.
+    #[must_use]
+    pub fn issue(&self) -> String {
+        let mut bytes = [0u8; 16];
+        rng().fill_bytes(&mut bytes);
+        let payload = format!("{:x}.{}", unix_now(), hex(&bytes));
+        let signature = hex(&self.mac(&payload).finalize().into_bytes());
+        format!("{payload}.{signature}")
+    }
+
+    /// Returns `true` if `nonce` was issued by this store within the TTL and not used
+    /// before.
+    pub fn consume(&self, nonce: &str) -> bool {
+        let Some((payload, signature)) = nonce.rsplit_once('.') else {
+            return false;
+        };
+        let Some(signature) = unhex(signature) else {
+            return false;
+        };
+        if self.mac(payload).verify_slice(&signature).is_err() {
+            return false;
+        }
+        let issued = payload
+            .split_once('.')
+            .and_then(|(issued, _)| u64::from_str_radix(issued, 16).ok());
+        let Some(issued) = issued else {
+            return false;
+        };
+        if Duration::from_secs(unix_now().saturating_sub(issued)) >= self.ttl {
+            return false;
+        }
+
+        let now = Instant::now();
+        let mut used = self.used.lock().unwrap_or_else(PoisonError::into_inner);
+        while let Some((at, _)) = used.order.front()
+            && now.duration_since(*at) >= self.ttl
+        {
+            if let Some((_, old)) = used.order.pop_front() {
+                used.nonces.remove(&old);
+            }
+        }
+        if !used.nonces.insert(nonce.to_string()) {
+            return false;
+        }
+        used.order.push_back((now, nonce.to_string()));
+        true
+    }
+
+    fn mac(&self, payload: &str) -> Hmac<Sha256> {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC accepts keys of any length");
+        mac.update(payload.as_bytes());
+        mac
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut s, b| {
+        let _ = write!(s, "{b:02x}");
+        s
+    })
+}
+
+fn unhex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn form_nonce_is_accepted_once() {
+        let nonces = FormNonces::new(Duration::from_mins(15));
+        let nonce = nonces.issue();
+
+        assert!(nonces.consume(&nonce));
+        assert!(!nonces.consume(&nonce));
+    }
+
+    #[test]
+    fn foreign_tampered_and_expired_form_nonces_are_rejected() {
+        let nonces = FormNonces::new(Duration::from_mins(15));
+        let nonce = nonces.issue();
+
+        assert!(!nonces.consume(&FormNonces::new(Duration::from_mins(15)).issue()));
+        let (payload, signature) = nonce.rsplit_once('.').unwrap();
+        assert!(!nonces.consume(&format!("0{payload}.{signature}")));
+        assert!(!nonces.consume("not a nonce"));
+
+        let expiring = FormNonces::new(Duration::ZERO);
+        assert!(!expiring.consume(&expiring.issue()));
+    }
+
+    #[test]
+    fn issuing_many_form_nonces_keeps_earlier_ones_valid() {
+        let nonces = FormNonces::new(Duration::from_mins(15));
+        let first = nonces.issue();
+        for _ in 0..MAX_NONCES * 2 {
+            let _ = nonces.issue();
+        }
+
+        assert!(nonces.consume(&first));
+    }
+}
@@ -1,26 +1,103 @@
 //! Prelude for the `full_stack_engine` crate.
 //!
 //! This module re-exports common types and traits for ease of use.
+//!
+//! Handlers can query the database without `sqlx` in their own `Cargo.toml`:
+//!
+//! ```no_run
+//! use full_stack_engine::prelude::*;
+//!
+//! async fn notes(data: Data<AppData>) -> AppResult {
+//!     let notes: Vec<(i64, String)> = query_as("SELECT id, title FROM notes")
+//!         .fetch_all(&data.db)
+//!         .await?;
+//!     Ok(HttpResponse::Ok().json(notes))
+//! }
+//! ```
+//!
+//! The compile-time checked `sqlx::query!` macros and `#[derive(FromRow)]` expand to
+//! `::sqlx` paths, so those still need the dependency.
 
 pub use crate::{
-    AppData, Env, FrameworkApp,
-    auth::{AdminUser, AuthUser, create_jwt, hash_password, verify_password},
-    error::{AppError, AppResult, ResultExt},
-    structs::{Table, TableAction, TableHeader, User, UserRole},
+    AppData, Env, FrameworkApp, audit,
+    auth::{
+        API_KEY_HEADER, AdminAction, AdminUser, ApiKey, Argon2Params, AuthUser, Claims,
+        CookieConfig, LockoutPolicy, OptionalAuthUser, PASSWORD_VERSION, PasswordError,
+        REFRESH_TOKEN_TTL, RESET_TOKEN_TTL, RequiredRole, Role, TOTP_STEP_SECONDS, VerifyResult,
+        consume_refresh_token, consume_reset_token, create_api_key, create_impersonation_jwt,
+        create_jwt, create_jwt_with_ttl, create_refresh_token, create_reset_token,
+        generate_totp_secret, hash_password, hash_password_async, login_locked, logout,
+        needs_rehash, record_login_failure, request_token, reset_login_failures, revoke_api_key,
+        revoke_refresh_token, revoke_user_refresh_tokens, role, session_cookie, token_cookie,
+        totp_provisioning_uri, verify_password, verify_password_async, verify_password_detailed,
+        verify_password_detailed_async, verify_totp_code,
+    },
+    client_ip::ClientIp,
+    csrf::{CSRF_FIELD, CSRF_HEADER, CsrfGuard},
+    ctx::Ctx,
+    download::{download, download_blocking, download_stream},
+    error::{AppError, AppResult, ErrorCode, FrameworkError, ResultExt},
+    framework_main,
+    list::{ColumnSet, ListParams, Paginated, SortOrder},
+    mail::Mail,
+    middleware::MiddlewareNext,
+    nav::{NavBuilder, NavItem},
+    nonce::{FormNonces, NonceStore},
+    redirect, render, render_tpl,
+    structs::{RoleOption, Table, TableAction, TableHeader, User, UserRole},
+    transaction::{Tx, unit_of_work},
+    validation::{JsonSchema, SchemaJson, ValidatedJson},
 };
 
 // Full crate re-exports (so users don't need them in Cargo.toml)
 pub use actix_web::{
-    self, HttpResponse, Responder, cookie, delete, get, http, http::header::LOCATION, main, post,
-    put, web, web::Data, web::Form,
+    self, HttpRequest, HttpResponse, Responder, cookie, delete, get, http, http::header::LOCATION,
+    main, post, put, web, web::Data, web::Form,
 };
 pub use include_dir;
 pub use log::{self, debug, error, info, warn};
 pub use reqwest;
 pub use serde::{self, Deserialize, Serialize};
 pub use serde_json::{self, json};
+pub use sqlx::{self, FromRow, Row, SqlitePool, query, query_as, query_scalar};
 pub use tera::{self, Context};
 pub use tokio_cron_scheduler;
+pub use validator::{self, Validate};
 
 // Common traits/types
 pub use std::convert::{TryFrom, TryInto};
+
+#[cfg(test)]
+mod tests {
+    // Only the prelude, as in a downstream crate without `sqlx` in its `Cargo.toml`
+    use super::*;
+    use actix_web::{
+        App,
+        test::{TestRequest, call_service, init_service, read_body_json},
+    };
+
+    async fn summary(data: Data<AppData>) -> AppResult {
+        let pool: &SqlitePool = &data.db;
+        let (answer, name): (i64, String) = query_as("SELECT 42, 'notes'").fetch_one(pool).await?;
+        let doubled: i64 = query_scalar("SELECT ? * 2")
+            .bind(answer)
+            .fetch_one(pool)
+            .await?;
+        let row = query("SELECT 'done' AS status").fetch_one(pool).await?;
+        let status: String = row.get("status");
+        Ok(HttpResponse::Ok().json(json!({ "name": name, "doubled": doubled, "status": status })))
+    }
+
+    #[actix_web::test]
+    async fn handler_queries_with_prelude_imports_only() {
+        let data = Data::new(crate::testing::app_data().await);
+        let app = init_service(App::new().app_data(data).route("/", web::get().to(summary))).await;
+
+        let res = call_service(&app, TestRequest::get().uri("/").to_request()).await;
+        let body: serde_json::Value = read_body_json(res).await;
+        assert_eq!(
+            body,
+            json!({ "name": "notes", "doubled": 84, "status": "done" })
+        );
+    }
+}
@@ -1,24 +1,457 @@
+use crate::{auth::read_jwt, client_ip::ClientIp, error::FrameworkError};
 use actix_governor::governor::middleware::NoOpMiddleware;
-use actix_governor::{Governor, GovernorConfigBuilder, PeerIpKeyExtractor};
+use actix_governor::governor::{
+    NotUntil,
+    clock::{Clock, DefaultClock, QuantaInstant},
+};
+use actix_governor::{Governor, GovernorConfigBuilder, KeyExtractor, SimpleKeyExtractionError};
+use actix_web::{
+    HttpResponse, HttpResponseBuilder,
+    dev::ServiceRequest,
+    http::header::{ContentType, RETRY_AFTER},
+};
+use log::error;
+use sha2::{Digest, Sha256};
+use std::{net::IpAddr, sync::LazyLock};
+
+/// Header trusted clients send `RATE_LIMIT_BYPASS_KEY` in.
+pub const BYPASS_KEY_HEADER: &str = "x-rate-limit-bypass";
 
 /// Rate limiter for authentication endpoints (login, register)
-pub fn auth_rate_limiter() -> Governor<PeerIpKeyExtractor, NoOpMiddleware> {
+///
+/// Allows `AUTH_RATE_BURST` requests (default 1), refilled one per
+/// `AUTH_RATE_SECONDS` (default 120). Invalid values are logged and the defaults
+/// used instead.
+///
+/// # Panics
+///
+/// Never in practice; the defaults are valid.
+#[must_use]
+pub fn auth_rate_limiter() -> Governor<TrustedKeyExtractor, NoOpMiddleware> {
+    let seconds_per_request = env_or("AUTH_RATE_SECONDS", AUTH_RATE_SECONDS);
+    let burst_size = env_or("AUTH_RATE_BURST", AUTH_RATE_BURST);
+
+    auth_rate_limiter_with(seconds_per_request, burst_size)
+        .or_else(|e| {
+            error!("{e}, using {AUTH_RATE_BURST} per {AUTH_RATE_SECONDS}s for auth endpoints");
+            auth_rate_limiter_with(AUTH_RATE_SECONDS, AUTH_RATE_BURST)
+        })
+        .expect("Failed to create auth rate limiter config")
+}
+
+/// Rate limiter for authentication endpoints allowing `burst_size` requests,
+/// refilled one per `seconds_per_request`.
+///
+/// # Errors
+///
+/// `FrameworkError::RateLimitConfig` if either value is 0.
+pub fn auth_rate_limiter_with(
+    seconds_per_request: u64,
+    burst_size: u32,
+) -> Result<Governor<TrustedKeyExtractor, NoOpMiddleware>, FrameworkError> {
     let config = GovernorConfigBuilder::default()
-        .seconds_per_request(120)
-        .burst_size(1)
+        .seconds_per_request(seconds_per_request)
+        .burst_size(burst_size)
+        .key_extractor(TrustedKeyExtractor)
         .finish()
-        .expect("Failed to create auth rate limiter config");
+        .ok_or(FrameworkError::RateLimitConfig {
+            seconds_per_request,
+            burst_size,
+        })?;
 
-    Governor::new(&config)
+    Ok(Governor::new(&config))
+}
+
+const AUTH_RATE_SECONDS: u64 = 120;
+const AUTH_RATE_BURST: u32 = 1;
+
+fn env_or<T: std::str::FromStr>(name: &str, default: T) -> T {
+    match std::env::var(name) {
+        Ok(value) => value.parse().unwrap_or_else(|_| {
+            error!("{name}={value} is not a valid number, ignoring it");
+            default
+        }),
+        Err(_) => default,
+    }
 }
 
 /// Rate limiter for general endpoints
-pub fn general_rate_limiter() -> Governor<PeerIpKeyExtractor, NoOpMiddleware> {
+///
+/// # Panics
+///
+/// Never in practice; the fixed quota is valid.
+#[must_use]
+pub fn general_rate_limiter() -> Governor<TrustedKeyExtractor, NoOpMiddleware> {
     let config = GovernorConfigBuilder::default()
         .seconds_per_request(1)
         .burst_size(100)
+        .key_extractor(TrustedKeyExtractor)
         .finish()
         .expect("Failed to create general rate limiter config");
 
     Governor::new(&config)
 }
+
+/// Rate limiter for expensive per-account endpoints: limits each signed-in user on
+/// their own, however many of them share an address, and anonymous clients by address.
+///
+/// # Panics
+///
+/// Never in practice; the fixed quota is valid.
+#[must_use]
+pub fn user_rate_limiter() -> Governor<ClaimsKeyExtractor, NoOpMiddleware> {
+    let config = GovernorConfigBuilder::default()
+        .seconds_per_request(2)
+        .burst_size(30)
+        .key_extractor(ClaimsKeyExtractor)
+        .finish()
+        .expect("Failed to create user rate limiter config");
+
+    Governor::new(&config)
+}
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub enum RateLimitKey {
+    /// Allowlisted client, never limited.
+    Trusted,
+    Peer(IpAddr),
+    /// Signed-in user, by `Claims::sub`.
+    User(i64),
+}
+
+/// Limits by `ClientIp` (per address for IPv4, per /56 prefix for IPv6), except for
+/// trusted clients: those in `RATE_LIMIT_ALLOWLIST` (comma-separated IPs or CIDRs,
+/// e.g. `10.0.0.0/8,::1`) and requests carrying `RATE_LIMIT_BYPASS_KEY` in the
+/// `X-Rate-Limit-Bypass` header.
+///
+/// Forwarding headers are only honoured with `TRUST_PROXY`, so the allowlist can't be
+/// spoofed by clients.
+#[derive(Clone, PartialEq, Eq)]
+pub struct TrustedKeyExtractor;
+
+impl KeyExtractor for TrustedKeyExtractor {
+    type Key = RateLimitKey;
+    type KeyExtractionError = SimpleKeyExtractionError<&'static str>;
+
+    fn extract(&self, req: &ServiceRequest) -> Result<Self::Key, Self::KeyExtractionError> {
+        let ip = ClientIp::of(req.request())
+            .ok_or_else(|| SimpleKeyExtractionError::new("Could not determine the client IP"))?
+            .0;
+        if is_trusted(req, ip) {
+            return Ok(RateLimitKey::Trusted);
+        }
+
+        // customers often get their own /56 prefix, so limit IPv6 per prefix
+        Ok(RateLimitKey::Peer(match ip {
+            IpAddr::V4(_) => ip,
+            IpAddr::V6(ipv6) => {
+                let mut octets = ipv6.octets();
+                octets[7..16].fill(0);
+                IpAddr::V6(octets.into())
+            }
+        }))
+    }
+
+    fn whitelisted_keys(&self) -> Vec<Self::Key> {
+        vec![RateLimitKey::Trusted]
+    }
+
+    /// governor truncates the wait to whole seconds, so a bucket refilling in 0.4s
+    /// would be announced as `Retry-After: 0` and clients retrying right away would be
+    /// limited again. Round up instead, so retrying after the announced time succeeds.
+    fn exceed_rate_limit_response(
+        &self,
+        negative: &NotUntil<QuantaInstant>,
+        response: HttpResponseBuilder,
+    ) -> HttpResponse {
+        rate_limited_response(negative, response)
+    }
+}
+
+/// Limits signed-in users by their id (the `sub` of a valid session token) and
+/// everyone else like `TrustedKeyExtractor`, whose allowlist and bypass key also
+/// apply here.
+#[derive(Clone, PartialEq, Eq)]
+pub struct ClaimsKeyExtractor;
+
+impl KeyExtractor for ClaimsKeyExtractor {
+    type Key = RateLimitKey;
+    type KeyExtractionError = SimpleKeyExtractionError<&'static str>;
+
+    fn extract(&self, req: &ServiceRequest) -> Result<Self::Key, Self::KeyExtractionError> {
+        let key = TrustedKeyExtractor.extract(req);
+        if matches!(key, Ok(RateLimitKey::Trusted)) {
+            return key;
+        }
+
+        match read_jwt(req.request()) {
+            Ok(claims) => Ok(RateLimitKey::User(claims.sub)),
+            Err(_) => key,
+        }
+    }
+
+    fn whitelisted_keys(&self) -> Vec<Self::Key> {
+        vec![RateLimitKey::Trusted]
+    }
+
+    fn exceed_rate_limit_response(
+        &self,
+        negative: &NotUntil<QuantaInstant>,
+        response: HttpResponseBuilder,
+    ) -> HttpResponse {
+        rate_limited_response(negative, response)
+    }
+}
+
+fn rate_limited_response(
+    negative: &NotUntil<QuantaInstant>,
+    mut response: HttpResponseBuilder,
+) -> HttpResponse {
+    let wait = retry_after_secs(negative);
+    response
+        .insert_header((RETRY_AFTER, wait))
+        .insert_header(("x-ratelimit-after", wait))
+        .content_type(ContentType::plaintext())
+        .body(format!("Too many requests, retry in {wait}s"))
+}
+
+/// Seconds until the bucket has a cell again, rounded up and at least 1.
+fn retry_after_secs(negative: &NotUntil<QuantaInstant>) -> u64 {
+    let wait = negative.wait_time_from(DefaultClock::default().now());
+    let secs = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+    secs.max(1)
+}
+
+struct Trusted {
+    networks: Vec<Network>,
+    /// SHA-256 of `RATE_LIMIT_BYPASS_KEY`, compared as a digest so the check doesn't
+    /// leak the key through timing.
+    key_digest: Option<[u8; 32]>,
+}
+
+static TRUSTED: LazyLock<Trusted> = LazyLock::new(|| {
+    Trusted::new(
+        &std::env::var("RATE_LIMIT_ALLOWLIST").unwrap_or_default(),
+        std::env::var("RATE_LIMIT_BYPASS_KEY").ok().as_deref(),
+    )
+});
+
+impl Trusted {
+    /// `allowlist` as in `RATE_LIMIT_ALLOWLIST`; an empty `key` disables the bypass key.
+    fn new(allowlist: &str, key: Option<&str>) -> Self {
+        Trusted {
+            networks: allowlist
+                .split(',')
+                .map(str::trim)
+                .filter(|entry| !entry.is_empty())
+                .filter_map(|entry| {
+                    let network = Network::parse(entry);
+                    if network.is_none() {
+                        error!("Ignoring invalid RATE_LIMIT_ALLOWLIST entry: {entry}");
+                    }
+                    network
+                })
+                .collect(),
+            key_digest: key
+                .filter(|key| !key.is_empty())
+                .map(|key| Sha256::digest(key.as_bytes()).into()),
+        }
+    }
+
+    fn allows(&self, req: &ServiceRequest, ip: IpAddr) -> bool {
+        if self.networks.iter().any(|network| network.contains(ip)) {
+            return true;
+        }
+
+        let Some(expected) = self.key_digest else {
+            return false;
+        };
+        req.headers()
+            .get(BYPASS_KEY_HEADER)
+            .is_some_and(|value| <[u8; 32]>::from(Sha256::digest(value.as_bytes())) == expected)
+    }
+}
+
+fn is_trusted(req: &ServiceRequest, ip: IpAddr) -> bool {
+    TRUSTED.allows(req, ip)
+}
+
+/// An IP address with a prefix length; a plain address is a network of one.
+struct Network {
+    addr: IpAddr,
+    prefix: u32,
+}
+
+impl Network {
+    fn parse(entry: &str) -> Option<Self> {
+        let (addr, prefix) = match entry.split_once('/') {
+            Some((addr, prefix)) => (addr.parse::<IpAddr>().ok()?, Some(prefix.parse().ok()?)),
+            None => (entry.parse::<IpAddr>().ok()?, None),
+        };
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = prefix.unwrap_or(max);
+
+        (prefix <= max).then_some(Self { addr, prefix })
+    }
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{structs::UserRole, testing};
+    use actix_web::{
+        App,
+        cookie::Cookie,
+        http::StatusCode,
+        test::{TestRequest, call_service, init_service},
+        web,
+    };
+    use std::net::SocketAddr;
+
+    fn from(peer: &str) -> TestRequest {
+        TestRequest::get().peer_addr(SocketAddr::new(peer.parse().unwrap(), 40000))
+    }
+
+    #[test]
+    fn allowlisted_addresses_and_the_bypass_key_are_trusted() {
+        let trusted = Trusted::new("10.0.0.0/8, ::1, not-an-ip", Some("monitor-key"));
+        let allows =
+            |req: TestRequest, ip: &str| trusted.allows(&req.to_srv_request(), ip.parse().unwrap());
+
+        assert!(allows(from("10.20.30.40"), "10.20.30.40"));
+        assert!(allows(from("::1"), "::1"));
+        assert!(!allows(from("192.0.2.1"), "192.0.2.1"));
+        assert!(allows(
+            from("192.0.2.1").insert_header((BYPASS_KEY_HEADER, "monitor-key")),
+            "192.0.2.1"
+        ));
+        assert!(!allows(
+            from("192.0.2.1").insert_header((BYPASS_KEY_HEADER, "guess")),
+            "192.0.2.1"
+        ));
+
+        // An empty key must not let requests with an empty header through
+        let req = from("192.0.2.1").insert_header((BYPASS_KEY_HEADER, ""));
+        let ip = "192.0.2.1".parse().unwrap();
+        assert!(!Trusted::new("", Some("")).allows(&req.to_srv_request(), ip));
+    }
+
+    #[actix_web::test]
+    async fn configured_burst_is_allowed_and_zero_values_are_rejected() {
+        assert!(matches!(
+            auth_rate_limiter_with(0, 1),
+            Err(FrameworkError::RateLimitConfig {
+                seconds_per_request: 0,
+                burst_size: 1
+            })
+        ));
+        assert!(auth_rate_limiter_with(60, 0).is_err());
+
+        let app = init_service(
+            App::new()
+                .wrap(auth_rate_limiter_with(60, 2).unwrap())
+                .route("/login", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        for expected in [
+            StatusCode::OK,
+            StatusCode::OK,
+            StatusCode::TOO_MANY_REQUESTS,
+        ] {
+            let res = call_service(&app, from("192.0.2.1").uri("/login").to_request()).await;
+            assert_eq!(res.status(), expected);
+        }
+    }
+
+    #[actix_web::test]
+    async fn other_clients_are_still_limited() {
+        let app = init_service(
+            App::new()
+                .wrap(auth_rate_limiter_with(60, 1).unwrap())
+                .route("/login", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let res = call_service(&app, from("192.0.2.1").uri("/login").to_request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        // Without TRUST_PROXY a forwarded address can't claim to be someone else
+        let req = from("192.0.2.1")
+            .uri("/login")
+            .insert_header(("x-forwarded-for", "10.0.0.1"));
+        let res = call_service(&app, req.to_request()).await;
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(res.headers().get(RETRY_AFTER).unwrap(), "60");
+    }
+
+    #[actix_web::test]
+    async fn sub_second_wait_is_announced_as_one_second() {
+        let config = GovernorConfigBuilder::default()
+            .milliseconds_per_request(400)
+            .burst_size(1)
+            .key_extractor(TrustedKeyExtractor)
+            .finish()
+            .unwrap();
+        let app = init_service(
+            App::new()
+                .wrap(Governor::new(&config))
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let res = call_service(&app, from("192.0.2.1").to_request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let res = call_service(&app, from("192.0.2.1").to_request()).await;
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(res.headers().get(RETRY_AFTER).unwrap(), "1");
+        assert_eq!(res.headers().get("x-ratelimit-after").unwrap(), "1");
+    }
+
+    #[actix_web::test]
+    async fn signed_in_users_are_limited_per_account() {
+        let data = web::Data::new(testing::app_data().await);
+        let config = GovernorConfigBuilder::default()
+            .seconds_per_request(60)
+            .burst_size(1)
+            .key_extractor(ClaimsKeyExtractor)
+            .finish()
+            .unwrap();
+        let app = init_service(
+            App::new()
+                .app_data(data.clone())
+                .wrap(Governor::new(&config))
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let status = |req: TestRequest| {
+            let app = &app;
+            async move { call_service(app, req.to_request()).await.status() }
+        };
+        let user = |sub| testing::signed_in(from("192.0.2.1"), &data, sub, UserRole::User);
+
+        // Users sharing an address each get their own budget
+        assert_eq!(status(user(1)).await, StatusCode::OK);
+        assert_eq!(status(user(1)).await, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(status(user(2)).await, StatusCode::OK);
+
+        // Anonymous requests and invalid tokens are limited by address
+        assert_eq!(status(from("192.0.2.1")).await, StatusCode::OK);
+        let forged = from("192.0.2.1").cookie(Cookie::new(data.cookie.name.clone(), "forged"));
+        assert_eq!(status(forged).await, StatusCode::TOO_MANY_REQUESTS);
+    }
+}
@@ -0,0 +1,140 @@
+use log::warn;
+use sqlx::SqlitePool;
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, PoisonError, RwLock},
+    time::Duration,
+};
+
+/// Key/value settings stored in the `settings` table, served from an in-memory cache.
+///
+/// The application's migrations must create the table:
+///
+/// ```sql
+/// CREATE TABLE settings (key TEXT PRIMARY KEY NOT NULL, value TEXT NOT NULL);
+/// ```
+///
+/// Writes through `set` update the cache immediately; changes made directly in the
+/// database (or by another instance) show up after the next periodic refresh.
+#[derive(Default)]
+pub struct Settings {
+    values: RwLock<BTreeMap<String, String>>,
+}
+
+impl Settings {
+    #[must_use]
+    pub fn get(&self, key: &str) -> Option<String> {
+        self.values
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(key)
+            .cloned()
+    }
+
+    /// All cached settings, ordered by key.
+    #[must_use]
+    pub fn all(&self) -> Vec<(String, String)> {
+        self.values
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect()
+    }
+
+    /// Reload the cache from the database.
+    ///
+    /// # Errors
+    ///
+    /// Returns the database error if the `settings` table can't be read.
+    pub async fn refresh(&self, db: &SqlitePool) -> Result<(), sqlx::Error> {
+        let rows: Vec<(String, String)> = sqlx::query_as("SELECT key, value FROM settings")
+            .fetch_all(db)
+            .await?;
+
+        *self.values.write().unwrap_or_else(PoisonError::into_inner) = rows.into_iter().collect();
+        Ok(())
+    }
+
+    /// Store a setting and update the cache.
+    ///
+    /// # Errors
+    ///
+    /// Returns the database error if the value can't be written.
+    pub async fn set(&self, db: &SqlitePool, key: &str, value: &str) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "INSERT INTO settings (key, value) VALUES (?, ?) \
+             ON CONFLICT(key) DO UPDATE SET value = excluded.value",
+        )
+        .bind(key)
+        .bind(value)
+        .execute(db)
+        .await?;
+
+        self.values
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(key.to_string(), value.to_string());
+        Ok(())
+    }
+}
+
+/// Refresh `settings` from the database every `interval`.
+pub(crate) fn refresh_periodically(settings: Arc<Settings>, db: SqlitePool, interval: Duration) {
+    actix_web::rt::spawn(async move {
+        let mut ticker = actix_web::rt::time::interval(interval);
+        // The first tick completes immediately; the cache was already loaded at startup.
+        ticker.tick().await;
+
+        loop {
+            ticker.tick().await;
+            if let Err(e) = settings.refresh(&db).await {
+                warn!("Failed to refresh settings: {e}");
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    async fn db_with_settings() -> SqlitePool {
+        let db = testing::app_data().await.db;
+        sqlx::query("CREATE TABLE settings (key TEXT PRIMARY KEY NOT NULL, value TEXT NOT NULL)")
+            .execute(&db)
+            .await
+            .unwrap();
+        db
+    }
+
+    #[actix_web::test]
+    async fn set_value_shows_up_elsewhere_after_refresh() {
+        let db = db_with_settings().await;
+        let here = Settings::default();
+        let elsewhere = Settings::default();
+
+        here.set(&db, "registration", "closed").await.unwrap();
+        assert_eq!(here.get("registration").as_deref(), Some("closed"));
+        assert_eq!(elsewhere.get("registration"), None);
+
+        elsewhere.refresh(&db).await.unwrap();
+        assert_eq!(elsewhere.get("registration").as_deref(), Some("closed"));
+    }
+
+    #[actix_web::test]
+    async fn periodic_refresh_picks_up_database_changes() {
+        let db = db_with_settings().await;
+        let settings = Arc::new(Settings::default());
+        refresh_periodically(settings.clone(), db.clone(), Duration::from_millis(20));
+
+        sqlx::query("INSERT INTO settings (key, value) VALUES ('motd', 'hello')")
+            .execute(&db)
+            .await
+            .unwrap();
+        actix_web::rt::time::sleep(Duration::from_millis(100)).await;
+
+        assert_eq!(settings.get("motd").as_deref(), Some("hello"));
+    }
+}
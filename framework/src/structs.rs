@@ -1,5 +1,8 @@
+use crate::AppData;
+use actix_web::{HttpRequest, HttpResponse};
 use chrono::NaiveDateTime;
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 
 #[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, sqlx::Type)]
 #[sqlx(rename_all = "lowercase")]
@@ -9,6 +12,55 @@ pub enum UserRole {
     None,
 }
 
+impl UserRole {
+    /// Human-friendly name for display in templates.
+    #[must_use]
+    pub fn label(&self) -> &'static str {
+        match self {
+            UserRole::Admin => "Administrator",
+            UserRole::User => "User",
+            UserRole::None => "No access",
+        }
+    }
+
+    /// Whether this role may do what `required` may: `Admin` satisfies `User`, which
+    /// satisfies `None`.
+    #[must_use]
+    pub fn satisfies(self, required: UserRole) -> bool {
+        fn rank(role: UserRole) -> u8 {
+            match role {
+                UserRole::None => 0,
+                UserRole::User => 1,
+                UserRole::Admin => 2,
+            }
+        }
+        rank(self) >= rank(required)
+    }
+
+    #[must_use]
+    pub fn all() -> [UserRole; 3] {
+        [UserRole::Admin, UserRole::User, UserRole::None]
+    }
+
+    /// All roles as `{ value, label }` pairs, e.g. for building a `<select>`.
+    #[must_use]
+    pub fn options() -> Vec<RoleOption> {
+        Self::all()
+            .into_iter()
+            .map(|role| RoleOption {
+                value: role.to_string(),
+                label: role.label(),
+            })
+            .collect()
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct RoleOption {
+    pub value: String,
+    pub label: &'static str,
+}
+
 impl std::fmt::Display for UserRole {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -49,8 +101,26 @@ pub struct User {
     pub id: i64,
     pub email: String,
     pub password: String,
+    /// `PASSWORD_VERSION` the password was hashed with; 0 for legacy hashes.
+    pub password_version: i64,
     pub role: UserRole,
+    /// UTC, as written by `CURRENT_TIMESTAMP`; use `AppData::local_time` to display it.
     pub created_at: NaiveDateTime,
+    /// Base32 TOTP secret; when set, login also requires a code (see `verify_totp_code`).
+    #[serde(skip_serializing)]
+    pub totp_secret: Option<String>,
+    /// Time step of the last accepted TOTP code, to reject replays.
+    pub totp_last_step: Option<i64>,
+}
+
+impl User {
+    /// Whether the password just checked with `verify_password_detailed` should be
+    /// rehashed: `verified` reports a hash weaker than the current parameters, or the
+    /// hash predates the current `PASSWORD_VERSION`.
+    #[must_use]
+    pub fn password_outdated(&self, verified: crate::auth::VerifyResult) -> bool {
+        verified.needs_rehash || self.password_version < crate::auth::PASSWORD_VERSION
+    }
 }
 
 #[derive(Serialize)]
@@ -64,8 +134,15 @@ pub struct TableHeader {
 #[derive(Serialize)]
 pub struct TableAction {
     pub label: String,
+    /// URL prefix of the action; the row's `id` is appended when rendered.
     pub action: String,
     pub method: String,
+    /// Confirmation prompt shown before the action is sent.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub confirm: Option<String>,
+    /// Visual style of the action button, e.g. `danger`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub style: Option<String>,
 }
 
 #[derive(Serialize)]
@@ -75,3 +152,160 @@ pub struct Table<T: Serialize> {
     #[serde(skip_serializing_if = "Vec::is_empty")]
     pub actions: Vec<TableAction>,
 }
+
+impl<T: Serialize> Table<T> {
+    /// Answer with the table as JSON when the client prefers `application/json`,
+    /// otherwise render `template` with `headers`, `rows` and `actions` merged into
+    /// `context` (which must be a JSON object, e.g. `json!({"role": "admin"})`).
+    pub async fn respond(
+        &self,
+        req: &HttpRequest,
+        data: &AppData,
+        template: &str,
+        context: Value,
+    ) -> HttpResponse {
+        if crate::prefers_json(req) {
+            return HttpResponse::Ok().json(self);
+        }
+
+        let mut context = match context {
+            Value::Object(map) => map,
+            _ => serde_json::Map::new(),
+        };
+        if let Ok(Value::Object(table)) = serde_json::to_value(self) {
+            context.extend(table);
+        }
+
+        data.render_tpl(template, &context).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use actix_web::{body::to_bytes, http::header, test::TestRequest};
+
+    #[test]
+    fn role_satisfies_itself_and_every_lower_role() {
+        use UserRole::{Admin, None, User};
+
+        for (role, required, satisfied) in [
+            (Admin, Admin, true),
+            (Admin, User, true),
+            (Admin, None, true),
+            (User, Admin, false),
+            (User, User, true),
+            (User, None, true),
+            (None, Admin, false),
+            (None, User, false),
+            (None, None, true),
+        ] {
+            assert_eq!(
+                role.satisfies(required),
+                satisfied,
+                "{role:?} satisfies {required:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn every_role_has_a_label() {
+        assert_eq!(UserRole::Admin.label(), "Administrator");
+        assert_eq!(UserRole::User.label(), "User");
+        assert_eq!(UserRole::None.label(), "No access");
+    }
+
+    #[test]
+    fn all_lists_every_role_once() {
+        let all = UserRole::all();
+        for role in [UserRole::Admin, UserRole::User, UserRole::None] {
+            assert_eq!(all.iter().filter(|r| **r == role).count(), 1);
+        }
+    }
+
+    #[test]
+    fn options_serialize_value_and_label() {
+        let options = serde_json::to_value(UserRole::options()).unwrap();
+        assert_eq!(
+            options,
+            serde_json::json!([
+                {"value": "admin", "label": "Administrator"},
+                {"value": "user", "label": "User"},
+                {"value": "none", "label": "No access"},
+            ])
+        );
+        for option in UserRole::options() {
+            assert_eq!(
+                option.value.parse::<UserRole>().unwrap().label(),
+                option.label
+            );
+        }
+    }
+
+    #[test]
+    fn table_action_serializes_confirm_prompt() {
+        let delete = TableAction {
+            label: "Delete".to_string(),
+            action: "/users".to_string(),
+            method: "DELETE".to_string(),
+            confirm: Some("Delete this user?".to_string()),
+            style: Some("danger".to_string()),
+        };
+        let json = serde_json::to_value(&delete).unwrap();
+        assert_eq!(json["confirm"], "Delete this user?");
+        assert_eq!(json["style"], "danger");
+
+        let plain = TableAction {
+            confirm: None,
+            style: None,
+            ..delete
+        };
+        let plain = serde_json::to_value(&plain).unwrap();
+        assert!(plain.get("confirm").is_none());
+        assert!(plain.get("style").is_none());
+    }
+
+    #[actix_web::test]
+    async fn table_responds_with_html_or_json() {
+        let mut data = testing::app_data().await;
+        data.tera
+            .add_raw_template(
+                "notes",
+                "{{ title }}:{% for row in rows %} {{ row.name }}{% endfor %}",
+            )
+            .unwrap();
+        let table = Table {
+            headers: vec![TableHeader {
+                label: "Name".to_string(),
+                key: "name".to_string(),
+                format: None,
+            }],
+            rows: vec![
+                serde_json::json!({"name": "first"}),
+                serde_json::json!({"name": "second"}),
+            ],
+            actions: Vec::new(),
+        };
+        let context = serde_json::json!({"title": "Notes"});
+
+        let req = TestRequest::default()
+            .insert_header((header::ACCEPT, "text/html"))
+            .to_http_request();
+        let res = table.respond(&req, &data, "notes", context.clone()).await;
+        assert_eq!(
+            to_bytes(res.into_body()).await.unwrap(),
+            "Notes: first second"
+        );
+
+        let req = TestRequest::default()
+            .insert_header((header::ACCEPT, "application/json"))
+            .to_http_request();
+        let res = table.respond(&req, &data, "notes", context).await;
+        let json: Value =
+            serde_json::from_slice(&to_bytes(res.into_body()).await.unwrap()).unwrap();
+        assert_eq!(json["rows"][1]["name"], "second");
+        assert_eq!(json["headers"][0]["label"], "Name");
+        assert!(json.get("title").is_none());
+    }
+}
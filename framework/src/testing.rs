@@ -0,0 +1,164 @@
+//! Helpers for testing handlers and middleware with `actix_web::test`.
+//!
+//! Available to the framework's own tests and, with the `testing` feature, to an app's
+//! tests (enable it on the dev-dependency):
+//!
+//! ```ignore
+//! let data = testing::app_data_with_migrations(&MIGRATIONS_DIR).await;
+//! let app = test::init_service(App::new().app_data(Data::new(data)).service(index)).await;
+//! ```
+
+use crate::{
+    AppData, Env, RuntimeSettings, auth,
+    mail::Mail,
+    migrations::{self, EmbeddedMigrations},
+    settings::Settings,
+    structs::UserRole,
+};
+use actix_web::{
+    cookie::{Cookie, SameSite},
+    test::TestRequest,
+};
+use chrono_tz::Tz;
+use include_dir::Dir;
+use sqlx::{migrate::Migrator, sqlite::SqlitePoolOptions};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, PoisonError, RwLock, atomic::AtomicBool},
+    time::Duration,
+};
+use tokio_cron_scheduler::JobScheduler;
+
+/// `jwt_secret` of `app_data`.
+pub const JWT_SECRET: &str = "test-secret";
+
+/// An `AppData` as `FrameworkApp::run` builds it, with `Env::Prod`, `DOMAIN`
+/// `localhost`, default cookie settings, no templates, no mailer and an empty
+/// in-memory database. Change its fields (e.g. add templates to `tera`) before
+/// wrapping it in `web::Data`.
+///
+/// The pool keeps its one connection open, so the database lives as long as the pool.
+///
+/// # Panics
+///
+/// Panics if the database or the job scheduler can't be created.
+pub async fn app_data() -> AppData {
+    let db = SqlitePoolOptions::new()
+        .max_connections(1)
+        .idle_timeout(None)
+        .max_lifetime(None)
+        .connect("sqlite::memory:")
+        .await
+        .expect("Failed to create in-memory database");
+
+    AppData {
+        tera: crate::new_tera(&crate::TeraExtensions::default()),
+        db,
+        env: Env::Prod,
+        domain: "localhost".to_string(),
+        jwt_secret: JWT_SECRET.to_string(),
+        jwt_previous_secrets: Arc::new(Vec::new()),
+        jwt_leeway: 0,
+        jwt_ttl: auth::DEFAULT_JWT_TTL,
+        scheduler: JobScheduler::new()
+            .await
+            .expect("Failed to create job scheduler"),
+        runtime: Arc::new(RuntimeSettings {
+            maintenance: AtomicBool::new(false),
+            feature_defaults: HashMap::new(),
+            features: RwLock::new(HashMap::new()),
+        }),
+        settings: Arc::new(Settings::default()),
+        timezone: Tz::UTC,
+        migrations: Arc::new(Vec::new()),
+        embed_origins: Arc::new(Vec::new()),
+        trust_proxy: false,
+        base_path: String::new(),
+        cookie: auth::CookieConfig {
+            name: "token".to_string(),
+            same_site: SameSite::Strict,
+            secure: true,
+            domain: None,
+            path: "/".to_string(),
+        },
+        public_paths: Arc::new(Vec::new()),
+        argon2_params: auth::Argon2Params::default(),
+        lockout: auth::LockoutPolicy::default(),
+        global_context: None,
+        mailer: None,
+        tera_extensions: Arc::default(),
+    }
+}
+
+/// `app_data` with the migrations of `dir` (see `FrameworkApp::migrations`) applied.
+///
+/// # Panics
+///
+/// Panics if a migration fails.
+pub async fn app_data_with_migrations(dir: &'static Dir<'static>) -> AppData {
+    let mut data = app_data().await;
+    let migrator = Migrator::new(EmbeddedMigrations(dir))
+        .await
+        .expect("Failed to load migrations");
+    migrations::run(&migrator, &data.db)
+        .await
+        .expect("Failed to run migrations");
+    data.migrations = Arc::new(migrations::versions(&migrator));
+    data
+}
+
+/// Register a mailer on `data` that keeps the mails instead of sending them, and
+/// return where they end up.
+pub fn capture_mail(data: &mut AppData) -> Arc<Mutex<Vec<Mail>>> {
+    let sent = Arc::new(Mutex::new(Vec::new()));
+    let sink = sent.clone();
+    data.mailer = Some(Arc::new(move |mail| {
+        sink.lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(mail);
+        Box::pin(async { Ok(()) })
+    }));
+    sent
+}
+
+/// A session token for user `sub` with `role`, signed with `data.jwt_secret`.
+///
+/// # Panics
+///
+/// Panics if the token can't be encoded.
+#[must_use]
+pub fn token(data: &AppData, sub: i64, role: UserRole) -> String {
+    auth::encode_jwt(sub, role, None, &data.jwt_secret, Duration::from_hours(1))
+        .expect("Failed to encode test token")
+}
+
+/// `req` signed in as user `sub` with `role`, see `token`.
+#[must_use]
+pub fn signed_in(req: TestRequest, data: &AppData, sub: i64, role: UserRole) -> TestRequest {
+    req.cookie(Cookie::new(
+        data.cookie.name.clone(),
+        token(data, sub, role),
+    ))
+}
+
+/// `req` with a CSRF cookie and the matching `X-CSRF-Token` header, so it passes
+/// `CsrfGuard`.
+#[must_use]
+pub fn with_csrf(req: TestRequest, data: &AppData) -> TestRequest {
+    req.cookie(Cookie::new(data.cookie.csrf_name(), "test-csrf"))
+        .insert_header((crate::csrf::CSRF_HEADER, "test-csrf"))
+}
+
+/// The code an authenticator app enrolled with the TOTP `secret` shows right now.
+///
+/// # Panics
+///
+/// Panics if `secret` isn't valid base32.
+#[must_use]
+pub fn totp_code(secret: &str) -> String {
+    let key = auth::decode_base32(secret).expect("Invalid TOTP secret");
+    let step = chrono::Utc::now()
+        .timestamp()
+        .div_euclid(auth::TOTP_STEP_SECONDS);
+    auth::totp_code(&key, step).expect("Failed to compute TOTP code")
+}
@@ -0,0 +1,170 @@
+use crate::{AppData, middleware::MiddlewareNext};
+use actix_web::{
+    Error, FromRequest, HttpMessage, HttpRequest,
+    dev::{Payload, ServiceRequest, ServiceResponse},
+    error::{ErrorInternalServerError, InternalError},
+    web,
+};
+use futures::future::{Ready, ready};
+use log::error;
+use sqlx::{Sqlite, SqliteConnection, SqlitePool, Transaction};
+use std::sync::Arc;
+use tokio::sync::{MappedMutexGuard, Mutex, MutexGuard};
+
+type Slot = Arc<Mutex<Option<Transaction<'static, Sqlite>>>>;
+
+#[derive(Clone)]
+struct TxSlot(Slot);
+
+/// Request-scoped unit of work.
+///
+/// Register with `FrameworkApp::middleware(unit_of_work)` (or on a `scope`). Handlers
+/// then take a `Tx` and run their queries on it. The transaction begins with the first
+/// query and is committed when the response is `2xx`, otherwise rolled back.
+///
+/// # Errors
+///
+/// Returns the inner service's error (after rolling back), or a `500` if the commit fails.
+pub async fn unit_of_work(
+    req: ServiceRequest,
+    next: MiddlewareNext,
+) -> Result<ServiceResponse, Error> {
+    let slot: Slot = Arc::new(Mutex::new(None));
+    req.extensions_mut().insert(TxSlot(slot.clone()));
+
+    let result = next.call(req).await;
+    let Some(tx) = slot.lock().await.take() else {
+        return result;
+    };
+
+    match result {
+        Ok(res) if res.status().is_success() => match tx.commit().await {
+            Ok(()) => Ok(res),
+            Err(e) => {
+                error!("Failed to commit request transaction: {e}");
+                let (req, _) = res.into_parts();
+                Ok(ServiceResponse::from_err(
+                    ErrorInternalServerError("Transaction commit failed"),
+                    req,
+                ))
+            }
+        },
+        result => {
+            if let Err(e) = tx.rollback().await {
+                error!("Failed to roll back request transaction: {e}");
+            }
+            result
+        }
+    }
+}
+
+/// The request's transaction, see `unit_of_work`.
+pub struct Tx {
+    slot: Slot,
+    pool: SqlitePool,
+}
+
+impl Tx {
+    /// Lock the transaction for running queries, beginning it if necessary.
+    ///
+    /// ```ignore
+    /// let mut conn = tx.conn().await?;
+    /// sqlx::query("...").execute(&mut *conn).await?;
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Returns the database error if the transaction can't be started.
+    pub async fn conn(&self) -> Result<MappedMutexGuard<'_, SqliteConnection>, sqlx::Error> {
+        let mut guard = self.slot.lock().await;
+        if guard.is_none() {
+            *guard = Some(self.pool.begin().await?);
+        }
+
+        Ok(MutexGuard::map(guard, |tx| match tx {
+            Some(tx) => &mut **tx,
+            None => unreachable!("transaction was just started"),
+        }))
+    }
+}
+
+impl FromRequest for Tx {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let slot = req.extensions().get::<TxSlot>().cloned();
+        let pool = req.app_data::<web::Data<AppData>>().map(|d| d.db.clone());
+
+        ready(match (slot, pool) {
+            (Some(TxSlot(slot)), Some(pool)) => Ok(Tx { slot, pool }),
+            _ => Err(InternalError::new(
+                "Tx used without the unit_of_work middleware",
+                actix_web::http::StatusCode::INTERNAL_SERVER_ERROR,
+            )
+            .into()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MiddlewareFn, error::AppError, middleware::Middlewares, testing};
+    use actix_web::{
+        App, HttpResponse,
+        http::StatusCode,
+        test::{TestRequest, call_service, init_service},
+    };
+
+    async fn insert(tx: &Tx) -> Result<(), AppError> {
+        sqlx::query("INSERT INTO notes (title) VALUES ('draft')")
+            .execute(&mut *tx.conn().await?)
+            .await?;
+        Ok(())
+    }
+
+    #[actix_web::test]
+    async fn failing_handler_rolls_back_its_writes() {
+        let data = testing::app_data().await;
+        sqlx::query("CREATE TABLE notes (id INTEGER PRIMARY KEY, title TEXT)")
+            .execute(&data.db)
+            .await
+            .unwrap();
+        let data = web::Data::new(data);
+        let unit_of_work: Vec<MiddlewareFn> =
+            vec![Box::new(|req, next| Box::pin(unit_of_work(req, next)))];
+        let app = init_service(
+            App::new()
+                .app_data(data.clone())
+                .wrap(Middlewares(Arc::new(unit_of_work)))
+                .route(
+                    "/ok",
+                    web::post().to(|tx: Tx| async move {
+                        insert(&tx).await?;
+                        Ok::<_, AppError>(HttpResponse::Created().finish())
+                    }),
+                )
+                .route(
+                    "/fail",
+                    web::post().to(|tx: Tx| async move {
+                        insert(&tx).await?;
+                        insert(&tx).await?;
+                        Err::<HttpResponse, _>(AppError::User("rejected".to_string()))
+                    }),
+                ),
+        )
+        .await;
+
+        let res = call_service(&app, TestRequest::post().uri("/fail").to_request()).await;
+        assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let res = call_service(&app, TestRequest::post().uri("/ok").to_request()).await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+
+        let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM notes")
+            .fetch_one(&data.db)
+            .await
+            .unwrap();
+        assert_eq!(count, 1);
+    }
+}
@@ -0,0 +1,338 @@
+use crate::error::{AppError, FrameworkError};
+use actix_web::{
+    Error, FromRequest, HttpRequest, HttpResponse, dev::Payload, error::InternalError, web,
+};
+use futures::future::LocalBoxFuture;
+use serde::{Serialize, de::DeserializeOwned};
+use serde_json::Value;
+use std::{collections::BTreeMap, sync::Arc};
+use validator::{Validate, ValidationErrors};
+
+/// Field name used for errors that don't belong to a single field, e.g. a malformed body.
+pub const BODY_FIELD: &str = "_body";
+
+/// Per-field error messages, keyed by the JSON field name.
+pub type FieldErrors = BTreeMap<String, Vec<String>>;
+
+/// Body of the `422 Unprocessable Entity` response returned by `ValidatedJson`.
+#[derive(Serialize, Debug)]
+pub struct ValidationErrorBody {
+    pub status: u16,
+    pub code: &'static str,
+    pub error: &'static str,
+    pub fields: FieldErrors,
+    /// For `SchemaJson`: the schema keywords each field failed, e.g.
+    /// `/properties/amount/minimum`.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub schema_paths: FieldErrors,
+}
+
+/// JSON body extractor that runs the `validator` rules of `T` after deserializing.
+///
+/// Both deserialization and validation failures answer with a `422` listing the
+/// messages per field instead of serde's positional error text.
+pub struct ValidatedJson<T>(pub T);
+
+impl<T> ValidatedJson<T> {
+    #[must_use]
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> std::ops::Deref for ValidatedJson<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T: DeserializeOwned + Validate + 'static> FromRequest for ValidatedJson<T> {
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let json = web::Json::<Value>::from_request(req, payload);
+
+        Box::pin(async move {
+            let value = json.await.map_err(|e| {
+                let mut fields = FieldErrors::new();
+                fields.insert(BODY_FIELD.to_string(), vec![e.to_string()]);
+                unprocessable(fields)
+            })?;
+
+            let inner: T = serde_path_to_error::deserialize(value.into_inner())
+                .map_err(|e| unprocessable(deserialize_errors(&e)))?;
+
+            inner
+                .validate()
+                .map_err(|e| unprocessable(validation_errors(&e)))?;
+
+            Ok(ValidatedJson(inner))
+        })
+    }
+}
+
+/// A compiled JSON Schema for the bodies of one route, read by `SchemaJson`.
+/// Register it as app data of the route or scope:
+///
+/// ```ignore
+/// let schema = JsonSchema::new(&json!({
+///     "type": "object",
+///     "required": ["event"],
+///     "properties": { "event": { "type": "string" } }
+/// }))?;
+/// cfg.service(web::resource("/webhook").app_data(schema).route(web::post().to(webhook)));
+/// ```
+///
+/// `$ref`s to other documents aren't fetched; keep schemas self-contained.
+#[derive(Clone)]
+pub struct JsonSchema(Arc<jsonschema::Validator>);
+
+impl JsonSchema {
+    /// Compile `schema`; its `$schema` picks the draft, 2020-12 by default.
+    ///
+    /// # Errors
+    ///
+    /// `FrameworkError::JsonSchema` if `schema` isn't a valid schema.
+    pub fn new(schema: &Value) -> Result<Self, FrameworkError> {
+        jsonschema::validator_for(schema)
+            .map(|validator| Self(Arc::new(validator)))
+            .map_err(|e| FrameworkError::JsonSchema(e.to_string()))
+    }
+}
+
+/// JSON body extractor that checks the body against the route's `JsonSchema` before
+/// deserializing it into `T`, for external payloads (webhooks, API clients) whose
+/// shape is easier to state as a schema than as `validator` rules. `T` defaults to
+/// the raw `serde_json::Value`.
+///
+/// Failures answer with a `422` like `ValidatedJson`, keyed by the JSON pointer of
+/// the offending value (`/items/0/price`, `_body` for the whole body) and with the
+/// failing schema paths under `schema_paths`. Without a registered schema the
+/// request fails with a 500, as that's a bug in the route setup.
+pub struct SchemaJson<T = Value>(pub T);
+
+impl<T> SchemaJson<T> {
+    #[must_use]
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> std::ops::Deref for SchemaJson<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T: DeserializeOwned + 'static> FromRequest for SchemaJson<T> {
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let schema = req.app_data::<JsonSchema>().cloned();
+        let path = req.path().to_string();
+        let json = web::Json::<Value>::from_request(req, payload);
+
+        Box::pin(async move {
+            let schema = schema.ok_or_else(|| {
+                AppError::Internal(format!("No JsonSchema registered for {path}"))
+            })?;
+            let value = json.await.map_err(|e| {
+                let mut fields = FieldErrors::new();
+                fields.insert(BODY_FIELD.to_string(), vec![e.to_string()]);
+                unprocessable(fields)
+            })?;
+
+            let (fields, schema_paths) = schema_errors(&schema, &value);
+            if !fields.is_empty() {
+                return Err(unprocessable_with(fields, schema_paths));
+            }
+
+            let inner: T = serde_path_to_error::deserialize(value.into_inner())
+                .map_err(|e| unprocessable(deserialize_errors(&e)))?;
+
+            Ok(SchemaJson(inner))
+        })
+    }
+}
+
+/// Messages and failing schema paths per instance path.
+fn schema_errors(schema: &JsonSchema, value: &Value) -> (FieldErrors, FieldErrors) {
+    let mut fields = FieldErrors::new();
+    let mut schema_paths = FieldErrors::new();
+    for error in schema.0.iter_errors(value) {
+        let field = match error.instance_path().to_string() {
+            path if path.is_empty() => BODY_FIELD.to_string(),
+            path => path,
+        };
+        fields
+            .entry(field.clone())
+            .or_default()
+            .push(error.to_string());
+        schema_paths
+            .entry(field)
+            .or_default()
+            .push(error.schema_path().to_string());
+    }
+    (fields, schema_paths)
+}
+
+fn unprocessable(fields: FieldErrors) -> Error {
+    unprocessable_with(fields, FieldErrors::new())
+}
+
+fn unprocessable_with(fields: FieldErrors, schema_paths: FieldErrors) -> Error {
+    let res = HttpResponse::UnprocessableEntity().json(ValidationErrorBody {
+        status: 422,
+        code: "VALIDATION_FAILED",
+        error: "Validation failed",
+        fields,
+        schema_paths,
+    });
+    InternalError::from_response("Validation failed", res).into()
+}
+
+fn deserialize_errors(err: &serde_path_to_error::Error<serde_json::Error>) -> FieldErrors {
+    let message = err.inner().to_string();
+    let path = err.path().to_string();
+
+    // serde reports a missing field against its parent, so pull the name out of
+    // the "missing field `name`" message.
+    let missing = message
+        .strip_prefix("missing field `")
+        .and_then(|rest| rest.split_once('`'))
+        .map(|(name, _)| name);
+
+    let field = match (missing, path.as_str()) {
+        (Some(name), ".") => name.to_string(),
+        (Some(name), parent) => format!("{parent}.{name}"),
+        (None, ".") => BODY_FIELD.to_string(),
+        (None, path) => path.to_string(),
+    };
+
+    let mut fields = FieldErrors::new();
+    fields.insert(field, vec![message]);
+    fields
+}
+
+fn validation_errors(errors: &ValidationErrors) -> FieldErrors {
+    errors
+        .field_errors()
+        .into_iter()
+        .map(|(field, errs)| {
+            let messages = errs
+                .iter()
+                .map(|e| match &e.message {
+                    Some(message) => message.to_string(),
+                    None => format!("{field} is invalid ({})", e.code),
+                })
+                .collect();
+            (field.to_string(), messages)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{
+        App,
+        test::{TestRequest, call_service, init_service, read_body_json},
+    };
+    use serde::Deserialize;
+    use serde_json::json;
+
+    #[derive(Deserialize, Validate)]
+    struct Signup {
+        #[validate(email(message = "must be an email address"))]
+        email: String,
+        #[validate(range(min = 18))]
+        age: u32,
+    }
+
+    /// The `fields` of the `422` a POST of `body` to a `ValidatedJson<Signup>` route gets.
+    async fn field_errors(body: Value) -> Value {
+        let app = init_service(App::new().route(
+            "/",
+            web::post().to(|_: ValidatedJson<Signup>| async { HttpResponse::Ok().finish() }),
+        ))
+        .await;
+        let req = TestRequest::post().uri("/").set_json(body).to_request();
+        let res = call_service(&app, req).await;
+        assert_eq!(res.status(), 422);
+
+        let body: Value = read_body_json(res).await;
+        assert_eq!(body["code"], "VALIDATION_FAILED");
+        body["fields"].clone()
+    }
+
+    #[actix_web::test]
+    async fn missing_field_is_reported_by_name() {
+        let fields = field_errors(json!({"email": "ada@example.com"})).await;
+
+        assert_eq!(fields, json!({"age": ["missing field `age`"]}));
+    }
+
+    #[actix_web::test]
+    async fn failed_rules_are_reported_per_field() {
+        let fields = field_errors(json!({"email": "not an email", "age": 12})).await;
+
+        assert_eq!(fields["email"], json!(["must be an email address"]));
+        assert_eq!(fields["age"], json!(["age is invalid (range)"]));
+    }
+
+    #[derive(Deserialize)]
+    struct Payment {
+        amount: u32,
+    }
+
+    #[actix_web::test]
+    async fn schema_json_checks_the_body_against_the_route_schema() {
+        let schema = JsonSchema::new(&json!({
+            "type": "object",
+            "required": ["amount"],
+            "properties": { "amount": { "type": "integer", "minimum": 1 } }
+        }))
+        .unwrap();
+        let app = init_service(App::new().app_data(schema).route(
+            "/",
+            web::post().to(|payment: SchemaJson<Payment>| async move {
+                HttpResponse::Ok().body(payment.amount.to_string())
+            }),
+        ))
+        .await;
+        let post = |body: Value| TestRequest::post().uri("/").set_json(body).to_request();
+
+        let res = call_service(&app, post(json!({"amount": 5}))).await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(actix_web::test::read_body(res).await, "5");
+
+        let res = call_service(&app, post(json!({"amount": 0}))).await;
+        assert_eq!(res.status(), 422);
+        let body: Value = read_body_json(res).await;
+        assert_eq!(body["code"], "VALIDATION_FAILED");
+        assert_eq!(body["fields"]["/amount"].as_array().unwrap().len(), 1);
+        assert_eq!(
+            body["schema_paths"],
+            json!({"/amount": ["/properties/amount/minimum"]})
+        );
+
+        let res = call_service(&app, post(json!({}))).await;
+        assert_eq!(res.status(), 422);
+        let body: Value = read_body_json(res).await;
+        assert_eq!(body["schema_paths"], json!({"_body": ["/required"]}));
+    }
+
+    #[test]
+    fn invalid_schema_is_a_config_error() {
+        assert!(matches!(
+            JsonSchema::new(&json!({"type": "no-such-type"})),
+            Err(FrameworkError::JsonSchema(_))
+        ));
+    }
+}
@@ -0,0 +1,4 @@
+ALTER TABLE users ADD COLUMN password_version INTEGER NOT NULL DEFAULT 0;
+
+-- Hashes written before this column existed are argon2 with the version 1 parameters
+UPDATE users SET password_version = 1 WHERE password LIKE '$argon2%';
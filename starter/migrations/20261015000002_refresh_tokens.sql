@@ -0,0 +1,6 @@
+CREATE TABLE
+    refresh_tokens (
+        token_hash TEXT PRIMARY KEY NOT NULL,
+        user_id INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+        expires_at TIMESTAMP NOT NULL
+    );
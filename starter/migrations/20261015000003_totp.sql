@@ -0,0 +1,4 @@
+-- Optional second factor: the base32 TOTP secret (NULL = password only) and the time
+-- step of the last accepted code, so a code can't be used twice
+ALTER TABLE users ADD COLUMN totp_secret TEXT;
+ALTER TABLE users ADD COLUMN totp_last_step INTEGER;
@@ -0,0 +1,7 @@
+-- Failed logins per (lowercased) email, for locking accounts under brute force
+CREATE TABLE
+    login_attempts (
+        email TEXT PRIMARY KEY NOT NULL,
+        failures INTEGER NOT NULL DEFAULT 0,
+        locked_until TIMESTAMP
+    );
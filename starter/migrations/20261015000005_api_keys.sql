@@ -0,0 +1,7 @@
+CREATE TABLE
+    api_keys (
+        key_hash TEXT PRIMARY KEY NOT NULL,
+        role TEXT NOT NULL,
+        revoked BOOLEAN NOT NULL DEFAULT FALSE,
+        created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+    );
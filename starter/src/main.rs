@@ -6,13 +6,11 @@ pub use full_stack_engine::prelude::*;
 mod cronjobs;
 mod services;
 
-static DIST_DIR: Dir<'_> = include_dir!("$CARGO_MANIFEST_DIR/src/frontend/dist");
+static MIGRATIONS_DIR: Dir<'_> = include_dir!("$CARGO_MANIFEST_DIR/migrations");
 
-#[main]
-async fn main() -> std::io::Result<()> {
-    FrameworkApp::new(&DIST_DIR)
+framework_main!(|app| {
+    app.migrations(&MIGRATIONS_DIR)
         .configure(services::configure)
+        .public_paths(&["/login", "/register", "/forgot", "/reset"])
         .cronjobs(cronjobs::add_cronjobs)
-        .run()
-        .await
-}
+});
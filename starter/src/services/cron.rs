@@ -0,0 +1,59 @@
+use crate::{AdminUser, AppResult, Ctx, Serialize, Table, TableHeader, actix_web::get};
+use full_stack_engine::cron::jobs;
+
+#[derive(Serialize)]
+struct Row {
+    pub name: String,
+    pub schedule: String,
+    pub next_run: String,
+}
+
+#[get("/cron")]
+pub async fn get(ctx: Ctx<AdminUser>) -> AppResult {
+    let rows: Vec<Row> = jobs(&ctx.data.scheduler)
+        .await
+        .into_iter()
+        .map(|job| Row {
+            name: job.name,
+            schedule: job.schedule,
+            next_run: job
+                .next_run
+                .map_or_else(|| "-".to_string(), |t| ctx.data.local_time(&t.naive_utc())),
+        })
+        .collect();
+
+    let table = Table {
+        headers: vec![
+            TableHeader {
+                label: "Name".to_string(),
+                key: "name".to_string(),
+                format: None,
+            },
+            TableHeader {
+                label: "Schedule".to_string(),
+                key: "schedule".to_string(),
+                format: None,
+            },
+            TableHeader {
+                label: "Next run".to_string(),
+                key: "next_run".to_string(),
+                format: None,
+            },
+        ],
+        rows,
+        actions: vec![],
+    };
+
+    Ok(ctx
+        .data
+        .render_tpl(
+            "cron",
+            &crate::json!({
+                "headers": table.headers,
+                "rows": table.rows,
+                "actions": table.actions,
+                "role": "admin"
+            }),
+        )
+        .await)
+}
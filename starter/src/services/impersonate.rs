@@ -0,0 +1,175 @@
+use crate::{
+    AdminAction, AppData, AppError, AppResult, AuthUser, ClientIp, CsrfGuard, HttpRequest, User,
+    UserRole, actix_web::post, audit, create_impersonation_jwt, create_jwt_with_ttl,
+    http::StatusCode, redirect, token_cookie, web,
+};
+
+/// Sign the admin in as user `id` to see the app as they do. The admin's refresh
+/// token stays, so the admin session comes back once the impersonation token runs out.
+#[post("/users/{id}/impersonate")]
+pub async fn start(
+    req: HttpRequest,
+    data: web::Data<AppData>,
+    admin: AdminAction,
+    path: web::Path<i64>,
+) -> AppResult {
+    if admin.claims.impersonator.is_some() {
+        return Ok(data
+            .render_error(StatusCode::CONFLICT, "Stop impersonating first", &req)
+            .await);
+    }
+
+    let user_id = path.into_inner();
+    let user = sqlx::query_as!(User, "SELECT * FROM users WHERE id = ?", user_id)
+        .fetch_optional(&data.db)
+        .await?
+        .ok_or_else(|| AppError::NotFound("User not found".into()))?;
+    if user.id == admin.claims.sub {
+        return Ok(data
+            .render_error(
+                StatusCode::BAD_REQUEST,
+                "You can't impersonate yourself",
+                &req,
+            )
+            .await);
+    }
+
+    audit::record(
+        &data.db,
+        admin.claims.sub,
+        "impersonate_start",
+        Some(user.id),
+        ClientIp::of(&req),
+    )
+    .await?;
+
+    let jwt = create_impersonation_jwt(&user, admin.claims.sub, &data.jwt_secret, data.jwt_ttl)
+        .map_err(|e| AppError::Internal(format!("JWT creation error: {e}")))?;
+    let cookie = token_cookie(&req, &data, jwt);
+    redirect!("/", cookie)
+}
+
+/// End an impersonation and return to the admin's own session.
+#[post("/impersonate/stop")]
+pub async fn stop(
+    req: HttpRequest,
+    data: web::Data<AppData>,
+    user: AuthUser,
+    _csrf: CsrfGuard,
+) -> AppResult {
+    let Some(admin_id) = user.claims.impersonator else {
+        return Ok(data
+            .render_error(StatusCode::BAD_REQUEST, "Not impersonating anyone", &req)
+            .await);
+    };
+
+    let admin = sqlx::query_as!(User, "SELECT * FROM users WHERE id = ?", admin_id)
+        .fetch_optional(&data.db)
+        .await?
+        .filter(|admin| admin.role.satisfies(UserRole::Admin))
+        .ok_or_else(|| AppError::Auth("Account disabled".into()))?;
+
+    audit::record(
+        &data.db,
+        admin.id,
+        "impersonate_stop",
+        Some(user.claims.sub),
+        ClientIp::of(&req),
+    )
+    .await?;
+
+    let jwt = create_jwt_with_ttl(&admin, &data.jwt_secret, data.jwt_ttl)
+        .map_err(|e| AppError::Internal(format!("JWT creation error: {e}")))?;
+    let cookie = token_cookie(&req, &data, jwt);
+    redirect!(format!("/users/{}", user.claims.sub), cookie)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        PASSWORD_VERSION,
+        actix_web::{
+            App,
+            cookie::Cookie,
+            dev::ServiceResponse,
+            http::header::LOCATION,
+            test::{TestRequest, call_service, init_service},
+        },
+    };
+    use full_stack_engine::testing;
+
+    /// Admin 1 and user 2 in the database.
+    async fn data_with_users() -> web::Data<AppData> {
+        let data = testing::app_data_with_migrations(&crate::MIGRATIONS_DIR).await;
+        for (email, role) in [
+            ("admin@example.com", UserRole::Admin),
+            ("ada@example.com", UserRole::User),
+        ] {
+            sqlx::query!(
+                "INSERT INTO users (email, password, password_version, role) VALUES (?, ?, ?, ?)",
+                email,
+                "unused",
+                PASSWORD_VERSION,
+                role
+            )
+            .execute(&data.db)
+            .await
+            .unwrap();
+        }
+        web::Data::new(data)
+    }
+
+    fn session_token(res: &ServiceResponse, data: &AppData) -> String {
+        res.response()
+            .cookies()
+            .find(|cookie| cookie.name() == data.cookie.name)
+            .unwrap()
+            .value()
+            .to_string()
+    }
+
+    #[actix_web::test]
+    async fn impersonation_is_recorded_and_reversible() {
+        let data = data_with_users().await;
+        let app = init_service(
+            App::new()
+                .app_data(data.clone())
+                .service(start)
+                .service(stop),
+        )
+        .await;
+
+        let req = TestRequest::post().uri("/users/2/impersonate");
+        let req = testing::signed_in(req, &data, 1, UserRole::Admin);
+        let res = call_service(&app, testing::with_csrf(req, &data).to_request()).await;
+        assert_eq!(res.status(), StatusCode::SEE_OTHER);
+        assert_eq!(res.headers().get(LOCATION).unwrap(), "/");
+        let token = session_token(&res, &data);
+        let claims = data.verify_jwt(&token).unwrap();
+        assert_eq!((claims.sub, claims.impersonator), (2, Some(1)));
+
+        let req = TestRequest::post()
+            .uri("/impersonate/stop")
+            .cookie(Cookie::new(data.cookie.name.clone(), token));
+        let res = call_service(&app, testing::with_csrf(req, &data).to_request()).await;
+        assert_eq!(res.status(), StatusCode::SEE_OTHER);
+        assert_eq!(res.headers().get(LOCATION).unwrap(), "/users/2");
+        let claims = data.verify_jwt(&session_token(&res, &data)).unwrap();
+        assert_eq!((claims.sub, claims.role), (1, UserRole::Admin));
+        assert_eq!(claims.impersonator, None);
+
+        let entries: Vec<(i64, String, Option<i64>)> =
+            sqlx::query_as("SELECT actor_id, action, target_id FROM audit_log ORDER BY id")
+                .fetch_all(&data.db)
+                .await
+                .unwrap();
+        assert_eq!(
+            entries,
+            [
+                (1, "impersonate_start".to_string(), Some(2)),
+                (1, "impersonate_stop".to_string(), Some(2)),
+            ]
+        );
+    }
+}
@@ -1,7 +1,130 @@
-use crate::{AppData, AuthUser, Data, Responder, get, json};
+use crate::{AppData, AppResult, AuthUser, Data, Serialize, UserRole, get, json, render_tpl};
+use std::collections::BTreeMap;
+
+/// Quick numbers for the admin dashboard.
+#[derive(Serialize)]
+struct Stats {
+    total_users: i64,
+    users_by_role: BTreeMap<String, i64>,
+    /// Sign-ups in the last 7 days.
+    recent_registrations: i64,
+    newest_users: Vec<NewUser>,
+    db_pool: PoolUsage,
+}
+
+#[derive(Serialize)]
+struct NewUser {
+    email: String,
+    created_at: String,
+}
+
+#[derive(Serialize)]
+struct PoolUsage {
+    connections: u32,
+    idle: usize,
+    max: u32,
+}
 
 #[get("/")]
-pub async fn index(data: Data<AppData>, user: AuthUser) -> impl Responder {
-    data.render_tpl("index", &json!({ "role": user.claims.role.to_string() }))
-        .await
+pub async fn index(data: Data<AppData>, user: AuthUser) -> AppResult {
+    let stats = if user.claims.role.satisfies(UserRole::Admin) {
+        Some(stats(&data).await?)
+    } else {
+        None
+    };
+
+    render_tpl!(
+        data,
+        "index",
+        &json!({
+            "role": user.claims.role.to_string(),
+            "stats": stats,
+        })
+    )
+}
+
+/// Counts come from one grouped query, so the dashboard stays cheap with many users.
+async fn stats(data: &AppData) -> AppResult<Stats> {
+    let roles = sqlx::query!(
+        r#"SELECT role, COUNT(*) AS "total!: i64",
+               SUM(created_at >= datetime('now', '-7 days')) AS "recent!: i64"
+           FROM users GROUP BY role"#
+    )
+    .fetch_all(&data.db)
+    .await?;
+
+    let newest_users =
+        sqlx::query!("SELECT email, created_at FROM users ORDER BY created_at DESC LIMIT 5")
+            .fetch_all(&data.db)
+            .await?
+            .into_iter()
+            .map(|row| NewUser {
+                email: row.email,
+                created_at: data.local_time(&row.created_at),
+            })
+            .collect();
+
+    Ok(Stats {
+        total_users: roles.iter().map(|row| row.total).sum(),
+        recent_registrations: roles.iter().map(|row| row.recent).sum(),
+        users_by_role: roles.into_iter().map(|row| (row.role, row.total)).collect(),
+        newest_users,
+        db_pool: PoolUsage {
+            connections: data.db.size(),
+            idle: data.db.num_idle(),
+            max: data.db.options().get_max_connections(),
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        PASSWORD_VERSION,
+        actix_web::{
+            App,
+            test::{TestRequest, call_service, init_service, read_body},
+        },
+    };
+    use full_stack_engine::testing;
+
+    #[actix_web::test]
+    async fn admins_get_user_counts_in_the_context() {
+        let mut data = testing::app_data_with_migrations(&crate::MIGRATIONS_DIR).await;
+        data.tera
+            .add_raw_template(
+                "index",
+                "{% if stats %}{{ stats.total_users }} {{ stats.users_by_role.user }} \
+                 {{ stats.users_by_role.admin }} {{ stats.recent_registrations }} \
+                 {{ stats.newest_users | length }}{% else %}{{ role }}{% endif %}",
+            )
+            .unwrap();
+        for (email, role) in [
+            ("admin@example.com", UserRole::Admin),
+            ("ada@example.com", UserRole::User),
+            ("grace@example.com", UserRole::User),
+        ] {
+            sqlx::query!(
+                "INSERT INTO users (email, password, password_version, role) VALUES (?, ?, ?, ?)",
+                email,
+                "unused",
+                PASSWORD_VERSION,
+                role
+            )
+            .execute(&data.db)
+            .await
+            .unwrap();
+        }
+        let data = Data::new(data);
+        let app = init_service(App::new().app_data(data.clone()).service(index)).await;
+
+        let req = testing::signed_in(TestRequest::get().uri("/"), &data, 1, UserRole::Admin);
+        let res = call_service(&app, req.to_request()).await;
+        assert_eq!(read_body(res).await, "3 2 1 3 3");
+
+        let req = testing::signed_in(TestRequest::get().uri("/"), &data, 2, UserRole::User);
+        let res = call_service(&app, req.to_request()).await;
+        assert_eq!(read_body(res).await, "user");
+    }
 }
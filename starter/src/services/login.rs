@@ -1,24 +1,65 @@
 use crate::{
-    AppData, AppError, AppResult, Data, Deserialize, Env, Form, HttpResponse, LOCATION, Responder,
-    User, cookie::Cookie, cookie::time::Duration, create_jwt, get, hash_password, json,
-    verify_password,
+    AppData, AppError, AppResult, CsrfGuard, Data, Deserialize, Form, FormNonces, HttpRequest,
+    NonceStore, PASSWORD_VERSION, REFRESH_TOKEN_TTL, Responder, User, VerifyResult, cookie::Cookie,
+    create_jwt_with_ttl, create_refresh_token, error, get, hash_password_async, json, login_locked,
+    record_login_failure, redirect, render_tpl, reset_login_failures, session_cookie, token_cookie,
+    verify_password_detailed_async, verify_totp_code, warn,
 };
-use std::sync::OnceLock;
+use std::sync::{LazyLock, OnceLock};
 
 static DUMMY_HASH: OnceLock<String> = OnceLock::new();
 
+// Each rendered login form carries a single-use nonce, so a double-clicked or
+// replayed submit is rejected
+static NONCES: LazyLock<FormNonces> =
+    LazyLock::new(|| FormNonces::new(std::time::Duration::from_mins(15)));
+
+// Logins that passed the password check and wait for the TOTP code, by a single-use
+// token carried in the code form
+static PENDING_TOTP: LazyLock<NonceStore<i64>> =
+    LazyLock::new(|| NonceStore::new(std::time::Duration::from_mins(5)));
+
 #[derive(Deserialize)]
 pub struct FormData {
     email: String,
     password: String,
+    #[serde(default)]
+    nonce: String,
+}
+
+#[derive(Deserialize)]
+pub struct TotpFormData {
+    pending: String,
+    code: String,
 }
 
 #[get("/login")]
 pub async fn get(data: Data<AppData>) -> impl Responder {
-    data.render("login").await
+    data.render_tpl("login", &json!({"nonce": NONCES.issue()}))
+        .await
 }
 
-pub async fn post(data: Data<AppData>, form: Form<FormData>) -> AppResult {
+pub async fn post(
+    req: HttpRequest,
+    data: Data<AppData>,
+    form: Form<FormData>,
+    _csrf: CsrfGuard,
+) -> AppResult {
+    if !NONCES.consume(&form.nonce) {
+        return render_tpl!(
+            data,
+            "login",
+            &json!({
+                "error": "Formular abgelaufen oder bereits gesendet, bitte erneut versuchen",
+                "nonce": NONCES.issue()
+            })
+        );
+    }
+
+    // Checked first but answered like a wrong password after the same hashing work, so
+    // neither the message nor the timing tells that the account is locked
+    let locked = login_locked(&data.db, &form.email).await?;
+
     let user_res = sqlx::query_as!(User, "SELECT * FROM users WHERE email = $1", form.email)
         .fetch_one(&data.db)
         .await;
@@ -30,50 +71,427 @@ pub async fn post(data: Data<AppData>, form: Form<FormData>) -> AppResult {
     };
 
     // Use user password or a dummy hash to keep timing consistent (resist timing attacks)
-    let dummy_hash = DUMMY_HASH.get_or_init(|| {
-        hash_password("dummy_password_for_timing_safety").unwrap_or_else(|_| {
-            // Failsafe in case hashing fails
-            "$argon2id$v=19$m=4096,t=3,p=1$c29tZXNhbHQ$i6PrS9n+AdfNf/U7/lH1XQ".to_string()
-        })
-    });
+    let dummy_hash = if let Some(hash) = DUMMY_HASH.get() {
+        hash
+    } else {
+        let hash = hash_password_async("dummy_password_for_timing_safety", data.argon2_params)
+            .await
+            .unwrap_or_else(|_| {
+                // Failsafe in case hashing fails
+                "$argon2id$v=19$m=4096,t=3,p=1$c29tZXNhbHQ$i6PrS9n+AdfNf/U7/lH1XQ".to_string()
+            });
+        DUMMY_HASH.get_or_init(|| hash)
+    };
     let hash = user.as_ref().map_or(dummy_hash.as_str(), |u| &u.password);
 
-    let password_ok = verify_password(&form.password, hash);
+    let verified =
+        match verify_password_detailed_async(&form.password, hash, data.argon2_params).await {
+            Ok(result) => result,
+            Err(e) => {
+                if let Some(user) = &user {
+                    error!(
+                        "Password hash of user {} could not be verified: {e}",
+                        user.id
+                    );
+                }
+                VerifyResult {
+                    ok: false,
+                    needs_rehash: false,
+                }
+            }
+        };
 
-    if !user_exists
-        || !password_ok
+    if locked
+        || !user_exists
+        || !verified.ok
         || user
             .as_ref()
             .map_or(true, |u| u.role == crate::UserRole::None)
     {
-        return Ok(data
-            .render_tpl("login", &json!({"error": "Falsche Daten"}))
-            .await);
+        // Only existing accounts are counted, so guessed emails don't pile up rows
+        if !locked && user_exists {
+            record_login_failure(&data.db, &form.email, data.lockout).await?;
+        }
+        return render_tpl!(
+            data,
+            "login",
+            &json!({"error": "Falsche Daten", "nonce": NONCES.issue()})
+        );
     }
 
     let user = match user {
         Some(u) => u,
         None => {
-            return Ok(data
-                .render_tpl("login", &json!({"error": "Falsche Daten"}))
-                .await);
+            return render_tpl!(
+                data,
+                "login",
+                &json!({"error": "Falsche Daten", "nonce": NONCES.issue()})
+            );
         }
     };
 
-    let jwt = create_jwt(user, &data.jwt_secret)
-        .map_err(|e| AppError::Internal(format!("JWT creation error: {}", e)))?;
-
-    let cookie = Cookie::build("token", jwt)
-        .domain(&data.domain)
-        .path("/")
-        .same_site(actix_web::cookie::SameSite::Strict)
-        .secure(data.env != Env::Dev)
-        .max_age(Duration::hours(1))
-        .http_only(true)
-        .finish();
-
-    Ok(HttpResponse::SeeOther()
-        .append_header((LOCATION, "/"))
-        .cookie(cookie)
-        .finish())
+    // Legacy (e.g. bcrypt) and outdated argon2 hashes are replaced on login
+    if user.password_outdated(verified) {
+        match hash_password_async(&form.password, data.argon2_params).await {
+            Ok(hash) => {
+                sqlx::query!(
+                    "UPDATE users SET password = $1, password_version = $2 WHERE id = $3",
+                    hash,
+                    PASSWORD_VERSION,
+                    user.id
+                )
+                .execute(&data.db)
+                .await?;
+            }
+            Err(e) => warn!("Could not upgrade password hash of user {}: {e}", user.id),
+        }
+    }
+
+    if user.totp_secret.is_some() {
+        return render_tpl!(
+            data,
+            "login",
+            &json!({"totp": true, "pending": PENDING_TOTP.issue_with(user.id)})
+        );
+    }
+
+    reset_login_failures(&data.db, &user.email).await?;
+    let (token, refresh) = start_session(&req, &data, user).await?;
+    redirect!("/", token, refresh)
+}
+
+/// Second login step for users with a TOTP secret. A wrong code restarts the login,
+/// so codes can't be guessed without the password.
+pub async fn post_totp(
+    req: HttpRequest,
+    data: Data<AppData>,
+    form: Form<TotpFormData>,
+    _csrf: CsrfGuard,
+) -> AppResult {
+    let Some(user_id) = PENDING_TOTP.take(&form.pending) else {
+        return render_tpl!(
+            data,
+            "login",
+            &json!({"error": "Anmeldung abgelaufen, bitte erneut versuchen", "nonce": NONCES.issue()})
+        );
+    };
+
+    let user = sqlx::query_as!(User, "SELECT * FROM users WHERE id = $1", user_id)
+        .fetch_one(&data.db)
+        .await?;
+
+    let step = user
+        .totp_secret
+        .as_deref()
+        .and_then(|secret| verify_totp_code(secret, &form.code, user.totp_last_step));
+
+    // Only one request can move totp_last_step forward, so a code submitted twice at
+    // the same time is still accepted once
+    let accepted = match step {
+        Some(step) => {
+            sqlx::query!(
+                "UPDATE users SET totp_last_step = $1
+                 WHERE id = $2 AND (totp_last_step IS NULL OR totp_last_step < $1)",
+                step,
+                user.id
+            )
+            .execute(&data.db)
+            .await?
+            .rows_affected()
+                == 1
+        }
+        None => false,
+    };
+
+    if !accepted {
+        record_login_failure(&data.db, &user.email, data.lockout).await?;
+        return render_tpl!(
+            data,
+            "login",
+            &json!({"error": "Falscher Code", "nonce": NONCES.issue()})
+        );
+    }
+
+    reset_login_failures(&data.db, &user.email).await?;
+    let (token, refresh) = start_session(&req, &data, user).await?;
+    redirect!("/", token, refresh)
+}
+
+/// Issue a fresh access token and refresh token for `user`, as cookies.
+pub async fn start_session(
+    req: &HttpRequest,
+    data: &AppData,
+    user: User,
+) -> AppResult<(Cookie<'static>, Cookie<'static>)> {
+    let refresh = create_refresh_token(&data.db, user.id, REFRESH_TOKEN_TTL).await?;
+    let jwt = create_jwt_with_ttl(&user, &data.jwt_secret, data.jwt_ttl)
+        .map_err(|e| AppError::Internal(format!("JWT creation error: {e}")))?;
+
+    let token = token_cookie(req, data, jwt);
+    let refresh = session_cookie(
+        req,
+        data,
+        &data.cookie.refresh_name(),
+        refresh,
+        REFRESH_TOKEN_TTL,
+    );
+    Ok((token, refresh))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        Argon2Params, UserRole,
+        actix_web::{
+            App,
+            http::StatusCode,
+            test::{TestRequest, call_service, init_service, read_body},
+        },
+        generate_totp_secret, needs_rehash, web,
+    };
+    use full_stack_engine::testing;
+
+    async fn data_with_user() -> Data<AppData> {
+        let hash = hash_password_async("correct horse", Argon2Params::default())
+            .await
+            .unwrap();
+        data_with_user_hash(&hash).await
+    }
+
+    /// ada@example.com with password "correct horse" stored as `hash`.
+    async fn data_with_user_hash(hash: &str) -> Data<AppData> {
+        let mut data = testing::app_data_with_migrations(&crate::MIGRATIONS_DIR).await;
+        data.tera
+            .add_raw_template(
+                "login",
+                "{{ nonce | default(value='') }}|{{ pending | default(value='') }}|{{ error | default(value='') }}",
+            )
+            .unwrap();
+        sqlx::query!(
+            "INSERT INTO users (email, password, password_version, role) VALUES (?, ?, ?, ?)",
+            "ada@example.com",
+            hash,
+            PASSWORD_VERSION,
+            UserRole::User
+        )
+        .execute(&data.db)
+        .await
+        .unwrap();
+        Data::new(data)
+    }
+
+    fn login_request(data: &AppData, nonce: &str) -> TestRequest {
+        let req = TestRequest::post().uri("/login").set_form([
+            ("email", "ada@example.com"),
+            ("password", "correct horse"),
+            ("nonce", nonce),
+        ]);
+        testing::with_csrf(req, data)
+    }
+
+    #[actix_web::test]
+    async fn login_nonce_admits_one_submit_and_rejects_the_replay() {
+        let data = data_with_user().await;
+        let app = init_service(
+            App::new()
+                .app_data(data.clone())
+                .service(get)
+                .route("/login", web::post().to(post)),
+        )
+        .await;
+
+        let page = call_service(&app, TestRequest::get().uri("/login").to_request()).await;
+        let page = String::from_utf8(read_body(page).await.to_vec()).unwrap();
+        let nonce = page.split('|').next().unwrap().to_string();
+
+        let res = call_service(&app, login_request(&data, &nonce).to_request()).await;
+        assert_eq!(res.status(), StatusCode::SEE_OTHER);
+
+        let res = call_service(&app, login_request(&data, &nonce).to_request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let page = String::from_utf8(read_body(res).await.to_vec()).unwrap();
+        assert!(
+            page.ends_with("|Formular abgelaufen oder bereits gesendet, bitte erneut versuchen")
+        );
+    }
+
+    #[actix_web::test]
+    async fn bcrypt_hash_is_upgraded_to_argon2_on_login() {
+        let data =
+            data_with_user_hash("$2b$04$JzofXxdYSuNtSkGNTIl/dOMlOYHYVrFklvOS1i/sFGuLaelkgU/gi")
+                .await;
+        let app = init_service(
+            App::new()
+                .app_data(data.clone())
+                .route("/login", web::post().to(post)),
+        )
+        .await;
+
+        let res = call_service(&app, login_request(&data, &NONCES.issue()).to_request()).await;
+        assert_eq!(res.status(), StatusCode::SEE_OTHER);
+
+        let user = sqlx::query_as!(
+            User,
+            "SELECT * FROM users WHERE email = $1",
+            "ada@example.com"
+        )
+        .fetch_one(&data.db)
+        .await
+        .unwrap();
+        assert!(user.password.starts_with("$argon2id$"));
+        assert!(!needs_rehash(&user.password, data.argon2_params));
+
+        let res = call_service(&app, login_request(&data, &NONCES.issue()).to_request()).await;
+        assert_eq!(res.status(), StatusCode::SEE_OTHER);
+    }
+
+    #[actix_web::test]
+    async fn outdated_password_version_is_rehashed_on_login() {
+        let data = data_with_user().await;
+        sqlx::query!("UPDATE users SET password_version = 0")
+            .execute(&data.db)
+            .await
+            .unwrap();
+        let old_hash = sqlx::query_scalar!("SELECT password FROM users")
+            .fetch_one(&data.db)
+            .await
+            .unwrap();
+        let app = init_service(
+            App::new()
+                .app_data(data.clone())
+                .route("/login", web::post().to(post)),
+        )
+        .await;
+
+        let res = call_service(&app, login_request(&data, &NONCES.issue()).to_request()).await;
+        assert_eq!(res.status(), StatusCode::SEE_OTHER);
+
+        let user = sqlx::query_as!(
+            User,
+            "SELECT * FROM users WHERE email = $1",
+            "ada@example.com"
+        )
+        .fetch_one(&data.db)
+        .await
+        .unwrap();
+        assert_eq!(user.password_version, PASSWORD_VERSION);
+        assert_ne!(user.password, old_hash);
+        assert!(!needs_rehash(&user.password, data.argon2_params));
+    }
+
+    #[actix_web::test]
+    async fn hash_weaker_than_the_configured_params_is_upgraded_on_login() {
+        let weak = Argon2Params {
+            mem_cost: 1024,
+            time_cost: 1,
+            lanes: 1,
+        };
+        let hash = hash_password_async("correct horse", weak).await.unwrap();
+        let data = data_with_user_hash(&hash).await;
+        let app = init_service(
+            App::new()
+                .app_data(data.clone())
+                .route("/login", web::post().to(post)),
+        )
+        .await;
+
+        let res = call_service(&app, login_request(&data, &NONCES.issue()).to_request()).await;
+        assert_eq!(res.status(), StatusCode::SEE_OTHER);
+
+        let password = sqlx::query_scalar!("SELECT password FROM users")
+            .fetch_one(&data.db)
+            .await
+            .unwrap();
+        let configured = data.argon2_params;
+        assert!(
+            password.contains(&format!(
+                "$m={},t={},p={}$",
+                configured.mem_cost, configured.time_cost, configured.lanes
+            )),
+            "{password}"
+        );
+    }
+
+    #[actix_web::test]
+    async fn totp_user_needs_a_code_that_works_only_once() {
+        let data = data_with_user().await;
+        let secret = generate_totp_secret();
+        sqlx::query!("UPDATE users SET totp_secret = $1", secret)
+            .execute(&data.db)
+            .await
+            .unwrap();
+        let app = init_service(
+            App::new()
+                .app_data(data.clone())
+                .route("/login", web::post().to(post))
+                .route("/login/totp", web::post().to(post_totp)),
+        )
+        .await;
+        let code = testing::totp_code(&secret);
+
+        let submit_code = async |code: &str| {
+            let res = call_service(&app, login_request(&data, &NONCES.issue()).to_request()).await;
+            // The password alone only leads to the code form
+            assert_eq!(res.status(), StatusCode::OK);
+            assert!(res.response().cookies().next().is_none());
+            let page = String::from_utf8(read_body(res).await.to_vec()).unwrap();
+            let pending = page.split('|').nth(1).unwrap().to_string();
+
+            let req = TestRequest::post()
+                .uri("/login/totp")
+                .set_form([("pending", pending.as_str()), ("code", code)]);
+            call_service(&app, testing::with_csrf(req, &data).to_request()).await
+        };
+
+        let res = submit_code("000000").await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(read_body(res).await.ends_with(b"|Falscher Code"));
+
+        let res = submit_code(&code).await;
+        assert_eq!(res.status(), StatusCode::SEE_OTHER);
+
+        let res = submit_code(&code).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(read_body(res).await.ends_with(b"|Falscher Code"));
+    }
+
+    #[actix_web::test]
+    async fn failed_logins_lock_existing_accounts_only() {
+        let data = data_with_user().await;
+        let app = init_service(
+            App::new()
+                .app_data(data.clone())
+                .route("/login", web::post().to(post)),
+        )
+        .await;
+        let attempt = async |email: &str, password: &str| {
+            let req = TestRequest::post().uri("/login").set_form([
+                ("email", email),
+                ("password", password),
+                ("nonce", &NONCES.issue()),
+            ]);
+            call_service(&app, testing::with_csrf(req, &data).to_request())
+                .await
+                .status()
+        };
+
+        for _ in 0..data.lockout.max_failures {
+            assert_eq!(attempt("nobody@example.com", "guess").await, StatusCode::OK);
+        }
+        let rows = sqlx::query_scalar!("SELECT COUNT(*) FROM login_attempts")
+            .fetch_one(&data.db)
+            .await
+            .unwrap();
+        assert_eq!(rows, 0);
+
+        for _ in 0..data.lockout.max_failures {
+            assert_eq!(attempt("ada@example.com", "guess").await, StatusCode::OK);
+        }
+        // Locked: even the right password gets the wrong-password page
+        assert_eq!(
+            attempt("ada@example.com", "correct horse").await,
+            StatusCode::OK
+        );
+    }
 }
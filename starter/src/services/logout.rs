@@ -1,21 +1,87 @@
-use crate::{
-    cookie::time::Duration, cookie::Cookie, post, AppData, AuthUser, Data, Env, HttpResponse,
-    Responder, LOCATION,
-};
+use crate::{AppData, AuthUser, CsrfGuard, Data, HttpRequest, Responder, get, logout, post};
+
+/// Logout via a plain link. A forged request can at worst sign the user out.
+#[get("/logout")]
+pub async fn get(req: HttpRequest, data: Data<AppData>) -> impl Responder {
+    logout(req, data).await
+}
 
 #[post("/logout")]
-pub async fn post(data: Data<AppData>, _user: AuthUser) -> impl Responder {
-    let cookie = Cookie::build("token", "")
-        .domain(&data.domain)
-        .path("/")
-        .same_site(actix_web::cookie::SameSite::Strict)
-        .secure(data.env != Env::Dev)
-        .http_only(true)
-        .max_age(Duration::seconds(0))
-        .finish();
-
-    HttpResponse::SeeOther()
-        .append_header((LOCATION, "/login"))
-        .cookie(cookie)
-        .finish()
+pub async fn post(
+    req: HttpRequest,
+    data: Data<AppData>,
+    _user: AuthUser,
+    _csrf: CsrfGuard,
+) -> impl Responder {
+    logout(req, data).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        REFRESH_TOKEN_TTL, UserRole,
+        actix_web::{
+            App,
+            cookie::Cookie,
+            http::{StatusCode, header::LOCATION},
+            test::{TestRequest, call_service, init_service},
+        },
+        consume_refresh_token, create_refresh_token,
+    };
+    use full_stack_engine::testing;
+
+    /// Asserts that `res` redirects to `/login` and expires both session cookies.
+    fn assert_signed_out(res: &crate::actix_web::dev::ServiceResponse) {
+        assert_eq!(res.status(), StatusCode::SEE_OTHER);
+        assert_eq!(res.headers().get(LOCATION).unwrap(), "/login");
+        let cleared: Vec<_> = res
+            .response()
+            .cookies()
+            .filter(|c| {
+                c.value().is_empty()
+                    && c.max_age()
+                        .is_some_and(crate::cookie::time::Duration::is_zero)
+            })
+            .map(|c| c.name().to_string())
+            .collect();
+        assert_eq!(cleared, ["token", "refresh_token"]);
+    }
+
+    #[actix_web::test]
+    async fn get_clears_the_session_and_revokes_the_refresh_token() {
+        let data = Data::new(testing::app_data_with_migrations(&crate::MIGRATIONS_DIR).await);
+        sqlx::query!("INSERT INTO users (id, email, password) VALUES (1, 'a@example.com', '')")
+            .execute(&data.db)
+            .await
+            .unwrap();
+        let refresh = create_refresh_token(&data.db, 1, REFRESH_TOKEN_TTL)
+            .await
+            .unwrap();
+        let app = init_service(App::new().app_data(data.clone()).service(get)).await;
+
+        let req = testing::signed_in(TestRequest::get().uri("/logout"), &data, 1, UserRole::User)
+            .cookie(Cookie::new("refresh_token", refresh.clone()));
+        let res = call_service(&app, req.to_request()).await;
+
+        assert_signed_out(&res);
+        assert_eq!(
+            consume_refresh_token(&data.db, &refresh).await.unwrap(),
+            None
+        );
+    }
+
+    #[actix_web::test]
+    async fn post_requires_csrf_and_clears_the_session() {
+        let data = Data::new(testing::app_data().await);
+        let app = init_service(App::new().app_data(data.clone()).service(post)).await;
+
+        let req = testing::signed_in(TestRequest::post().uri("/logout"), &data, 1, UserRole::User);
+        let res = call_service(&app, req.to_request()).await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+        let req = testing::signed_in(TestRequest::post().uri("/logout"), &data, 1, UserRole::User);
+        let res = call_service(&app, testing::with_csrf(req, &data).to_request()).await;
+        assert_signed_out(&res);
+    }
 }
@@ -1,10 +1,15 @@
 use crate::web;
 use full_stack_engine::rate_limiter::auth_rate_limiter;
 
+mod cron;
+mod impersonate;
 mod index;
 mod login;
 mod logout;
+mod password_reset;
+mod refresh;
 mod register;
+mod settings;
 mod users;
 
 pub fn configure(cfg: &mut web::ServiceConfig) {
@@ -15,15 +20,40 @@ pub fn configure(cfg: &mut web::ServiceConfig) {
             .route(web::post().to(login::post))
             .wrap(auth_rate_limiter()),
     );
+    cfg.service(
+        web::resource("/login/totp")
+            .route(web::post().to(login::post_totp))
+            .wrap(auth_rate_limiter()),
+    );
     cfg.service(register::get);
     cfg.service(
         web::resource("/register")
             .route(web::post().to(register::post))
             .wrap(auth_rate_limiter()),
     );
+    cfg.service(password_reset::get_forgot);
+    cfg.service(
+        web::resource("/forgot")
+            .route(web::post().to(password_reset::post_forgot))
+            .wrap(auth_rate_limiter()),
+    );
+    cfg.service(password_reset::get_reset);
+    cfg.service(
+        web::resource("/reset")
+            .route(web::post().to(password_reset::post_reset))
+            .wrap(auth_rate_limiter()),
+    );
+    cfg.service(logout::get);
     cfg.service(logout::post);
+    cfg.service(refresh::post);
     cfg.service(users::get);
     cfg.service(users::get_user);
     cfg.service(users::post_user);
+    cfg.service(users::reset_password);
     cfg.service(users::delete_user);
+    cfg.service(impersonate::start);
+    cfg.service(impersonate::stop);
+    cfg.service(cron::get);
+    cfg.service(settings::get);
+    cfg.service(settings::post);
 }
@@ -0,0 +1,232 @@
+use crate::{
+    AppData, AppError, AppResult, CsrfGuard, Deserialize, Env, HttpResponse, Mail,
+    PASSWORD_VERSION, RESET_TOKEN_TTL, actix_web, actix_web::get, consume_reset_token,
+    create_reset_token, error, hash_password_async, info, redirect, render_tpl,
+    revoke_user_refresh_tokens, serde_json::json, web,
+};
+
+use super::register::check_password;
+
+#[get("/forgot")]
+pub async fn get_forgot(data: web::Data<AppData>) -> HttpResponse {
+    data.render("forgot").await
+}
+
+#[derive(Deserialize)]
+pub struct ForgotForm {
+    pub email: String,
+}
+
+pub async fn post_forgot(
+    data: web::Data<AppData>,
+    form: web::Form<ForgotForm>,
+    _csrf: CsrfGuard,
+) -> AppResult {
+    let email = form.email.trim().to_lowercase();
+
+    // the lookup and the mail happen after answering, so neither the answer nor its
+    // timing reveals which emails are registered
+    let task_data = data.clone();
+    actix_web::rt::spawn(async move {
+        if let Err(e) = send_reset_link(&task_data, &email).await {
+            error!("Failed to send a password reset link: {e}");
+        }
+    });
+
+    render_tpl!(
+        data,
+        "forgot",
+        &json!({"message": "Falls die E-Mail-Adresse registriert ist, wurde ein Link gesendet"})
+    )
+}
+
+/// Mail a reset link to `email` if it belongs to a user. Without a mailer the link
+/// only goes to the log in development; elsewhere it is never logged.
+async fn send_reset_link(data: &AppData, email: &str) -> AppResult<()> {
+    let Some(user_id) = sqlx::query_scalar!("SELECT id FROM users WHERE email = ?", email)
+        .fetch_optional(&data.db)
+        .await?
+    else {
+        return Ok(());
+    };
+
+    let token = create_reset_token(&data.db, user_id, RESET_TOKEN_TTL).await?;
+    if data.env == Env::Dev && !data.can_send_mail() {
+        info!("Password reset link for user {user_id}: /reset?token={token}");
+        return Ok(());
+    }
+
+    data.send_mail(Mail {
+        to: email.to_string(),
+        subject: "Passwort zurücksetzen".to_string(),
+        body: format!(
+            "Über diesen Link kannst du ein neues Passwort setzen:\n\n\
+             https://{}{}/reset?token={token}",
+            data.domain, data.base_path
+        ),
+    })
+    .await
+}
+
+#[derive(Deserialize)]
+pub struct ResetQuery {
+    pub token: String,
+}
+
+#[get("/reset")]
+pub async fn get_reset(data: web::Data<AppData>, query: web::Query<ResetQuery>) -> HttpResponse {
+    data.render_tpl("reset", &json!({"token": query.token}))
+        .await
+}
+
+#[derive(Deserialize)]
+pub struct ResetForm {
+    pub token: String,
+    pub password: String,
+    pub repeat_password: String,
+}
+
+pub async fn post_reset(
+    data: web::Data<AppData>,
+    form: web::Form<ResetForm>,
+    _csrf: CsrfGuard,
+) -> AppResult {
+    // check the password before redeeming, so a typo doesn't use up the link
+    let error = check_password(&form.password, &form.repeat_password);
+    if let Some(error) = error {
+        return render_tpl!(data, "reset", &json!({"token": form.token, "error": error}));
+    }
+
+    let Some(user_id) = consume_reset_token(&data.db, &form.token).await? else {
+        return render_tpl!(
+            data,
+            "reset",
+            &json!({"error": "Link ungültig oder abgelaufen, bitte erneut anfordern"})
+        );
+    };
+
+    let hashed_password = hash_password_async(&form.password, data.argon2_params)
+        .await
+        .map_err(|e| AppError::Internal(e.to_string()))?;
+
+    sqlx::query!(
+        "UPDATE users SET password = ?, password_version = ? WHERE id = ?",
+        hashed_password,
+        PASSWORD_VERSION,
+        user_id
+    )
+    .execute(&data.db)
+    .await?;
+
+    // whoever knew the old password loses their sessions
+    revoke_user_refresh_tokens(&data.db, user_id).await?;
+
+    redirect!("/login")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        UserRole,
+        actix_web::{
+            App,
+            http::{StatusCode, header::LOCATION},
+            test::{TestRequest, call_service, init_service, read_body},
+        },
+        verify_password,
+    };
+    use full_stack_engine::testing;
+
+    /// ada@example.com with password "old password", and one-line templates.
+    async fn data_with_user() -> AppData {
+        let mut data = testing::app_data_with_migrations(&crate::MIGRATIONS_DIR).await;
+        data.tera
+            .add_raw_templates([
+                ("forgot", "{{ message }}"),
+                ("reset", "{{ error | default(value='') }}"),
+            ])
+            .unwrap();
+        let hash = hash_password_async("old password", data.argon2_params)
+            .await
+            .unwrap();
+        sqlx::query!(
+            "INSERT INTO users (email, password, password_version, role) VALUES (?, ?, ?, ?)",
+            "ada@example.com",
+            hash,
+            PASSWORD_VERSION,
+            UserRole::User
+        )
+        .execute(&data.db)
+        .await
+        .unwrap();
+        data
+    }
+
+    #[actix_web::test]
+    async fn forgot_answers_alike_for_unknown_emails() {
+        let data = web::Data::new(data_with_user().await);
+        let app = init_service(
+            App::new()
+                .app_data(data.clone())
+                .route("/forgot", web::post().to(post_forgot)),
+        )
+        .await;
+
+        let mut bodies = Vec::new();
+        for email in ["ada@example.com", "nobody@example.com"] {
+            let req = TestRequest::post()
+                .uri("/forgot")
+                .set_form([("email", email)]);
+            let res = call_service(&app, testing::with_csrf(req, &data).to_request()).await;
+            assert_eq!(res.status(), StatusCode::OK);
+            bodies.push(read_body(res).await);
+        }
+        assert_eq!(bodies[0], bodies[1]);
+    }
+
+    #[actix_web::test]
+    async fn reset_link_is_mailed_and_works_once() {
+        let mut data = data_with_user().await;
+        // outside development the link needs a mailer, it never goes to the log
+        assert!(send_reset_link(&data, "ada@example.com").await.is_err());
+
+        let sent = testing::capture_mail(&mut data);
+        send_reset_link(&data, "nobody@example.com").await.unwrap();
+        send_reset_link(&data, "ada@example.com").await.unwrap();
+        let mail = sent.lock().unwrap().pop().unwrap();
+        assert!(sent.lock().unwrap().is_empty());
+        assert_eq!(mail.to, "ada@example.com");
+        let token = mail.body.rsplit("token=").next().unwrap().to_string();
+
+        let data = web::Data::new(data);
+        let app = init_service(
+            App::new()
+                .app_data(data.clone())
+                .route("/reset", web::post().to(post_reset)),
+        )
+        .await;
+        let reset = |data: &AppData| {
+            let req = TestRequest::post().uri("/reset").set_form([
+                ("token", token.as_str()),
+                ("password", "new password"),
+                ("repeat_password", "new password"),
+            ]);
+            testing::with_csrf(req, data).to_request()
+        };
+
+        let res = call_service(&app, reset(&data)).await;
+        assert_eq!(res.status(), StatusCode::SEE_OTHER);
+        assert_eq!(res.headers().get(LOCATION).unwrap(), "/login");
+        let hash: String = sqlx::query_scalar("SELECT password FROM users WHERE email = ?")
+            .bind("ada@example.com")
+            .fetch_one(&data.db)
+            .await
+            .unwrap();
+        assert!(verify_password("new password", &hash));
+
+        let res = call_service(&app, reset(&data)).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(!read_body(res).await.is_empty());
+    }
+}
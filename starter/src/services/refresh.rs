@@ -0,0 +1,96 @@
+use super::login::start_session;
+use crate::{
+    AppData, AppError, AppResult, Data, HttpRequest, HttpResponse, User, UserRole,
+    consume_refresh_token, post,
+};
+
+/// Trade the refresh token cookie for a new access token. The refresh token is
+/// rotated: the old one is consumed and a new one is set alongside the access token.
+#[post("/auth/refresh")]
+pub async fn post(req: HttpRequest, data: Data<AppData>) -> AppResult {
+    let token = req
+        .cookie(&data.cookie.refresh_name())
+        .ok_or_else(|| AppError::Auth("Refresh token missing".into()))?;
+
+    let user_id = consume_refresh_token(&data.db, token.value())
+        .await?
+        .ok_or_else(|| AppError::Auth("Refresh token invalid or expired".into()))?;
+
+    let user = sqlx::query_as!(User, "SELECT * FROM users WHERE id = ?", user_id)
+        .fetch_optional(&data.db)
+        .await?
+        .filter(|user| user.role != UserRole::None)
+        .ok_or_else(|| AppError::Auth("Account disabled".into()))?;
+
+    let (token, refresh) = start_session(&req, &data, user).await?;
+    Ok(HttpResponse::NoContent()
+        .cookie(token)
+        .cookie(refresh)
+        .finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        REFRESH_TOKEN_TTL,
+        actix_web::{
+            App,
+            http::StatusCode,
+            test::{TestRequest, call_service, init_service},
+        },
+        cookie::Cookie,
+        create_refresh_token,
+    };
+    use full_stack_engine::testing;
+
+    #[actix_web::test]
+    async fn refresh_rotates_the_token_and_rejects_the_old_one() {
+        let data = testing::app_data_with_migrations(&crate::MIGRATIONS_DIR).await;
+        let user_id = sqlx::query_scalar!(
+            "INSERT INTO users (email, password, role) VALUES (?, ?, ?) RETURNING id",
+            "ada@example.com",
+            "unused",
+            UserRole::User
+        )
+        .fetch_one(&data.db)
+        .await
+        .unwrap();
+        let token = create_refresh_token(&data.db, user_id, REFRESH_TOKEN_TTL)
+            .await
+            .unwrap();
+        let data = Data::new(data);
+        let app = init_service(App::new().app_data(data.clone()).service(post)).await;
+        let refresh = |token: &str| {
+            TestRequest::post()
+                .uri("/auth/refresh")
+                .cookie(Cookie::new(data.cookie.refresh_name(), token.to_string()))
+                .to_request()
+        };
+
+        let res = call_service(&app, refresh(&token)).await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        let rotated = res
+            .response()
+            .cookies()
+            .find(|c| c.name() == data.cookie.refresh_name())
+            .unwrap()
+            .value()
+            .to_string();
+        assert_ne!(rotated, token);
+        let session = res
+            .response()
+            .cookies()
+            .find(|c| c.name() == data.cookie.name)
+            .unwrap();
+        let exp = i64::try_from(data.verify_jwt(session.value()).unwrap().exp).unwrap();
+        let remaining = exp - sqlx::types::chrono::Utc::now().timestamp();
+        let max_age = session.max_age().unwrap().whole_seconds();
+        assert!((remaining - max_age).abs() <= 1);
+
+        let res = call_service(&app, refresh(&token)).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        let res = call_service(&app, refresh(&rotated)).await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+    }
+}
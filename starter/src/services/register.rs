@@ -1,6 +1,6 @@
 use crate::{
-    AppData, AppError, AppResult, Deserialize, HttpResponse, actix_web::get,
-    actix_web::http::header::LOCATION, hash_password, serde_json::json, web,
+    AppData, AppError, AppResult, CsrfGuard, Deserialize, HttpResponse, PASSWORD_VERSION,
+    actix_web::get, hash_password_async, redirect, render_tpl, serde_json::json, web,
 };
 
 #[derive(Deserialize, Debug)]
@@ -12,12 +12,28 @@ pub struct FormData {
     // pub register_key: String,
 }
 
+/// The password policy every form that sets a password checks; the message to show
+/// if `password` doesn't meet it.
+pub fn check_password(password: &str, repeat_password: &str) -> Option<&'static str> {
+    if password.len() < 8 {
+        Some("Passwort muss mindestens 8 Zeichen lang sein")
+    } else if password != repeat_password {
+        Some("Passwörter stimmen nicht überein")
+    } else {
+        None
+    }
+}
+
 #[get("/register")]
 pub async fn get(data: web::Data<AppData>) -> HttpResponse {
     data.render("register").await
 }
 
-pub async fn post(data: web::Data<AppData>, form: web::Form<FormData>) -> AppResult {
+pub async fn post(
+    data: web::Data<AppData>,
+    form: web::Form<FormData>,
+    _csrf: CsrfGuard,
+) -> AppResult {
     // Optional
     // if let Some(register_key) = &data.register_key {
     //     if form.register_key != *register_key {
@@ -27,52 +43,41 @@ pub async fn post(data: web::Data<AppData>, form: web::Form<FormData>) -> AppRes
     //     }
     // }
 
-    if form.password.len() < 8 {
-        return Ok(data
-            .render_tpl(
-                "register",
-                &json!({"error": "Passwort muss mindestens 8 Zeichen lang sein"}),
-            )
-            .await);
-    }
-
-    if form.password != form.repeat_password {
-        return Ok(data
-            .render_tpl(
-                "register",
-                &json!({"error": "Passwörter stimmen nicht überein"}),
-            )
-            .await);
+    if let Some(error) = check_password(&form.password, &form.repeat_password) {
+        return render_tpl!(data, "register", &json!({"error": error}));
     }
 
     let email = form.email.trim().to_lowercase();
     if !email.contains('@') || email.is_empty() {
-        return Ok(data
-            .render_tpl("register", &json!({"error": "Ungültige E-Mail-Adresse"}))
-            .await);
+        return render_tpl!(
+            data,
+            "register",
+            &json!({"error": "Ungültige E-Mail-Adresse"})
+        );
     }
 
-    let hashed_password =
-        hash_password(&form.password).map_err(|e| AppError::Internal(e.to_string()))?;
+    let hashed_password = hash_password_async(&form.password, data.argon2_params)
+        .await
+        .map_err(|e| AppError::Internal(e.to_string()))?;
 
-    let user_exists = sqlx::query!("SELECT id FROM users WHERE email = ?", email)
-        .fetch_optional(&data.db)
+    let user_exists = data
+        .exists(sqlx::query!("SELECT id FROM users WHERE email = ?", email))
         .await
         .map_err(|e| AppError::Internal(e.to_string()))?;
 
-    if user_exists.is_some() {
-        return Ok(data
-            .render_tpl(
-                "register",
-                &json!({"error": "E-Mail wird bereits verwendet"}),
-            )
-            .await);
+    if user_exists {
+        return render_tpl!(
+            data,
+            "register",
+            &json!({"error": "E-Mail wird bereits verwendet"})
+        );
     }
 
     let _user_id = sqlx::query!(
-        "INSERT INTO users (email, password, role) VALUES (?, ?, ?)",
+        "INSERT INTO users (email, password, password_version, role) VALUES (?, ?, ?, ?)",
         email,
         hashed_password,
+        PASSWORD_VERSION,
         crate::UserRole::User
     )
     .execute(&data.db)
@@ -80,7 +85,5 @@ pub async fn post(data: web::Data<AppData>, form: web::Form<FormData>) -> AppRes
     .map_err(|e| AppError::Internal(e.to_string()))?
     .last_insert_rowid();
 
-    Ok(HttpResponse::SeeOther()
-        .append_header((LOCATION, "/login"))
-        .finish())
+    redirect!("/login")
 }
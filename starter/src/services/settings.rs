@@ -0,0 +1,106 @@
+use crate::{
+    AdminAction, AdminUser, AppData, AppResult, Ctx, Deserialize, Serialize, Table, TableHeader,
+    actix_web::{get, post, web},
+    redirect, render_tpl,
+};
+
+#[derive(Serialize)]
+struct Row {
+    pub key: String,
+    pub value: String,
+}
+
+#[get("/settings")]
+pub async fn get(ctx: Ctx<AdminUser>) -> AppResult {
+    let rows: Vec<Row> = ctx
+        .data
+        .settings
+        .all()
+        .into_iter()
+        .map(|(key, value)| Row { key, value })
+        .collect();
+
+    let table = Table {
+        headers: vec![
+            TableHeader {
+                label: "Key".to_string(),
+                key: "key".to_string(),
+                format: None,
+            },
+            TableHeader {
+                label: "Value".to_string(),
+                key: "value".to_string(),
+                format: None,
+            },
+        ],
+        rows,
+        actions: vec![],
+    };
+
+    render_tpl!(
+        ctx.data,
+        "settings",
+        &crate::json!({
+            "headers": table.headers,
+            "rows": table.rows,
+            "actions": table.actions,
+            "role": "admin"
+        })
+    )
+}
+
+#[derive(Deserialize)]
+pub struct SettingForm {
+    pub key: String,
+    pub value: String,
+}
+
+#[post("/settings")]
+pub async fn post(
+    data: web::Data<AppData>,
+    _admin: AdminAction,
+    form: web::Form<SettingForm>,
+) -> AppResult {
+    let key = form.key.trim();
+    if !key.is_empty() {
+        data.set_setting(key, form.value.trim()).await?;
+    }
+
+    redirect!("/settings")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        UserRole,
+        actix_web::{
+            App,
+            http::{StatusCode, header::LOCATION},
+            test::{TestRequest, call_service, init_service},
+        },
+    };
+    use full_stack_engine::testing;
+
+    #[actix_web::test]
+    async fn post_requires_csrf() {
+        let data = web::Data::new(testing::app_data_with_migrations(&crate::MIGRATIONS_DIR).await);
+        let app = init_service(App::new().app_data(data.clone()).service(post)).await;
+        let request = |data: &AppData| {
+            let req = TestRequest::post()
+                .uri("/settings")
+                .set_form([("key", "greeting"), ("value", "Hallo")]);
+            testing::signed_in(req, data, 1, UserRole::Admin)
+        };
+
+        let res = call_service(&app, request(&data).to_request()).await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        assert_eq!(data.settings.get("greeting"), None);
+
+        let req = testing::with_csrf(request(&data), &data);
+        let res = call_service(&app, req.to_request()).await;
+        assert_eq!(res.status(), StatusCode::SEE_OTHER);
+        assert_eq!(res.headers().get(LOCATION).unwrap(), "/settings");
+        assert_eq!(data.settings.get("greeting").as_deref(), Some("Hallo"));
+    }
+}
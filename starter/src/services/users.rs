@@ -1,8 +1,12 @@
 use crate::{
-    AdminUser, AppData, AppResult, Deserialize, Serialize, Table, TableHeader, User,
-    actix_web::{HttpResponse, delete, get, post, web},
+    AdminAction, AdminUser, AppData, AppError, AppResult, Deserialize, PASSWORD_VERSION, Serialize,
+    Table, TableAction, TableHeader, User,
+    actix_web::{HttpRequest, HttpResponse, delete, get, post, web},
+    hash_password_async, redirect, render_tpl, revoke_user_refresh_tokens,
 };
 
+use super::register::check_password;
+
 #[derive(Serialize)]
 struct Row {
     pub id: i64,
@@ -13,7 +17,7 @@ struct Row {
 }
 
 #[get("/users")]
-pub async fn get(data: web::Data<AppData>, _user: AdminUser) -> AppResult {
+pub async fn get(req: HttpRequest, data: web::Data<AppData>, _user: AdminUser) -> AppResult {
     let users = sqlx::query_as!(User, "SELECT * FROM users ORDER BY created_at DESC")
         .fetch_all(&data.db)
         .await?;
@@ -24,7 +28,7 @@ pub async fn get(data: web::Data<AppData>, _user: AdminUser) -> AppResult {
             id: u.id,
             email: u.email,
             role: u.role,
-            created_at: u.created_at.to_string(),
+            created_at: data.local_time(&u.created_at),
             link: format!("/users/{}", u.id),
         })
         .collect();
@@ -51,26 +55,22 @@ pub async fn get(data: web::Data<AppData>, _user: AdminUser) -> AppResult {
                 key: "created_at".to_string(),
                 format: None,
             },
-            TableHeader {
-                label: "Actions".to_string(),
-                key: "id".to_string(),
-                format: Some("delete_user".to_string()),
-            },
         ],
         rows,
-        actions: vec![],
+        actions: vec![TableAction {
+            label: "Delete".to_string(),
+            action: "/users".to_string(),
+            method: "DELETE".to_string(),
+            confirm: Some(
+                "Are you sure you want to delete this user? This action cannot be undone."
+                    .to_string(),
+            ),
+            style: Some("danger".to_string()),
+        }],
     };
 
-    Ok(data
-        .render_tpl(
-            "users",
-            &crate::json!({
-                "headers": table.headers,
-                "rows": table.rows,
-                "actions": table.actions,
-                "role": "admin"
-            }),
-        )
+    Ok(table
+        .respond(&req, &data, "users", crate::json!({"role": "admin"}))
         .await)
 }
 
@@ -85,16 +85,16 @@ pub async fn get_user(
         .fetch_one(&data.db)
         .await?;
 
-    Ok(data
-        .render_tpl(
-            "user",
-            &crate::json!({
-                "id": user_data.id,
-                "email": user_data.email,
-                "role": "admin"
-            }),
-        )
-        .await)
+    render_tpl!(
+        data,
+        "user",
+        &crate::json!({
+            "id": user_data.id,
+            "email": user_data.email,
+            "role": "admin",
+            "roles": crate::UserRole::options()
+        })
+    )
 }
 
 #[derive(Deserialize)]
@@ -105,7 +105,7 @@ pub struct UserUpdateForm {
 #[post("/users/{id}")]
 pub async fn post_user(
     data: web::Data<AppData>,
-    _user: AdminUser,
+    _admin: AdminAction,
     path: web::Path<i64>,
     form: web::Form<UserUpdateForm>,
 ) -> AppResult {
@@ -115,15 +115,66 @@ pub async fn post_user(
         .execute(&data.db)
         .await?;
 
-    Ok(HttpResponse::Found()
-        .append_header(("Location", format!("/users/{}", user_id)))
-        .finish())
+    redirect!(format!("/users/{user_id}"))
+}
+
+#[derive(Deserialize)]
+pub struct ResetPasswordForm {
+    pub password: String,
+    pub repeat_password: String,
+}
+
+#[post("/users/{id}/reset-password")]
+pub async fn reset_password(
+    data: web::Data<AppData>,
+    _admin: AdminAction,
+    path: web::Path<i64>,
+    form: web::Form<ResetPasswordForm>,
+) -> AppResult {
+    let user_id = path.into_inner();
+    let user_data = sqlx::query_as!(User, "SELECT * FROM users WHERE id = ?", user_id)
+        .fetch_one(&data.db)
+        .await?;
+
+    let error = check_password(&form.password, &form.repeat_password);
+
+    if let Some(error) = error {
+        return render_tpl!(
+            data,
+            "user",
+            &crate::json!({
+                "id": user_data.id,
+                "email": user_data.email,
+                "role": "admin",
+                "roles": crate::UserRole::options(),
+                "error": error
+            })
+        );
+    }
+
+    let hashed_password = hash_password_async(&form.password, data.argon2_params)
+        .await
+        .map_err(|e| AppError::Internal(e.to_string()))?;
+
+    sqlx::query!(
+        "UPDATE users SET password = ?, password_version = ? WHERE id = ?",
+        hashed_password,
+        PASSWORD_VERSION,
+        user_id
+    )
+    .execute(&data.db)
+    .await?;
+
+    // sessions signed in with the old password end with their session token
+    revoke_user_refresh_tokens(&data.db, user_id).await?;
+
+    redirect!(format!("/users/{user_id}"))
 }
 
 #[delete("/users/{id}")]
 pub async fn delete_user(
     data: web::Data<AppData>,
-    _user: AdminUser,
+    _admin: AdminAction,
     path: web::Path<i64>,
 ) -> AppResult {
     let user_id = path.into_inner();
@@ -134,3 +185,113 @@ pub async fn delete_user(
 
     Ok(HttpResponse::Ok().finish())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        UserRole,
+        actix_web::{
+            App,
+            http::StatusCode,
+            test::{TestRequest, call_service, init_service},
+        },
+        create_refresh_token, verify_password,
+    };
+    use full_stack_engine::testing;
+
+    async fn data_with_user() -> (web::Data<AppData>, i64) {
+        let mut data = testing::app_data_with_migrations(&crate::MIGRATIONS_DIR).await;
+        data.tera.add_raw_template("user", "{{ error }}").unwrap();
+        let hash = hash_password_async("old password", data.argon2_params)
+            .await
+            .unwrap();
+        let user_id = sqlx::query!(
+            "INSERT INTO users (email, password, password_version, role) VALUES (?, ?, ?, ?)",
+            "user@example.com",
+            hash,
+            PASSWORD_VERSION,
+            UserRole::User
+        )
+        .execute(&data.db)
+        .await
+        .unwrap()
+        .last_insert_rowid();
+        (web::Data::new(data), user_id)
+    }
+
+    fn reset_request(data: &AppData, user_id: i64, password: &str, repeat: &str) -> TestRequest {
+        let req = TestRequest::post()
+            .uri(&format!("/users/{user_id}/reset-password"))
+            .set_form([("password", password), ("repeat_password", repeat)]);
+        testing::with_csrf(testing::signed_in(req, data, 1, UserRole::Admin), data)
+    }
+
+    #[actix_web::test]
+    async fn reset_password_sets_new_password_and_ends_sessions() {
+        let (data, user_id) = data_with_user().await;
+        create_refresh_token(&data.db, user_id, crate::REFRESH_TOKEN_TTL)
+            .await
+            .unwrap();
+        let app = init_service(App::new().app_data(data.clone()).service(reset_password)).await;
+
+        let req = reset_request(&data, user_id, "new password", "new password");
+        let res = call_service(&app, req.to_request()).await;
+        assert_eq!(res.status(), StatusCode::SEE_OTHER);
+
+        let hash = sqlx::query_scalar!("SELECT password FROM users WHERE id = ?", user_id)
+            .fetch_one(&data.db)
+            .await
+            .unwrap();
+        assert!(verify_password("new password", &hash));
+        let sessions = sqlx::query_scalar!(
+            "SELECT COUNT(*) FROM refresh_tokens WHERE user_id = ?",
+            user_id
+        )
+        .fetch_one(&data.db)
+        .await
+        .unwrap();
+        assert_eq!(sessions, 0);
+    }
+
+    #[actix_web::test]
+    async fn reset_password_checks_the_policy() {
+        let (data, user_id) = data_with_user().await;
+        let app = init_service(App::new().app_data(data.clone()).service(reset_password)).await;
+
+        let req = reset_request(&data, user_id, "short", "short");
+        let res = call_service(&app, req.to_request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let hash = sqlx::query_scalar!("SELECT password FROM users WHERE id = ?", user_id)
+            .fetch_one(&data.db)
+            .await
+            .unwrap();
+        assert!(verify_password("old password", &hash));
+    }
+
+    #[actix_web::test]
+    async fn reset_password_requires_an_admin() {
+        let (data, user_id) = data_with_user().await;
+        let app = init_service(App::new().app_data(data.clone()).service(reset_password)).await;
+
+        let req = TestRequest::post()
+            .uri(&format!("/users/{user_id}/reset-password"))
+            .set_form([
+                ("password", "new password"),
+                ("repeat_password", "new password"),
+            ]);
+        let req = testing::with_csrf(
+            testing::signed_in(req, &data, user_id, UserRole::User),
+            &data,
+        );
+        let res = call_service(&app, req.to_request()).await;
+        assert_eq!(res.status(), StatusCode::FOUND);
+
+        let hash = sqlx::query_scalar!("SELECT password FROM users WHERE id = ?", user_id)
+            .fetch_one(&data.db)
+            .await
+            .unwrap();
+        assert!(verify_password("old password", &hash));
+    }
+}